@@ -5,11 +5,11 @@ use netsel::NetSelServer;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create and start NetSel server with default configuration
     let server = NetSelServer::new();
-    server.start().await?;
+    let handle = server.start().await?;
     
     // Wait for shutdown signal
     signal::ctrl_c().await?;
-    println!("Shutting down NetSel Service...");
+    handle.shutdown().await?;
     
     Ok(())
-}
\ No newline at end of file
+}
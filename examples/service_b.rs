@@ -3,15 +3,30 @@ use std::str::FromStr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::time::Duration;
-use netsel::client::ServiceClient;
+use netsel::client::{ClientEvent, ServiceClient};
+use netsel::logging::{self, LogFormat};
+use netsel::protocol::HealthCheck;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    logging::init(LogFormat::Text, "info")?;
+    
     let service_a_addr = SocketAddr::from_str("127.0.0.1:9000")?;
     let hostname = "test-service-3"; // Use unique hostname to avoid conflicts
     
+    // Start the echo server first so its address can be advertised at registration
+    let local_addr = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 11000);
+    println!("Starting echo server on {} (local testing)", local_addr);
+    let listener = TcpListener::bind(local_addr).await?;
+    
     // Create service client
     let mut client = ServiceClient::new(service_a_addr, hostname.to_string());
+    client.set_listen_host(local_addr.ip().to_string());
+    client.set_listen_port(local_addr.port());
+    client.set_version(env!("CARGO_PKG_VERSION").to_string());
+    client.add_tag("echo".to_string());
+    client.set_health_check(HealthCheck::tcp());
+    client.set_ttl(Duration::from_secs(30));
     
     // Register with Service A
     println!("Registering service '{}' with Service A at {}", hostname, service_a_addr);
@@ -20,25 +35,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     println!("Successfully registered! Assigned address: {}", assigned_addr);
     
-    // Start heartbeats
-    let heartbeat_client = client.clone();
+    // Report transparent re-registrations, e.g. after a registry restart
+    let mut events = client.events();
     tokio::spawn(async move {
-        loop {
-            if let Err(e) = heartbeat_client.send_heartbeat().await {
-                eprintln!("Heartbeat failed: {}", e);
+        while let Ok(event) = events.recv().await {
+            match event {
+                ClientEvent::Reregistered { instance_id, addr } => {
+                    println!("Registered again as {} with the same address {}", instance_id, addr);
+                }
+                ClientEvent::AddressChanged { instance_id, new_addr, .. } => {
+                    println!("Registered again as {}, address changed to {}", instance_id, new_addr);
+                }
             }
-            tokio::time::sleep(Duration::from_secs(10)).await;
         }
     });
     
-    // Use a larger offset to avoid port conflicts
-    let echo_port = assigned_port + 2000; // Use 11000 instead of 10000
-    let local_addr = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), echo_port);
-    println!("Starting echo server on {} (local testing)", local_addr);
-    let listener = TcpListener::bind(local_addr).await?;
+    // Start sending heartbeats at a third of the granted TTL; they stop when the handle is dropped
+    let _heartbeat = client.start_lease_heartbeat();
     
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        
+        match accepted {
             Ok((mut stream, peer_addr)) => {
                 println!("New connection from {}", peer_addr);
                 
@@ -46,7 +67,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let mut buffer = [0u8; 1024];
                     loop {
                         let n = match stream.read(&mut buffer).await {
-                            Ok(n) if n == 0 => {
+                            Ok(0) => {
                                 println!("Connection closed by {}", peer_addr);
                                 return;
                             }
@@ -71,4 +92,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
     }
+    
+    // Leave the registry right away so no more traffic is routed here
+    println!("Deregistering service '{}'", hostname);
+    client.deregister().await?;
+    
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,444 @@
+//! Admin HTTP API for NetSel
+//!
+//! This module serves a small JSON API for inspecting and managing the registry:
+//!
+//! | Route                                           | Description                                                  |
+//! |-------------------------------------------------|--------------------------------------------------------------|
+//! | `GET /v1/services`                              | List every service with its instances                        |
+//! | `GET /v1/services/{name}`                       | Show one service                                             |
+//! | `DELETE /v1/services/{name}`                    | Force the deregistration of all its instances                |
+//! | `PUT /v1/services/{name}/instances/{id}/status` | Set an instance `draining`, `maintenance` or back to `ready` |
+//! | `GET /v1/health`                                | Health of the NetSel server itself                           |
+//! | `GET /metrics`                                  | Prometheus metrics, see [`crate::metrics`]                   |
+//!
+//! The status route takes a JSON body such as `{"status": "draining"}` and answers with the updated instance. Path
+//! segments are percent-decoded before they are matched.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{ALLOW, CONTENT_TYPE, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+use crate::metrics::Metrics;
+use crate::persistence::to_unix_millis;
+use crate::registry::{ServiceInfo, ServiceStatus, SharedRegistry};
+use crate::shutdown::Shutdown;
+
+/// A service as returned by the admin API
+#[derive(Serialize)]
+struct ServiceView {
+    name: String,
+    instances: Vec<InstanceView>,
+}
+
+/// An instance as returned by the admin API
+#[derive(Serialize)]
+struct InstanceView {
+    instance_id: String,
+    status: &'static str,
+    addr: SocketAddr,
+    endpoint: SocketAddr,
+    version: Option<String>,
+    tags: BTreeSet<String>,
+    metadata: BTreeMap<String, String>,
+    /// Registration time, in milliseconds since the Unix epoch
+    registered_at: u64,
+    /// Time since the last heartbeat, in milliseconds
+    last_heartbeat_ms_ago: u64,
+    /// Whether the instance is kept out of rotation for flapping
+    flapping: bool,
+    /// Times the instance went in or out of rotation within the flap window
+    flap_count: usize,
+}
+
+impl From<&ServiceInfo> for InstanceView {
+    fn from(service: &ServiceInfo) -> Self {
+        InstanceView {
+            instance_id: service.instance_id.clone(),
+            status: service.status.as_str(),
+            addr: service.addr,
+            endpoint: service.endpoint,
+            version: service.version.clone(),
+            tags: service.tags.clone(),
+            metadata: service.metadata.clone(),
+            registered_at: to_unix_millis(service.registered_at),
+            last_heartbeat_ms_ago: service.last_heartbeat.elapsed().as_millis() as u64,
+            flapping: service.is_flapping(),
+            flap_count: service.transitions.len(),
+        }
+    }
+}
+
+/// Body of a status change request
+#[derive(Deserialize)]
+struct StatusRequest {
+    status: String,
+}
+
+/// Largest accepted request body, in bytes
+const MAX_BODY_LEN: usize = 4096;
+
+/// Health of the NetSel server
+#[derive(Serialize)]
+struct HealthView {
+    status: &'static str,
+    uptime_secs: u64,
+    services: usize,
+    instances: usize,
+}
+
+/// Serve the admin HTTP API on `listener` until `shutdown` fires
+///
+/// Serves HTTP/1.1. The API can deregister services, so it should only be reachable by operators. Open connections
+/// are closed on shutdown.
+pub async fn serve_admin(
+    listener: TcpListener,
+    registry: Arc<SharedRegistry>,
+    metrics: Arc<Metrics>,
+    mut shutdown: Shutdown
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(addr = %listener.local_addr()?, "Admin API listening");
+    let started = Instant::now();
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((inbound, peer_addr)) => {
+                    let registry_clone = registry.clone();
+                    let metrics_clone = metrics.clone();
+                    connections.spawn(async move {
+                        let service = service_fn(move |req| {
+                            handle_admin_request(req, started, registry_clone.clone(), metrics_clone.clone())
+                        });
+                        if let Err(e) = http1::Builder::new()
+                            .serve_connection(TokioIo::new(inbound), service)
+                            .await
+                        {
+                            warn!(peer = %peer_addr, error = %e, "Error handling admin connection");
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!(error = %e, "Error accepting admin connection");
+                }
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    connections.shutdown().await;
+    info!("Admin API stopped");
+    Ok(())
+}
+
+/// Route a single admin request
+async fn handle_admin_request(
+    req: Request<Incoming>,
+    started: Instant,
+    registry: Arc<SharedRegistry>,
+    metrics: Arc<Metrics>
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = req.uri().path().trim_end_matches('/');
+    let segments: Option<Vec<String>> = path.split('/').skip(1).map(percent_decode).collect();
+    let Some(segments) = segments else {
+        return Ok(error_response(StatusCode::BAD_REQUEST, "Invalid percent-encoding in path"));
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    let response = match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["v1", "health"]) => {
+            let registry_r = registry.read().await;
+            json_response(StatusCode::OK, &HealthView {
+                status: "ok",
+                uptime_secs: started.elapsed().as_secs(),
+                services: registry_r.services.len(),
+                instances: registry_r.services.values().map(|instances| instances.len()).sum(),
+            })
+        }
+        (&Method::GET, ["v1", "services"]) => {
+            let registry_r = registry.read().await;
+            let mut services: Vec<ServiceView> = registry_r.services
+                .iter()
+                .map(|(name, instances)| ServiceView {
+                    name: name.clone(),
+                    instances: instances.values().map(InstanceView::from).collect(),
+                })
+                .collect();
+            services.sort_by(|a, b| a.name.cmp(&b.name));
+            json_response(StatusCode::OK, &services)
+        }
+        (&Method::GET, ["v1", "services", name]) => {
+            let name = name.to_ascii_lowercase();
+            let registry_r = registry.read().await;
+            let instances = registry_r.get_instances(&name);
+            if instances.is_empty() {
+                error_response(StatusCode::NOT_FOUND, "Service not found")
+            } else {
+                json_response(StatusCode::OK, &ServiceView {
+                    name,
+                    instances: instances.into_iter().map(InstanceView::from).collect(),
+                })
+            }
+        }
+        (&Method::DELETE, ["v1", "services", name]) => {
+            let name = name.to_ascii_lowercase();
+            let removed = registry.write().await.unregister_service(&name);
+            if removed.is_empty() {
+                error_response(StatusCode::NOT_FOUND, "Service not found")
+            } else {
+                info!(service = %name, instances = removed.len(), "Admin API deregistered service");
+                json_response(StatusCode::OK, &ServiceView {
+                    name,
+                    instances: removed.iter().map(InstanceView::from).collect(),
+                })
+            }
+        }
+        (&Method::PUT, ["v1", "services", name, "instances", instance_id, "status"]) => {
+            let (name, instance_id) = (name.to_ascii_lowercase(), instance_id.to_ascii_lowercase());
+            set_instance_status(req, &name, &instance_id, &registry).await
+        }
+        (&Method::GET, ["metrics"]) => {
+            let body = metrics.render(&*registry.read().await);
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+            response
+        }
+        (_, ["v1", "health"]) | (_, ["v1", "services"]) | (_, ["metrics"]) => method_not_allowed("GET"),
+        (_, ["v1", "services", _]) => method_not_allowed("GET, DELETE"),
+        (_, ["v1", "services", _, "instances", _, "status"]) => method_not_allowed("PUT"),
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+/// Set the status of an instance from the JSON body of `req`
+async fn set_instance_status(
+    req: Request<Incoming>,
+    name: &str,
+    instance_id: &str,
+    registry: &SharedRegistry
+) -> Response<Full<Bytes>> {
+    let body = match Limited::new(req.into_body(), MAX_BODY_LEN).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "Cannot read request body"),
+    };
+    let status = match serde_json::from_slice::<StatusRequest>(&body) {
+        Ok(request) => request.status.parse::<ServiceStatus>(),
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("Invalid request body: {}", e)),
+    };
+    let status = match status {
+        Ok(status @ (ServiceStatus::Ready | ServiceStatus::Draining | ServiceStatus::Maintenance)) => status,
+        _ => return error_response(StatusCode::BAD_REQUEST, "Status must be ready, draining or maintenance"),
+    };
+
+    match registry.write().await.set_status(name, instance_id, status) {
+        Some(service) => {
+            info!(service = %name, instance = %instance_id, status = service.status.as_str(), "Admin API set instance status");
+            json_response(StatusCode::OK, &InstanceView::from(&service))
+        }
+        None => error_response(StatusCode::NOT_FOUND, "Instance not found"),
+    }
+}
+
+/// Decode the `%XX` escapes of a path segment, or `None` if they are malformed or do not decode to UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Build a JSON response
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    match serde_json::to_vec_pretty(body) {
+        Ok(mut body) => {
+            body.push(b'\n');
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            *response.status_mut() = status;
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(e) => {
+            error!(error = %e, "Error encoding admin response");
+            let mut response = Response::new(Full::new(Bytes::new()));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+/// Build a JSON error response of the form `{"error": "..."}`
+fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json_response(status, &BTreeMap::from([("error", message)]))
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Full<Bytes>> {
+    let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    response.headers_mut().insert(ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::watch;
+
+    use crate::registry::{Registration, ServiceRegistry};
+
+    /// Serve the admin API on an ephemeral port, with one `orders` instance whose ID is returned
+    async fn admin() -> (SocketAddr, String) {
+        let mut registry = ServiceRegistry::new();
+        let registration = Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]));
+        let instance_id = registry.register(registration).unwrap().instance_id;
+        let metrics = registry.metrics();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The sender is dropped, so the API is served until the test ends
+        let shutdown = Shutdown::new(watch::channel(false).1, Duration::from_secs(1));
+        let registry = Arc::new(SharedRegistry::new(registry));
+        tokio::spawn(async move { serve_admin(listener, registry, metrics, shutdown).await.unwrap() });
+        (addr, instance_id)
+    }
+
+    /// Send a request and return the status, the `Allow` header if any and the body of the response
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Option<String>, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: admin\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head[9..12].parse().unwrap();
+        let allow = head
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(name, _)| name.eq_ignore_ascii_case("allow"))
+            .map(|(_, value)| value.to_string());
+        (status, allow, body.to_string())
+    }
+
+    #[test]
+    fn path_segments_are_percent_decoded() {
+        assert_eq!(percent_decode("orders").as_deref(), Some("orders"));
+        assert_eq!(percent_decode("orders%2D0").as_deref(), Some("orders-0"));
+        assert_eq!(percent_decode("%6f%72ders").as_deref(), Some("orders"));
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("caf\u{e9}"));
+        for malformed in ["%", "%2", "%zz", "%+1", "%C3"] {
+            assert_eq!(percent_decode(malformed), None, "{}", malformed);
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_paths_reach_their_service_and_instance() {
+        let (addr, instance_id) = admin().await;
+
+        let (status, _, body) = request(addr, "GET", "/v1/services/%6Frders/", "").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains(&format!("\"instance_id\": \"{}\"", instance_id)), "{}", body);
+
+        let encoded = instance_id.replace('-', "%2d");
+        let path = format!("/v1/services/orders/instances/{}/status", encoded);
+        let (status, _, body) = request(addr, "PUT", &path, r#"{"status": "draining"}"#).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("\"status\": \"draining\""), "{}", body);
+
+        let (status, _, body) = request(addr, "GET", "/v1/services/Orders", "").await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("\"name\": \"orders\""), "{}", body);
+
+        let (status, _, body) = request(addr, "GET", "/v1/services/or%zzders", "").await;
+        assert_eq!(status, 400, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_services_and_instances_are_not_found() {
+        let (addr, _) = admin().await;
+
+        for (method, path) in [
+            ("GET", "/"),
+            ("GET", "/v2/services"),
+            ("GET", "/v1/services/orders/instances"),
+            ("GET", "/v1/services/billing"),
+            ("DELETE", "/v1/services/billing"),
+        ] {
+            let (status, _, body) = request(addr, method, path, "").await;
+            assert_eq!(status, 404, "{} {}", method, path);
+            assert!(body.contains("\"error\""), "{}", body);
+        }
+        let path = "/v1/services/orders/instances/orders-99/status";
+        let (status, _, body) = request(addr, "PUT", path, r#"{"status": "ready"}"#).await;
+        assert_eq!(status, 404);
+        assert!(body.contains("Instance not found"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn other_methods_are_not_allowed_and_list_the_allowed_ones() {
+        let (addr, instance_id) = admin().await;
+        let status_path = format!("/v1/services/orders/instances/{}/status", instance_id);
+
+        for (method, path, allowed) in [
+            ("POST", "/v1/services", "GET"),
+            ("DELETE", "/v1/health", "GET"),
+            ("PUT", "/metrics", "GET"),
+            ("PATCH", "/v1/services/orders", "GET, DELETE"),
+            ("GET", status_path.as_str(), "PUT"),
+        ] {
+            let (status, allow, _) = request(addr, method, path, "").await;
+            assert_eq!(status, 405, "{} {}", method, path);
+            assert_eq!(allow.as_deref(), Some(allowed), "{} {}", method, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_status_bodies_are_bad_requests() {
+        let (addr, instance_id) = admin().await;
+        let path = format!("/v1/services/orders/instances/{}/status", instance_id);
+
+        let too_long = format!(r#"{{"status": "ready", "padding": "{}"}}"#, "x".repeat(MAX_BODY_LEN));
+        for (body, error) in [
+            ("", "Invalid request body"),
+            ("draining", "Invalid request body"),
+            (r#"{"state": "draining"}"#, "Invalid request body"),
+            (r#"{"status": "offline"}"#, "Status must be ready, draining or maintenance"),
+            (r#"{"status": "sleeping"}"#, "Status must be ready, draining or maintenance"),
+            (too_long.as_str(), "Cannot read request body"),
+        ] {
+            let (status, _, response) = request(addr, "PUT", &path, body).await;
+            assert_eq!(status, 400, "{}", body);
+            assert!(response.contains(error), "{}: {}", body, response);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Load balancing for the NetSel proxies
+//!
+//! This module provides the `LoadBalancer`, which picks one of a service's ready instances for every proxied
+//! connection according to a per-service `LoadBalanceStrategy`, and keeps track of the number of active
+//! connections to each instance.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::random_u64;
+use crate::registry::ServiceInfo;
+
+/// Active connection counts, keyed by hostname and instance ID since instance IDs are only unique per service
+type ActiveConnections = Arc<Mutex<HashMap<(String, String), usize>>>;
+
+/// Strategy used to choose an instance among the ready instances of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadBalanceStrategy {
+    /// Cycle through the instances in turn
+    #[default]
+    RoundRobin,
+    /// Pick an instance at random
+    Random,
+    /// Pick the instance with the fewest active proxied connections
+    LeastConnections,
+    /// Pick an instance from a hash of the client IP, so a client keeps hitting the same instance
+    ConsistentHash,
+}
+
+impl FromStr for LoadBalanceStrategy {
+    type Err = String;
+
+    /// Parse a strategy name such as `round_robin` or `LeastConnections`, ignoring case, `_` and `-`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['_', '-'], "").as_str() {
+            "roundrobin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            "leastconnections" => Ok(Self::LeastConnections),
+            "consistenthash" => Ok(Self::ConsistentHash),
+            _ => Err(format!(
+                "Unknown load balancing strategy {}, expected round_robin, random, least_connections or consistent_hash",
+                s
+            )),
+        }
+    }
+}
+
+/// Load balancer shared by the TCP and HTTP proxies
+///
+/// # Example
+///
+/// ```rust
+/// use netsel::balancer::{LoadBalanceStrategy, LoadBalancer};
+///
+/// let balancer = LoadBalancer::new(LoadBalanceStrategy::RoundRobin);
+/// balancer.set_strategy("orders", LoadBalanceStrategy::LeastConnections);
+///
+/// assert_eq!(balancer.strategy_for("orders"), LoadBalanceStrategy::LeastConnections);
+/// assert_eq!(balancer.strategy_for("billing"), LoadBalanceStrategy::RoundRobin);
+/// ```
+pub struct LoadBalancer {
+    default_strategy: LoadBalanceStrategy,
+    strategies: Mutex<HashMap<String, LoadBalanceStrategy>>,
+    round_robin: Mutex<HashMap<String, usize>>,
+    active: ActiveConnections,
+}
+
+impl Default for LoadBalancer {
+    fn default() -> Self {
+        Self::new(LoadBalanceStrategy::default())
+    }
+}
+
+impl LoadBalancer {
+    /// Create a load balancer using `default_strategy` for services without an explicit strategy
+    pub fn new(default_strategy: LoadBalanceStrategy) -> Self {
+        Self {
+            default_strategy,
+            strategies: Mutex::new(HashMap::new()),
+            round_robin: Mutex::new(HashMap::new()),
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Select the strategy used for `service`, whose name is matched regardless of case
+    pub fn set_strategy(&self, service: &str, strategy: LoadBalanceStrategy) {
+        self.strategies.lock().unwrap().insert(service.to_ascii_lowercase(), strategy);
+    }
+
+    /// Strategy currently used for `service`
+    pub fn strategy_for(&self, service: &str) -> LoadBalanceStrategy {
+        self.strategies
+            .lock()
+            .unwrap()
+            .get(&service.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(self.default_strategy)
+    }
+
+    /// Pick one of `instances` of `service` for a connection from `client_ip`
+    pub fn select<'a>(
+        &self,
+        service: &str,
+        instances: &[&'a ServiceInfo],
+        client_ip: IpAddr,
+    ) -> Option<&'a ServiceInfo> {
+        if instances.is_empty() {
+            return None;
+        }
+
+        let index = match self.strategy_for(service) {
+            LoadBalanceStrategy::RoundRobin => {
+                let mut round_robin = self.round_robin.lock().unwrap();
+                let next = round_robin.entry(service.to_string()).or_insert(0);
+                let index = *next % instances.len();
+                *next = next.wrapping_add(1);
+                index
+            }
+            LoadBalanceStrategy::Random => (random_u64() % instances.len() as u64) as usize,
+            LoadBalanceStrategy::LeastConnections => {
+                let active = self.active.lock().unwrap();
+                (0..instances.len())
+                    .min_by_key(|&i| {
+                        let key = (instances[i].hostname.clone(), instances[i].instance_id.clone());
+                        active.get(&key).copied().unwrap_or(0)
+                    })
+                    .unwrap_or(0)
+            }
+            LoadBalanceStrategy::ConsistentHash => {
+                // Rendezvous hashing: adding or removing an instance only moves the clients that hashed to it
+                (0..instances.len())
+                    .max_by_key(|&i| {
+                        let mut hasher = DefaultHasher::new();
+                        client_ip.hash(&mut hasher);
+                        instances[i].instance_id.hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .unwrap_or(0)
+            }
+        };
+        Some(instances[index])
+    }
+
+    /// Record a new active connection to `instance`, which lasts until the returned guard is dropped
+    pub fn connect(&self, instance: &ServiceInfo) -> ConnectionGuard {
+        let key = (instance.hostname.clone(), instance.instance_id.clone());
+        *self.active.lock().unwrap().entry(key.clone()).or_insert(0) += 1;
+
+        ConnectionGuard {
+            active: self.active.clone(),
+            key,
+        }
+    }
+
+    /// Number of active proxied connections to the instance `instance_id` of `hostname`
+    pub fn active_connections(&self, hostname: &str, instance_id: &str) -> usize {
+        let key = (hostname.to_string(), instance_id.to_string());
+        self.active.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+}
+
+/// Marks a proxied connection to an instance as active for as long as it is alive
+pub struct ConnectionGuard {
+    active: ActiveConnections,
+    key: (String, String),
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registry::{Registration, ServiceRegistry};
+
+    /// A registry holding `count` instances of `orders`
+    fn registry(count: u16) -> ServiceRegistry {
+        let mut registry = ServiceRegistry::new();
+        for port in 0..count {
+            let mut registration = Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]));
+            registration.listen_port = Some(8000 + port);
+            registry.register(registration).unwrap();
+        }
+        registry
+    }
+
+    fn client(last_octet: u8) -> IpAddr {
+        IpAddr::from([192, 168, 0, last_octet])
+    }
+
+    /// Instance IDs picked by `count` selections from the same client
+    fn picks(balancer: &LoadBalancer, instances: &[&ServiceInfo], count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| balancer.select("orders", instances, client(1)).unwrap().instance_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn strategies_are_set_per_service_regardless_of_case() {
+        let balancer = LoadBalancer::new(LoadBalanceStrategy::Random);
+        balancer.set_strategy("Orders", LoadBalanceStrategy::LeastConnections);
+
+        assert_eq!(balancer.strategy_for("orders"), LoadBalanceStrategy::LeastConnections);
+        assert_eq!(balancer.strategy_for("ORDERS"), LoadBalanceStrategy::LeastConnections);
+        assert_eq!(balancer.strategy_for("billing"), LoadBalanceStrategy::Random);
+    }
+
+    #[test]
+    fn nothing_is_selected_without_instances() {
+        let balancer = LoadBalancer::default();
+        assert!(balancer.select("orders", &[], client(1)).is_none());
+    }
+
+    #[test]
+    fn round_robin_cycles_through_the_instances() {
+        let registry = registry(3);
+        let instances = registry.get_instances("orders");
+        let ids: Vec<String> = instances.iter().map(|info| info.instance_id.clone()).collect();
+
+        let balancer = LoadBalancer::new(LoadBalanceStrategy::RoundRobin);
+        let expected: Vec<String> = ids.iter().chain(&ids).cloned().collect();
+        assert_eq!(picks(&balancer, &instances, 6), expected);
+    }
+
+    #[test]
+    fn random_picks_among_the_instances() {
+        let registry = registry(3);
+        let instances = registry.get_instances("orders");
+
+        let balancer = LoadBalancer::new(LoadBalanceStrategy::Random);
+        let picked = picks(&balancer, &instances, 100);
+        assert!(picked.iter().all(|id| instances.iter().any(|info| &info.instance_id == id)));
+        // 100 picks landing on a single instance out of three would be a broken generator
+        assert!(picked.iter().any(|id| id != &picked[0]));
+    }
+
+    #[test]
+    fn least_connections_picks_the_least_loaded_instance() {
+        let registry = registry(2);
+        let instances = registry.get_instances("orders");
+        let balancer = LoadBalancer::new(LoadBalanceStrategy::LeastConnections);
+
+        let first = balancer.select("orders", &instances, client(1)).unwrap();
+        let _guard = balancer.connect(first);
+        let second = balancer.select("orders", &instances, client(1)).unwrap();
+        assert_ne!(second.instance_id, first.instance_id);
+
+        let guard = balancer.connect(second);
+        let third = balancer.connect(second);
+        assert_eq!(balancer.select("orders", &instances, client(1)).unwrap().instance_id, first.instance_id);
+
+        // Closing connections makes the instance the least loaded again
+        drop(guard);
+        drop(third);
+        assert_eq!(balancer.select("orders", &instances, client(1)).unwrap().instance_id, second.instance_id);
+    }
+
+    #[test]
+    fn consistent_hash_keeps_a_client_on_the_same_instance() {
+        let registry = registry(4);
+        let instances = registry.get_instances("orders");
+        let balancer = LoadBalancer::new(LoadBalanceStrategy::ConsistentHash);
+
+        let mut chosen = Vec::new();
+        for octet in 1..=50 {
+            let first = balancer.select("orders", &instances, client(octet)).unwrap();
+            for _ in 0..5 {
+                assert_eq!(balancer.select("orders", &instances, client(octet)).unwrap().instance_id, first.instance_id);
+            }
+            chosen.push(first.instance_id.clone());
+        }
+        // Clients are spread over the instances
+        assert!(chosen.iter().any(|id| id != &chosen[0]));
+
+        // Removing an instance only moves the clients that were on it
+        let removed = instances[0].instance_id.clone();
+        let remaining: Vec<&ServiceInfo> = instances[1..].to_vec();
+        for (octet, id) in (1..=50).zip(&chosen) {
+            let now = &balancer.select("orders", &remaining, client(octet)).unwrap().instance_id;
+            if id != &removed {
+                assert_eq!(now, id);
+            }
+        }
+    }
+
+    #[test]
+    fn connection_guards_count_active_connections_per_instance() {
+        let registry = registry(2);
+        let instances = registry.get_instances("orders");
+        let balancer = LoadBalancer::default();
+        let (a, b) = (instances[0], instances[1]);
+
+        let first = balancer.connect(a);
+        let second = balancer.connect(a);
+        assert_eq!(balancer.active_connections("orders", &a.instance_id), 2);
+        assert_eq!(balancer.active_connections("orders", &b.instance_id), 0);
+
+        drop(first);
+        assert_eq!(balancer.active_connections("orders", &a.instance_id), 1);
+        drop(second);
+        assert_eq!(balancer.active_connections("orders", &a.instance_id), 0);
+        assert!(balancer.active.lock().unwrap().is_empty());
+    }
+}
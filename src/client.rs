@@ -1,18 +1,40 @@
 //! Service client implementation for NetSel
 //! 
-//! This module provides the `ServiceClient` struct, which allows services to register with the NetSel server
-//! and send heartbeat messages to maintain their health status.
+//! This module provides the `ServiceClient` struct, which allows services to register with the NetSel server,
+//! send heartbeat messages to maintain their health status, deregister on shutdown and look up other services.
+//! It speaks the framed protocol from [`crate::protocol`].
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::{IpAddr, SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::protocol::{self, ErrorCode, EventKind, HealthCheck, InstanceRecord, Message};
+use crate::random_u64;
+
+/// Capacity of the client event channel
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+
+/// Heartbeat interval of [`ServiceClient::start_lease_heartbeat`] before a TTL was granted
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Shortest interval between two heartbeats, so a zero or tiny interval does not flood the registry
+const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest wait for the NetSel server to accept a request and answer it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Service client for registering with NetSel server and sending heartbeats
 /// 
 /// This struct provides methods to register a service with the NetSel server, send periodic heartbeat
 /// messages, and retrieve information about the assigned address.
 /// 
-/// The `ServiceClient` is `Clone`able, making it easy to share between tasks.
+/// The `ServiceClient` is `Clone`able, making it easy to share between tasks. Clones share the registration
+/// state, so a re-registration done by one clone (e.g. the heartbeat task) is visible to all of them.
 /// 
 /// # Example
 /// 
@@ -34,16 +56,8 @@ use tokio::net::TcpStream;
 ///     let (assigned_ip, assigned_port) = client.register().await?;
 ///     println!("Successfully registered! Assigned address: {}:{}", assigned_ip, assigned_port);
 ///     
-///     // Start sending heartbeats
-///     let heartbeat_client = client.clone();
-///     tokio::spawn(async move {
-///         loop {
-///             if let Err(e) = heartbeat_client.send_heartbeat().await {
-///                 eprintln!("Heartbeat failed: {}", e);
-///             }
-///             tokio::time::sleep(Duration::from_secs(10)).await;
-///         }
-///     });
+///     // Start sending heartbeats every 10 seconds; they stop when the handle is dropped
+///     let _heartbeat = client.start_heartbeat(Duration::from_secs(10));
 ///     
 ///     Ok(())
 /// }
@@ -52,9 +66,37 @@ use tokio::net::TcpStream;
 pub struct ServiceClient {
     server_addr: SocketAddr,
     hostname: String,
-    registered: bool,
+    listen_host: Option<String>,
+    listen_port: Option<u16>,
+    version: Option<String>,
+    tags: BTreeSet<String>,
+    metadata: BTreeMap<String, String>,
+    health_check: Option<HealthCheck>,
+    ttl: Option<Duration>,
+    state: Arc<Mutex<RegistrationState>>,
+    events: broadcast::Sender<ClientEvent>,
+}
+
+/// Registration state shared by all clones of a `ServiceClient`
+#[derive(Default)]
+struct RegistrationState {
+    instance_id: Option<String>,
     assigned_ip: Option<IpAddr>,
     assigned_port: Option<u16>,
+    ttl: Option<Duration>,
+}
+
+/// Event reported by a `ServiceClient` to the application
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// The registry no longer knew this instance and the client registered it again with the same address
+    Reregistered { instance_id: String, addr: SocketAddr },
+    /// The client registered again but the registry could not keep the previous address
+    AddressChanged {
+        instance_id: String,
+        old_addr: Option<SocketAddr>,
+        new_addr: SocketAddr,
+    },
 }
 
 impl ServiceClient {
@@ -63,7 +105,7 @@ impl ServiceClient {
     /// # Arguments
     /// 
     /// * `server_addr` - The address of the NetSel server's registration endpoint
-    /// * `hostname` - The unique hostname for this service
+    /// * `hostname` - The service name; several clients may register under the same name as separate instances
     /// 
     /// # Example
     /// 
@@ -80,16 +122,161 @@ impl ServiceClient {
         Self {
             server_addr,
             hostname,
-            registered: false,
-            assigned_ip: None,
-            assigned_port: None,
+            listen_host: None,
+            listen_port: None,
+            version: None,
+            tags: BTreeSet::new(),
+            metadata: BTreeMap::new(),
+            health_check: None,
+            ttl: None,
+            state: Arc::new(Mutex::new(RegistrationState::default())),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
         }
     }
     
+    /// Set the host the service listens on
+    /// 
+    /// The host (an IP address or a name the server can resolve) is advertised at registration so that the proxies
+    /// and DNS point at it. Without it, the server records the IP the registration came from.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `host` - The host the service listens on
+    pub fn set_listen_host(&mut self, host: String) {
+        self.listen_host = Some(host);
+    }
+    
+    /// Set the port the service listens on
+    /// 
+    /// The port is advertised at registration so that the proxies and DNS point at it. Without it, the service is
+    /// expected to listen on the port assigned at registration.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `port` - The port the service listens on
+    /// 
+    /// # Example
+    /// 
+    /// ```rust
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    /// let mut client = ServiceClient::new(server_addr, "my-service".to_string());
+    /// client.set_listen_host("10.1.2.3".to_string());
+    /// client.set_listen_port(8080);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = Some(port);
+    }
+    
+    /// Set the version of the service, sent at registration
+    /// 
+    /// The version, tags and metadata are returned by registry queries and published in DNS TXT records, so other
+    /// services and deploy tooling can tell instances apart.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `version` - The version of the service, e.g. `1.4.0`
+    /// 
+    /// # Example
+    /// 
+    /// ```rust
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    /// let mut client = ServiceClient::new(server_addr, "orders".to_string());
+    /// client.set_version("1.4.0".to_string());
+    /// client.add_tag("canary".to_string());
+    /// client.set_metadata("region".to_string(), "eu-west-1".to_string());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_version(&mut self, version: String) {
+        self.version = Some(version);
+    }
+    
+    /// Add a tag to the service, sent at registration
+    /// 
+    /// # Arguments
+    /// 
+    /// * `tag` - A free-form label, e.g. `canary` or `stable`
+    pub fn add_tag(&mut self, tag: String) {
+        self.tags.insert(tag);
+    }
+    
+    /// Set a metadata entry of the service, sent at registration
+    /// 
+    /// # Arguments
+    /// 
+    /// * `key` - The metadata key
+    /// * `value` - The metadata value
+    pub fn set_metadata(&mut self, key: String, value: String) {
+        self.metadata.insert(key, value);
+    }
+    
+    /// Ask the server to actively check the service, in addition to heartbeats
+    /// 
+    /// The check is sent at registration and targets the advertised address. While it fails, the instance stays
+    /// registered but receives no traffic.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `check` - A TCP connect or HTTP GET check, with its interval, timeout and failure threshold
+    /// 
+    /// # Example
+    /// 
+    /// ```rust
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::ServiceClient;
+    /// use netsel::protocol::HealthCheck;
+    /// 
+    /// let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    /// let mut client = ServiceClient::new(server_addr, "orders".to_string());
+    /// client.set_listen_port(8080);
+    /// client.set_health_check(HealthCheck::http("/health"));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_health_check(&mut self, check: HealthCheck) {
+        self.health_check = Some(check);
+    }
+    
+    /// Request a lease TTL at registration
+    /// 
+    /// The server clamps it to its configured bounds; the granted TTL is available through [`ServiceClient::ttl`].
+    /// Without a heartbeat the instance becomes suspect after half of its TTL and stops receiving traffic after all
+    /// of it. Without a request, the server grants its default TTL.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `ttl` - The requested TTL, in whole seconds
+    /// 
+    /// # Example
+    /// 
+    /// ```rust
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use std::time::Duration;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    /// let mut client = ServiceClient::new(server_addr, "orders".to_string());
+    /// client.set_ttl(Duration::from_secs(15));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+    }
+    
     /// Register the service with the NetSel server
     /// 
-    /// This method sends a registration request to the NetSel server, which will assign an IP address
-    /// and port to the service. It returns the assigned IP and port if registration is successful.
+    /// This method sends a registration request to the NetSel server, which will create a new instance of the
+    /// service and assign it an IP address and port. It returns the assigned IP and port if registration is
+    /// successful; the generated instance ID is available through [`ServiceClient::instance_id`].
     /// 
     /// # Returns
     /// 
@@ -115,36 +302,60 @@ impl ServiceClient {
     /// }
     /// ```
     pub async fn register(&mut self) -> Result<(IpAddr, u16), Box<dyn std::error::Error>> {
-        let mut stream = TcpStream::connect(self.server_addr).await?;
-        
-        // Send hostname (padded to 256 bytes with null terminator)
-        let mut hostname_buf = [0u8; 256];
-        let hostname_bytes = self.hostname.as_bytes();
-        hostname_buf[..hostname_bytes.len()].copy_from_slice(hostname_bytes);
-        stream.write_all(&hostname_buf).await?;
-        
-        // Read response
-        let mut response_buf = [0u8; 512];
-        let n = stream.read(&mut response_buf).await?;
-        let response = String::from_utf8_lossy(&response_buf[..n])
-            .trim_end_matches(char::from(0))
-            .to_string();
-        
-        println!("Registration response: {}", response);
+        self.register_instance(None).await
+    }
+    
+    /// Register with the NetSel server, asking to keep the current assignment if there is one
+    /// 
+    /// When `replacing` is set, the registration only goes ahead, and is only kept, while the state still holds that
+    /// instance ID: an instance registered after the application deregistered meanwhile is removed again.
+    async fn register_instance(&self, replacing: Option<&str>) -> Result<(IpAddr, u16), Box<dyn std::error::Error>> {
+        let (instance_id, ip, port) = {
+            let state = self.state.lock().unwrap();
+            if replacing.is_some() && state.instance_id.as_deref() != replacing {
+                return Err("Service was deregistered".into());
+            }
+            (state.instance_id.clone(), state.assigned_ip, state.assigned_port)
+        };
+        let request = Message::Register {
+            hostname: self.hostname.clone(),
+            listen_host: self.listen_host.clone(),
+            listen_port: self.listen_port,
+            instance_id,
+            ip,
+            port,
+            version: self.version.clone(),
+            tags: self.tags.clone(),
+            metadata: self.metadata.clone(),
+            health_check: self.health_check.clone(),
+            ttl: self.ttl.map(|ttl| u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX)),
+        };
+        let (instance_id, ip, port, ttl) = match self.request(&request).await? {
+            Message::Registered { instance_id, ip, port, ttl } => (instance_id, ip, port, ttl),
+            Message::Error { message, .. } => return Err(format!("Registration failed: {}", message).into()),
+            other => return Err(format!("Unexpected registration response: {:?}", other).into()),
+        };
         
-        let parts: Vec<&str> = response.split('|').collect();
-        if parts.len() < 4 || parts[0] != "SUCCESS" {
-            return Err(format!("Registration failed: {}", response).into());
+        {
+            let mut state = self.state.lock().unwrap();
+            if replacing.is_none() || state.instance_id.as_deref() == replacing {
+                info!(hostname = %self.hostname, %instance_id, %ip, port, ttl_secs = ttl, "Registered");
+                state.instance_id = Some(instance_id);
+                state.assigned_ip = Some(ip);
+                state.assigned_port = Some(port);
+                state.ttl = Some(Duration::from_secs(ttl.into()));
+                return Ok((ip, port));
+            }
         }
         
-        let ip = parts[1].parse::<IpAddr>()?;
-        let port = parts[2].parse::<u16>()?;
-        
-        self.registered = true;
-        self.assigned_ip = Some(ip);
-        self.assigned_port = Some(port);
-        
-        Ok((ip, port))
+        let request = Message::Deregister {
+            hostname: self.hostname.clone(),
+            instance_id,
+        };
+        if let Err(e) = self.request(&request).await {
+            warn!(hostname = %self.hostname, error = %e, "Could not remove the instance registered meanwhile");
+        }
+        Err("Service was deregistered".into())
     }
     
     /// Send a heartbeat message to the NetSel server
@@ -152,10 +363,14 @@ impl ServiceClient {
     /// This method sends a heartbeat message to the NetSel server to indicate that the service is still alive.
     /// Heartbeat messages should be sent periodically to maintain the service's health status.
     /// 
+    /// If the server no longer knows this instance (for example after a registry restart or after it was evicted),
+    /// the client registers again, asking to keep its instance ID and assigned address. The outcome is reported
+    /// through [`ServiceClient::events`].
+    /// 
     /// # Returns
     /// 
-    /// * `Ok(())` - If the heartbeat is sent successfully
-    /// * `Err(Box<dyn std::error::Error>)` - If sending the heartbeat fails
+    /// * `Ok(())` - If the heartbeat was acknowledged, or the service was registered again
+    /// * `Err(Box<dyn std::error::Error>)` - If the service is not registered or the heartbeat fails
     /// 
     /// # Example
     /// 
@@ -180,19 +395,377 @@ impl ServiceClient {
     /// }
     /// ```
     pub async fn send_heartbeat(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // For simplicity, we'll reuse the registration protocol for heartbeats
-        let mut stream = TcpStream::connect(self.server_addr).await?;
+        let instance_id = self.instance_id().ok_or("Service is not registered")?;
+        let heartbeat = Message::Heartbeat {
+            hostname: self.hostname.clone(),
+            instance_id: instance_id.clone(),
+        };
         
-        // Send hostname with a special prefix for heartbeat
-        let heartbeat_msg = format!("HEARTBEAT|{}", self.hostname);
-        let mut buf = [0u8; 256];
-        let msg_bytes = heartbeat_msg.as_bytes();
-        buf[..msg_bytes.len()].copy_from_slice(msg_bytes);
-        stream.write_all(&buf).await?;
+        let response = self.request(&heartbeat).await?;
+        match response {
+            Message::HeartbeatAck => Ok(()),
+            Message::Error { code: ErrorCode::UnknownInstance, .. } => self.reregister(&instance_id).await,
+            Message::Error { message, .. } => Err(format!("Heartbeat failed: {}", message).into()),
+            other => Err(format!("Unexpected heartbeat response: {:?}", other).into()),
+        }
+    }
+    
+    /// Register again after the server forgot `instance_id`, and report the outcome to the application
+    /// 
+    /// Nothing is registered if the application deregistered, or another clone registered again, while the heartbeat
+    /// was in flight.
+    async fn reregister(&self, instance_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self.instance_id() {
+            Some(current) if current == instance_id => {}
+            Some(_) => return Ok(()),
+            None => return Err("Service was deregistered".into()),
+        }
+        let old_addr = self.get_assigned_addr();
+        warn!(hostname = %self.hostname, "Instance unknown to the registry, registering again");
+        
+        let (ip, port) = self.register_instance(Some(instance_id)).await?;
+        let new_addr = SocketAddr::new(ip, port);
+        let instance_id = self.instance_id().unwrap_or_default();
+        
+        let event = if old_addr == Some(new_addr) {
+            ClientEvent::Reregistered { instance_id, addr: new_addr }
+        } else {
+            ClientEvent::AddressChanged { instance_id, old_addr, new_addr }
+        };
+        // Having no subscriber is fine
+        let _ = self.events.send(event);
         
         Ok(())
     }
     
+    /// Subscribe to events about this client's registration
+    /// 
+    /// Events are sent when the client transparently registers again, in particular when the server could not
+    /// keep the previously assigned address.
+    /// 
+    /// # Example
+    /// 
+    /// ```rust,ignore
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::{ClientEvent, ServiceClient};
+    /// 
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    ///     let client = ServiceClient::new(server_addr, "my-service".to_string());
+    ///     let mut events = client.events();
+    ///     
+    ///     while let Ok(event) = events.recv().await {
+    ///         if let ClientEvent::AddressChanged { new_addr, .. } = event {
+    ///             println!("Now reachable at {}", new_addr);
+    ///         }
+    ///     }
+    ///     
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+    
+    /// Start sending heartbeats in the background
+    /// 
+    /// This method spawns a task that calls [`ServiceClient::send_heartbeat`] every `interval`, with up to 10% of
+    /// random jitter so that many services do not heartbeat in lockstep. After a failure the next attempt comes
+    /// sooner, backing off exponentially from half a second up to `interval` while failures persist. A heartbeat
+    /// the server has not answered within `interval` counts as a failure.
+    /// 
+    /// The task runs until the returned handle is dropped or [`HeartbeatHandle::stop`] is called.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `interval` - Time between two successful heartbeats, raised to 100ms if shorter
+    /// 
+    /// # Example
+    /// 
+    /// ```rust,ignore
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use tokio::time::Duration;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    ///     let mut client = ServiceClient::new(server_addr, "my-service".to_string());
+    ///     client.register().await?;
+    ///     
+    ///     let heartbeat = client.start_heartbeat(Duration::from_secs(10));
+    ///     
+    ///     // ... later
+    ///     if let Some(last_success) = heartbeat.last_success() {
+    ///         println!("Last heartbeat {:?} ago", last_success.elapsed());
+    ///     }
+    ///     
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn start_heartbeat(&self, interval: Duration) -> HeartbeatHandle {
+        self.spawn_heartbeat(move |_| interval)
+    }
+    
+    /// Start sending heartbeats in the background, at an interval derived from the granted lease TTL
+    /// 
+    /// Heartbeats are sent every third of the TTL granted at the last registration (see
+    /// [`ServiceClient::heartbeat_interval`]), so a single lost heartbeat does not make the instance suspect. A
+    /// re-registration granting a different TTL changes the interval accordingly. Otherwise this behaves like
+    /// [`ServiceClient::start_heartbeat`], including its 100ms floor on the interval.
+    /// 
+    /// # Example
+    /// 
+    /// ```rust,ignore
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use tokio::time::Duration;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    ///     let mut client = ServiceClient::new(server_addr, "my-service".to_string());
+    ///     client.set_ttl(Duration::from_secs(30));
+    ///     client.register().await?;
+    ///     
+    ///     // Heartbeats every 10 seconds, if the server granted the requested TTL
+    ///     let _heartbeat = client.start_lease_heartbeat();
+    ///     
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn start_lease_heartbeat(&self) -> HeartbeatHandle {
+        self.spawn_heartbeat(|client| client.heartbeat_interval().unwrap_or(DEFAULT_HEARTBEAT_INTERVAL))
+    }
+    
+    /// Spawn the heartbeat task, reading the interval between two successful heartbeats before each wait
+    fn spawn_heartbeat<F>(&self, interval: F) -> HeartbeatHandle
+    where
+        F: Fn(&ServiceClient) -> Duration + Send + 'static,
+    {
+        let client = self.clone();
+        let status = Arc::new(Mutex::new(HeartbeatStatus::default()));
+        let task_status = status.clone();
+        
+        let task = tokio::spawn(async move {
+            loop {
+                // An unresponsive server must not hold the task past the time the next heartbeat is due
+                let timeout = interval(&client).max(MIN_HEARTBEAT_INTERVAL);
+                let result = match tokio::time::timeout(timeout, client.send_heartbeat()).await {
+                    Ok(result) => result.map_err(|e| e.to_string()),
+                    Err(_) => Err(format!("Heartbeat not answered within {:?}", timeout)),
+                };
+                let interval = interval(&client).max(MIN_HEARTBEAT_INTERVAL);
+                let delay = {
+                    let mut status = task_status.lock().unwrap();
+                    match result {
+                        Ok(()) => {
+                            status.last_success = Some(Instant::now());
+                            status.consecutive_failures = 0;
+                            interval
+                        }
+                        Err(e) => {
+                            warn!(hostname = %client.hostname, error = %e, "Heartbeat failed");
+                            status.last_failure = Some(Instant::now());
+                            status.last_error = Some(e);
+                            status.consecutive_failures += 1;
+                            retry_delay(status.consecutive_failures, interval)
+                        }
+                    }
+                };
+                tokio::time::sleep(with_jitter(delay)).await;
+            }
+        });
+        
+        HeartbeatHandle { task, status }
+    }
+    
+    /// Deregister the service from the NetSel server
+    /// 
+    /// This method removes this instance from the registry right away, so the proxies and DNS stop sending traffic
+    /// to it without waiting for its heartbeats to time out. Call it before shutting the service down.
+    /// 
+    /// # Returns
+    /// 
+    /// * `Ok(())` - If the instance was removed
+    /// * `Err(Box<dyn std::error::Error>)` - If the service is not registered or deregistration fails
+    /// 
+    /// # Example
+    /// 
+    /// ```rust,ignore
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    ///     let mut client = ServiceClient::new(server_addr, "my-service".to_string());
+    ///     
+    ///     client.register().await?;
+    ///     
+    ///     // ... serve traffic until shutdown
+    ///     
+    ///     client.deregister().await?;
+    ///     
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn deregister(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let instance_id = self.instance_id().ok_or("Service is not registered")?;
+        let request = Message::Deregister {
+            hostname: self.hostname.clone(),
+            instance_id,
+        };
+        match self.request(&request).await? {
+            Message::Deregistered => {}
+            Message::Error { message, .. } => return Err(format!("Deregistration failed: {}", message).into()),
+            other => return Err(format!("Unexpected deregistration response: {:?}", other).into()),
+        }
+        
+        *self.state.lock().unwrap() = RegistrationState::default();
+        
+        Ok(())
+    }
+    
+    /// Query the NetSel server for the instances of a service
+    /// 
+    /// # Arguments
+    /// 
+    /// * `hostname` - The name of the service to look up
+    /// 
+    /// # Returns
+    /// 
+    /// * `Ok(Vec<InstanceRecord>)` - All registered instances of the service, ready or not
+    /// * `Err(Box<dyn std::error::Error>)` - If the query fails
+    /// 
+    /// # Example
+    /// 
+    /// ```rust,ignore
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    ///     let client = ServiceClient::new(server_addr, "my-service".to_string());
+    ///     
+    ///     for instance in client.query("orders").await? {
+    ///         println!("{} listens on {}", instance.instance_id, instance.endpoint);
+    ///     }
+    ///     
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn query(&self, hostname: &str) -> Result<Vec<InstanceRecord>, Box<dyn std::error::Error>> {
+        let request = Message::Query {
+            hostname: hostname.to_string(),
+        };
+        match self.request(&request).await? {
+            Message::QueryResult { instances } => Ok(instances),
+            Message::Error { message, .. } => Err(format!("Query failed: {}", message).into()),
+            other => Err(format!("Unexpected query response: {:?}", other).into()),
+        }
+    }
+    
+    /// Watch the instances of a service
+    /// 
+    /// Opens a long-lived subscription on the NetSel server. The returned [`ServiceWatch`] first yields a
+    /// `Registered` event for every current instance of the service, then one event for each change, as soon as
+    /// the registry sees it.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `hostname` - The name of the service to watch
+    /// 
+    /// # Example
+    /// 
+    /// ```rust,ignore
+    /// use std::net::SocketAddr;
+    /// use std::str::FromStr;
+    /// use netsel::client::ServiceClient;
+    /// 
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server_addr = SocketAddr::from_str("127.0.0.1:9000")?;
+    ///     let client = ServiceClient::new(server_addr, "my-service".to_string());
+    ///     
+    ///     let mut watch = client.watch("orders").await?;
+    ///     while let Some(event) = watch.next().await? {
+    ///         println!("{:?}: {} at {}", event.kind, event.instance.instance_id, event.instance.endpoint);
+    ///     }
+    ///     
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn watch(&self, hostname: &str) -> Result<ServiceWatch, Box<dyn std::error::Error>> {
+        let mut stream = TcpStream::connect(self.server_addr).await?;
+        let request = Message::Watch {
+            hostname: Some(hostname.to_string()),
+        };
+        protocol::write_message(&mut stream, &request).await?;
+        
+        Ok(ServiceWatch { stream })
+    }
+    
+    /// Send the current version, tags and metadata of the registered instance to the NetSel server
+    /// 
+    /// Use this after changing them with [`ServiceClient::set_version`], [`ServiceClient::add_tag`] or
+    /// [`ServiceClient::set_metadata`] once registered; watchers get a `MetadataChanged` event.
+    /// 
+    /// # Returns
+    /// 
+    /// * `Ok(())` - If the server updated the instance
+    /// * `Err(Box<dyn std::error::Error>)` - If the service is not registered or the update fails
+    pub async fn update_metadata(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let instance_id = self.instance_id().ok_or("Service is not registered")?;
+        let request = Message::UpdateMetadata {
+            hostname: self.hostname.clone(),
+            instance_id,
+            version: self.version.clone(),
+            tags: self.tags.clone(),
+            metadata: self.metadata.clone(),
+        };
+        match self.request(&request).await? {
+            Message::MetadataUpdated => Ok(()),
+            Message::Error { message, .. } => Err(format!("Metadata update failed: {}", message).into()),
+            other => Err(format!("Unexpected metadata update response: {:?}", other).into()),
+        }
+    }
+    
+    /// Send a request to the NetSel server and wait for its response, for at most [`REQUEST_TIMEOUT`]
+    async fn request(&self, request: &Message) -> Result<Message, Box<dyn std::error::Error>> {
+        let round_trip = async {
+            let mut stream = TcpStream::connect(self.server_addr).await?;
+            protocol::write_message(&mut stream, request).await?;
+            protocol::read_message(&mut stream).await
+        };
+        match tokio::time::timeout(REQUEST_TIMEOUT, round_trip).await {
+            Ok(Ok(Some(response))) => Ok(response),
+            Ok(Ok(None)) => Err("Connection closed by server".into()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(format!("NetSel server did not answer within {:?}", REQUEST_TIMEOUT).into()),
+        }
+    }
+    
+    /// Get the instance ID assigned to this client during registration
+    /// 
+    /// Every registration creates a separate instance of the service, identified by this ID.
+    /// If the service is not yet registered, it returns None.
+    /// 
+    /// # Returns
+    /// 
+    /// * `Some(String)` - The instance ID if registered
+    /// * `None` - If the service is not registered
+    pub fn instance_id(&self) -> Option<String> {
+        self.state.lock().unwrap().instance_id.clone()
+    }
+    
     /// Get the assigned address for this service
     /// 
     /// This method returns the SocketAddr that was assigned to this service during registration.
@@ -227,12 +800,23 @@ impl ServiceClient {
     /// }
     /// ```
     pub fn get_assigned_addr(&self) -> Option<SocketAddr> {
-        match (self.assigned_ip, self.assigned_port) {
+        let state = self.state.lock().unwrap();
+        match (state.assigned_ip, state.assigned_port) {
             (Some(ip), Some(port)) => Some(SocketAddr::new(ip, port)),
             _ => None,
         }
     }
     
+    /// Get the lease TTL granted at the last registration, if registered
+    pub fn ttl(&self) -> Option<Duration> {
+        self.state.lock().unwrap().ttl
+    }
+    
+    /// Get the heartbeat interval suited to the granted lease TTL: a third of it
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.ttl().map(|ttl| ttl / 3)
+    }
+    
     /// Check if the service is registered
     /// 
     /// This method returns whether the service has been successfully registered with the NetSel server.
@@ -264,6 +848,329 @@ impl ServiceClient {
     /// }
     /// ```
     pub fn is_registered(&self) -> bool {
-        self.registered
+        self.state.lock().unwrap().instance_id.is_some()
+    }
+}
+
+/// Snapshot of the state of a background heartbeat task
+#[derive(Debug, Clone, Default)]
+pub struct HeartbeatStatus {
+    /// When the last heartbeat succeeded
+    pub last_success: Option<Instant>,
+    /// When the last heartbeat failed
+    pub last_failure: Option<Instant>,
+    /// Error of the last failed heartbeat
+    pub last_error: Option<String>,
+    /// Number of failures since the last success
+    pub consecutive_failures: u32,
+}
+
+/// Handle to a background heartbeat task started with [`ServiceClient::start_heartbeat`]
+/// 
+/// Dropping the handle stops the task.
+pub struct HeartbeatHandle {
+    task: JoinHandle<()>,
+    status: Arc<Mutex<HeartbeatStatus>>,
+}
+
+impl HeartbeatHandle {
+    /// Get the current state of the heartbeat task
+    pub fn status(&self) -> HeartbeatStatus {
+        self.status.lock().unwrap().clone()
+    }
+    
+    /// Get the time of the last successful heartbeat, if any
+    pub fn last_success(&self) -> Option<Instant> {
+        self.status.lock().unwrap().last_success
+    }
+    
+    /// Get the time of the last failed heartbeat, if any
+    pub fn last_failure(&self) -> Option<Instant> {
+        self.status.lock().unwrap().last_failure
+    }
+    
+    /// Stop sending heartbeats
+    pub fn stop(self) {
+        // Dropping the handle aborts the task
+    }
+}
+
+impl Drop for HeartbeatHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Delay before retrying after `failures` consecutive heartbeat failures
+fn retry_delay(failures: u32, interval: Duration) -> Duration {
+    let backoff = Duration::from_millis(500).saturating_mul(1 << failures.saturating_sub(1).min(16));
+    backoff.min(interval)
+}
+
+/// Add up to 10% of random jitter to `delay`
+fn with_jitter(delay: Duration) -> Duration {
+    let max_jitter = delay.as_millis() as u64 / 10;
+    if max_jitter == 0 {
+        return delay;
+    }
+    delay + Duration::from_millis(random_u64() % max_jitter)
+}
+
+/// A change to an instance of a watched service
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchEvent {
+    pub kind: EventKind,
+    pub hostname: String,
+    pub instance: InstanceRecord,
+}
+
+/// Subscription to the changes of a service, returned by [`ServiceClient::watch`]
+pub struct ServiceWatch {
+    stream: TcpStream,
+}
+
+impl ServiceWatch {
+    /// Wait for the next event
+    /// 
+    /// # Returns
+    /// 
+    /// * `Ok(Some(WatchEvent))` - The next change
+    /// * `Ok(None)` - If the server closed the subscription
+    /// * `Err(Box<dyn std::error::Error>)` - If the subscription failed, e.g. because it fell too far behind; watch
+    ///   again to rebuild the view of the service
+    pub async fn next(&mut self) -> Result<Option<WatchEvent>, Box<dyn std::error::Error>> {
+        match protocol::read_message(&mut self.stream).await? {
+            Some(Message::Event { kind, hostname, instance }) => Ok(Some(WatchEvent { kind, hostname, instance })),
+            Some(Message::Error { message, .. }) => Err(format!("Watch failed: {}", message).into()),
+            Some(other) => Err(format!("Unexpected watch message: {:?}", other).into()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, oneshot};
+
+    type Requests = mpsc::UnboundedReceiver<(Message, oneshot::Sender<Message>)>;
+
+    /// A registration server handing every request to the test, which answers it
+    async fn fake_server() -> (SocketAddr, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    while let Ok(Some(request)) = protocol::read_message(&mut stream).await {
+                        let (reply, response) = oneshot::channel();
+                        if sender.send((request, reply)).is_err() {
+                            return;
+                        }
+                        let Ok(response) = response.await else {
+                            return;
+                        };
+                        protocol::write_message(&mut stream, &response).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    /// Answer the next request with `response`, returning the request
+    async fn answer(requests: &mut Requests, response: Message) -> Message {
+        let (request, reply) = requests.recv().await.unwrap();
+        reply.send(response).unwrap();
+        request
+    }
+
+    fn registered(instance_id: &str, port: u16) -> Message {
+        Message::Registered {
+            instance_id: instance_id.to_string(),
+            ip: IpAddr::from([10, 0, 0, 100]),
+            port,
+            ttl: 30,
+        }
+    }
+
+    fn unknown_instance() -> Message {
+        Message::error(ErrorCode::UnknownInstance, "Unknown instance")
+    }
+
+    async fn registered_client(requests: &mut Requests, addr: SocketAddr) -> ServiceClient {
+        let mut client = ServiceClient::new(addr, "orders".to_string());
+        let (result, _) = tokio::join!(client.register(), answer(requests, registered("orders-1", 9000)));
+        result.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn a_rejected_heartbeat_registers_again_with_the_same_assignment() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+        let mut events = client.events();
+
+        let (result, (_, register)) = tokio::join!(client.send_heartbeat(), async {
+            let heartbeat = answer(&mut requests, unknown_instance()).await;
+            (heartbeat, answer(&mut requests, registered("orders-1", 9000)).await)
+        });
+        result.unwrap();
+        match register {
+            Message::Register { instance_id, ip, port, .. } => {
+                assert_eq!(instance_id.as_deref(), Some("orders-1"));
+                assert_eq!(ip, Some(IpAddr::from([10, 0, 0, 100])));
+                assert_eq!(port, Some(9000));
+            }
+            other => panic!("Expected a registration, got {:?}", other),
+        }
+        let addr = SocketAddr::from(([10, 0, 0, 100], 9000));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ClientEvent::Reregistered { instance_id: "orders-1".to_string(), addr }
+        );
+    }
+
+    #[tokio::test]
+    async fn registering_again_at_another_address_is_reported() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+        let mut events = client.events();
+
+        let (result, _) = tokio::join!(client.send_heartbeat(), async {
+            answer(&mut requests, unknown_instance()).await;
+            answer(&mut requests, registered("orders-7", 9001)).await
+        });
+        result.unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ClientEvent::AddressChanged {
+                instance_id: "orders-7".to_string(),
+                old_addr: Some(SocketAddr::from(([10, 0, 0, 100], 9000))),
+                new_addr: SocketAddr::from(([10, 0, 0, 100], 9001)),
+            }
+        );
+        assert_eq!(client.instance_id().as_deref(), Some("orders-7"));
+    }
+
+    #[tokio::test]
+    async fn a_heartbeat_rejected_after_deregistration_does_not_register_again() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+
+        let (result, _) = tokio::join!(client.send_heartbeat(), async {
+            // Deregister while the heartbeat waits for its answer
+            let (heartbeat, reply) = requests.recv().await.unwrap();
+            assert!(matches!(heartbeat, Message::Heartbeat { .. }));
+            let (deregistered, _) = tokio::join!(client.deregister(), answer(&mut requests, Message::Deregistered));
+            deregistered.unwrap();
+            reply.send(unknown_instance()).unwrap();
+        });
+        assert!(result.is_err());
+        assert_eq!(client.instance_id(), None);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn an_instance_registered_again_during_deregistration_is_removed() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+
+        let (result, _) = tokio::join!(client.send_heartbeat(), async {
+            answer(&mut requests, unknown_instance()).await;
+            // Deregister while the new registration waits for its answer
+            let (register, reply) = requests.recv().await.unwrap();
+            assert!(matches!(register, Message::Register { .. }));
+            let (deregistered, _) = tokio::join!(client.deregister(), answer(&mut requests, Message::Deregistered));
+            deregistered.unwrap();
+            reply.send(registered("orders-2", 9001)).unwrap();
+
+            match answer(&mut requests, Message::Deregistered).await {
+                Message::Deregister { instance_id, .. } => assert_eq!(instance_id, "orders-2"),
+                other => panic!("Expected a deregistration, got {:?}", other),
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(client.instance_id(), None);
+    }
+
+    #[test]
+    fn retries_back_off_from_half_a_second_up_to_the_interval() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(retry_delay(1, interval), Duration::from_millis(500));
+        assert_eq!(retry_delay(2, interval), Duration::from_secs(1));
+        assert_eq!(retry_delay(4, interval), Duration::from_secs(4));
+        assert_eq!(retry_delay(5, interval), Duration::from_secs(8));
+        assert_eq!(retry_delay(6, interval), interval);
+        assert_eq!(retry_delay(u32::MAX, interval), interval);
+        assert_eq!(retry_delay(1, Duration::from_millis(100)), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_adds_at_most_a_tenth_of_the_delay() {
+        let delay = Duration::from_secs(10);
+        for _ in 0..100 {
+            let jittered = with_jitter(delay);
+            assert!(jittered >= delay && jittered < delay + Duration::from_secs(1), "{:?}", jittered);
+        }
+        assert_eq!(with_jitter(Duration::from_millis(5)), Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn a_zero_heartbeat_interval_is_raised_to_the_floor() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+        let _heartbeat = client.start_heartbeat(Duration::ZERO);
+
+        let started = Instant::now();
+        answer(&mut requests, Message::HeartbeatAck).await;
+        answer(&mut requests, Message::error(ErrorCode::Unavailable, "Shutting down")).await;
+        requests.recv().await.unwrap();
+        // One wait after the success and one after the failure, neither shorter than the floor
+        assert!(started.elapsed() >= 2 * MIN_HEARTBEAT_INTERVAL, "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn an_unanswered_heartbeat_is_recorded_as_a_failure() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+        let heartbeat = client.start_heartbeat(MIN_HEARTBEAT_INTERVAL);
+
+        // Hold the first heartbeat without answering it; the task gives up and tries again
+        let (_unanswered, _reply) = requests.recv().await.unwrap();
+        let (retry, _) = tokio::time::timeout(Duration::from_secs(5), requests.recv()).await.unwrap().unwrap();
+        assert!(matches!(retry, Message::Heartbeat { .. }));
+        let status = heartbeat.status();
+        assert!(status.last_failure.is_some());
+        assert_eq!(status.last_success, None);
+        assert_eq!(status.consecutive_failures, 1);
+        assert!(status.last_error.unwrap().contains("not answered"));
+    }
+
+    #[tokio::test]
+    async fn the_heartbeat_task_records_successes_and_failures_until_dropped() {
+        let (addr, mut requests) = fake_server().await;
+        let client = registered_client(&mut requests, addr).await;
+        let heartbeat = client.start_heartbeat(MIN_HEARTBEAT_INTERVAL);
+
+        assert!(matches!(answer(&mut requests, Message::HeartbeatAck).await, Message::Heartbeat { .. }));
+        answer(&mut requests, Message::error(ErrorCode::Unavailable, "Shutting down")).await;
+
+        // The next heartbeat is only sent once the failure is recorded
+        let (request, reply) = requests.recv().await.unwrap();
+        assert!(matches!(request, Message::Heartbeat { .. }));
+        let status = heartbeat.status();
+        assert!(status.last_success.is_some());
+        assert!(status.last_failure.is_some());
+        assert!(heartbeat.last_success().unwrap() <= heartbeat.last_failure().unwrap());
+        assert_eq!(status.consecutive_failures, 1);
+        assert!(status.last_error.unwrap().contains("Shutting down"));
+
+        drop(heartbeat);
+        drop(reply);
+        assert!(tokio::time::timeout(Duration::from_millis(200), requests.recv()).await.is_err());
     }
 }
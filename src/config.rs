@@ -0,0 +1,471 @@
+//! Configuration loading for NetSel
+//!
+//! A [`NetSelConfig`] can be read from a TOML file whose keys are the field names of the struct, and every scalar
+//! setting can be overridden by a `NETSEL_`-prefixed environment variable (`NETSEL_REGISTRY_ADDR`,
+//! `NETSEL_DNS_ZONE`, ...). Keys missing from both keep their default value.
+//!
+//! ```toml
+//! registry_addr = "0.0.0.0:9000"
+//! tcp_proxy_addr = "0.0.0.0:8080"
+//! http_proxy_addr = "0.0.0.0:8081"
+//! dns_addr = "127.0.0.1:5353"
+//! admin_addr = "127.0.0.1:8082"
+//! dns_zone = "netsel"
+//! health_check_interval = 30
+//! max_heartbeat_age = 60
+//! reap_timeout = 600
+//! min_ttl = 5
+//! max_ttl = 300
+//! flap_threshold = 5
+//! flap_window = 600
+//! flap_cooldown = 300
+//! load_balancing = "round_robin"
+//! legacy_protocol = false
+//! data_dir = "/var/lib/netsel"
+//! snapshot_interval = 300
+//! shutdown_timeout = 30
+//! port_range = [9000, 9999]
+//! subnet = "10.0.0.0/24"
+//! log_format = "json"
+//! log_filter = "info"
+//!
+//! [service_load_balancing]
+//! orders = "least_connections"
+//! ```
+//!
+//! In environment variables, `NETSEL_PORT_RANGE` is written `9000-9999` and an empty `NETSEL_DATA_DIR` disables
+//! persistence. Per-service load balancing can only be set in the file; its service names are lowercased on
+//! loading, since services are looked up in lowercase.
+
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::NetSelConfig;
+
+/// Prefix of the environment variables overriding the configuration
+pub const ENV_PREFIX: &str = "NETSEL_";
+
+/// Configuration file contents, every key being optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    registry_addr: Option<SocketAddr>,
+    tcp_proxy_addr: Option<SocketAddr>,
+    http_proxy_addr: Option<SocketAddr>,
+    dns_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    dns_zone: Option<String>,
+    health_check_interval: Option<u64>,
+    max_heartbeat_age: Option<u64>,
+    reap_timeout: Option<u64>,
+    min_ttl: Option<u64>,
+    max_ttl: Option<u64>,
+    flap_threshold: Option<u32>,
+    flap_window: Option<u64>,
+    flap_cooldown: Option<u64>,
+    load_balancing: Option<String>,
+    service_load_balancing: Option<HashMap<String, String>>,
+    legacy_protocol: Option<bool>,
+    data_dir: Option<PathBuf>,
+    snapshot_interval: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    port_range: Option<[u16; 2]>,
+    subnet: Option<String>,
+    log_format: Option<String>,
+    log_filter: Option<String>,
+}
+
+impl NetSelConfig {
+    /// Read the configuration from a TOML file, using the default value of every missing key
+    ///
+    /// The result is not validated, so that environment overrides can still be applied; see [`NetSelConfig::load`].
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file: ConfigFile = toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let mut config = Self::default();
+        set(&mut config.registry_addr, file.registry_addr);
+        set(&mut config.tcp_proxy_addr, file.tcp_proxy_addr);
+        set(&mut config.http_proxy_addr, file.http_proxy_addr);
+        set(&mut config.dns_addr, file.dns_addr);
+        set(&mut config.admin_addr, file.admin_addr);
+        set(&mut config.dns_zone, file.dns_zone);
+        set(&mut config.health_check_interval, file.health_check_interval);
+        set(&mut config.max_heartbeat_age, file.max_heartbeat_age);
+        set(&mut config.reap_timeout, file.reap_timeout);
+        set(&mut config.min_ttl, file.min_ttl);
+        set(&mut config.max_ttl, file.max_ttl);
+        set(&mut config.flap_threshold, file.flap_threshold);
+        set(&mut config.flap_window, file.flap_window);
+        set(&mut config.flap_cooldown, file.flap_cooldown);
+        if let Some(strategy) = file.load_balancing {
+            config.load_balancing = parse("load_balancing", &strategy)?;
+        }
+        for (service, strategy) in file.service_load_balancing.unwrap_or_default() {
+            let strategy = parse(&format!("service_load_balancing.{}", service), &strategy)?;
+            if config.service_load_balancing.insert(service.to_ascii_lowercase(), strategy).is_some() {
+                return Err(format!("service_load_balancing.{}: service is listed more than once", service).into());
+            }
+        }
+        set(&mut config.legacy_protocol, file.legacy_protocol);
+        if file.data_dir.is_some() {
+            config.data_dir = file.data_dir;
+        }
+        set(&mut config.snapshot_interval, file.snapshot_interval);
+        set(&mut config.shutdown_timeout, file.shutdown_timeout);
+        if let Some([start, end]) = file.port_range {
+            config.port_range = start..=end;
+        }
+        if let Some(subnet) = file.subnet {
+            config.subnet = parse("subnet", &subnet)?;
+        }
+        if let Some(format) = file.log_format {
+            config.log_format = parse("log_format", &format)?;
+        }
+        set(&mut config.log_filter, file.log_filter);
+        Ok(config)
+    }
+
+    /// Override settings from the `NETSEL_*` environment variables that are set
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use netsel::NetSelConfig;
+    ///
+    /// let mut config = NetSelConfig::default();
+    /// // With NETSEL_DNS_ZONE=internal in the environment, `dns_zone` becomes "internal"
+    /// config.apply_env()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn apply_env(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.apply_vars(|name| env::var(name))
+    }
+
+    /// Override settings from the `NETSEL_*` variables read by `var`, which looks a variable up like [`env::var`]
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Result<String, VarError>) -> Result<(), Box<dyn std::error::Error>> {
+        override_from_env(&mut self.registry_addr, &var, "REGISTRY_ADDR")?;
+        override_from_env(&mut self.tcp_proxy_addr, &var, "TCP_PROXY_ADDR")?;
+        override_from_env(&mut self.http_proxy_addr, &var, "HTTP_PROXY_ADDR")?;
+        override_from_env(&mut self.dns_addr, &var, "DNS_ADDR")?;
+        override_from_env(&mut self.admin_addr, &var, "ADMIN_ADDR")?;
+        override_from_env(&mut self.dns_zone, &var, "DNS_ZONE")?;
+        override_from_env(&mut self.health_check_interval, &var, "HEALTH_CHECK_INTERVAL")?;
+        override_from_env(&mut self.max_heartbeat_age, &var, "MAX_HEARTBEAT_AGE")?;
+        override_from_env(&mut self.reap_timeout, &var, "REAP_TIMEOUT")?;
+        override_from_env(&mut self.min_ttl, &var, "MIN_TTL")?;
+        override_from_env(&mut self.max_ttl, &var, "MAX_TTL")?;
+        override_from_env(&mut self.flap_threshold, &var, "FLAP_THRESHOLD")?;
+        override_from_env(&mut self.flap_window, &var, "FLAP_WINDOW")?;
+        override_from_env(&mut self.flap_cooldown, &var, "FLAP_COOLDOWN")?;
+        override_from_env(&mut self.load_balancing, &var, "LOAD_BALANCING")?;
+        override_from_env(&mut self.legacy_protocol, &var, "LEGACY_PROTOCOL")?;
+        if let Some(data_dir) = env_var(&var, "DATA_DIR")? {
+            self.data_dir = (!data_dir.is_empty()).then(|| PathBuf::from(data_dir));
+        }
+        override_from_env(&mut self.snapshot_interval, &var, "SNAPSHOT_INTERVAL")?;
+        override_from_env(&mut self.shutdown_timeout, &var, "SHUTDOWN_TIMEOUT")?;
+        if let Some(port_range) = env_var(&var, "PORT_RANGE")? {
+            self.port_range = parse_port_range(&port_range).map_err(|e| format!("{}PORT_RANGE: {}", ENV_PREFIX, e))?;
+        }
+        override_from_env(&mut self.subnet, &var, "SUBNET")?;
+        override_from_env(&mut self.log_format, &var, "LOG_FORMAT")?;
+        override_from_env(&mut self.log_filter, &var, "LOG_FILTER")?;
+        Ok(())
+    }
+
+    /// Check the configuration for settings the server cannot run with
+    ///
+    /// Rejects listeners sharing an address, zero intervals, lease TTL bounds that do not hold
+    /// `min_ttl <= max_heartbeat_age <= max_ttl <= reap_timeout`, an empty DNS zone, an empty or inverted port range
+    /// and per-service load balancing for a service name that is not lowercase, which would never match.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use netsel::NetSelConfig;
+    ///
+    /// let config = NetSelConfig {
+    ///     tcp_proxy_addr: "0.0.0.0:9000".parse()?,
+    ///     ..NetSelConfig::default()
+    /// };
+    /// assert!(config.validate().is_err()); // same address as the registration server
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // The DNS server binds both UDP and TCP, the other listeners TCP only
+        let listeners = [
+            ("registry_addr", self.registry_addr),
+            ("tcp_proxy_addr", self.tcp_proxy_addr),
+            ("http_proxy_addr", self.http_proxy_addr),
+            ("dns_addr", self.dns_addr),
+            ("admin_addr", self.admin_addr),
+        ];
+        for (i, (name, addr)) in listeners.iter().enumerate() {
+            for (other_name, other_addr) in &listeners[i + 1..] {
+                if clashes(*addr, *other_addr) {
+                    return Err(format!("{} and {} both listen on {}", name, other_name, addr).into());
+                }
+            }
+        }
+
+        for (name, value) in [
+            ("health_check_interval", self.health_check_interval),
+            ("max_heartbeat_age", self.max_heartbeat_age),
+            ("min_ttl", self.min_ttl),
+            ("flap_window", self.flap_window),
+            ("flap_cooldown", self.flap_cooldown),
+            ("snapshot_interval", self.snapshot_interval),
+        ] {
+            if value == 0 {
+                return Err(format!("{} must be greater than zero", name).into());
+            }
+        }
+        let lease = [
+            ("min_ttl", self.min_ttl),
+            ("max_heartbeat_age", self.max_heartbeat_age),
+            ("max_ttl", self.max_ttl),
+            ("reap_timeout", self.reap_timeout),
+        ];
+        for pair in lease.windows(2) {
+            let ((name, value), (next_name, next_value)) = (pair[0], pair[1]);
+            if value > next_value {
+                return Err(format!(
+                    "{} ({}) must not be longer than {} ({})",
+                    name, value, next_name, next_value
+                )
+                .into());
+            }
+        }
+
+        if self.dns_zone.trim_matches('.').is_empty() {
+            return Err("dns_zone must not be empty".into());
+        }
+        if self.port_range.is_empty() || *self.port_range.start() == 0 {
+            return Err(format!(
+                "port_range {}-{} must be a non-empty range of non-zero ports",
+                self.port_range.start(),
+                self.port_range.end()
+            )
+            .into());
+        }
+        if let Some(service) = self.service_load_balancing.keys().find(|s| s.chars().any(|c| c.is_ascii_uppercase())) {
+            return Err(format!("service_load_balancing key {} must be lowercase", service).into());
+        }
+        Ok(())
+    }
+
+    /// Load the configuration: defaults or the TOML file at `path`, then environment overrides, then validation
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use netsel::{NetSelConfig, NetSelServer};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let config = NetSelConfig::load(Some("/etc/netsel/netsel.toml".as_ref()))?;
+    ///     let handle = NetSelServer::with_config(config).start().await?;
+    ///     tokio::signal::ctrl_c().await?;
+    ///     handle.shutdown().await
+    /// }
+    /// ```
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Overwrite `target` with `value`, if any
+pub fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, Box<dyn std::error::Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value {:?} for {}: {}", value, key, e).into())
+}
+
+/// Read `NETSEL_<name>` with `var`, if set
+fn env_var(
+    var: impl Fn(&str) -> Result<String, VarError>,
+    name: &str
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match var(&format!("{}{}", ENV_PREFIX, name)) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(format!("{}{}: {}", ENV_PREFIX, name, e).into()),
+    }
+}
+
+fn override_from_env<T>(
+    target: &mut T,
+    var: impl Fn(&str) -> Result<String, VarError>,
+    name: &str
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env_var(var, name)? {
+        *target = parse(&format!("{}{}", ENV_PREFIX, name), &value)?;
+    }
+    Ok(())
+}
+
+/// Parse a port range written `start-end`, as in `NETSEL_PORT_RANGE`
+///
+/// # Example
+///
+/// ```rust
+/// use netsel::config::parse_port_range;
+///
+/// assert_eq!(parse_port_range("9000-9999")?, 9000..=9999);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, Box<dyn std::error::Error>> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid port range {}, expected start-end", value))?;
+    Ok(start.trim().parse()?..=end.trim().parse()?)
+}
+
+/// Whether two listeners cannot both be bound, either being on the same address or on the same port with one of them
+/// on the unspecified address
+fn clashes(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration file in the temporary directory, removed on drop
+    struct ConfigPath(PathBuf);
+
+    impl ConfigPath {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = env::temp_dir().join(format!("netsel-config-{}-{}.toml", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for ConfigPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Variable lookup seeing only `vars`
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Result<String, VarError> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+                .ok_or(VarError::NotPresent)
+        }
+    }
+
+    /// Message of the error `result` should hold
+    fn failure<T>(result: Result<T, Box<dyn std::error::Error>>) -> String {
+        match result {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// Validation error of the default configuration after `change`
+    fn invalid(change: impl FnOnce(&mut NetSelConfig)) -> String {
+        let mut config = NetSelConfig::default();
+        change(&mut config);
+        failure(config.validate())
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        NetSelConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let file = ConfigPath::new("unknown", "dns_zone = \"internal\"\nmax_heartbeat = 30\n");
+        let error = failure(NetSelConfig::from_file(&file.0));
+        assert!(error.contains("unknown field `max_heartbeat`"), "{}", error);
+
+        let file = ConfigPath::new("unknown-table", "[service_load_balancing]\norders = \"fastest\"\n");
+        assert!(NetSelConfig::from_file(&file.0).is_err());
+    }
+
+    #[test]
+    fn environment_values_override_file_values() {
+        let file = ConfigPath::new("override", "dns_zone = \"file\"\nmin_ttl = 3\ndata_dir = \"/var/lib/netsel\"\n");
+        let mut config = NetSelConfig::from_file(&file.0).unwrap();
+        config
+            .apply_vars(vars(&[
+                ("NETSEL_DNS_ZONE", "env"),
+                ("NETSEL_PORT_RANGE", "7000-7099"),
+                ("NETSEL_DATA_DIR", ""),
+            ]))
+            .unwrap();
+        assert_eq!(config.dns_zone, "env");
+        assert_eq!(config.port_range, 7000..=7099);
+        assert_eq!(config.data_dir, None);
+        // Settings without a variable keep the file value
+        assert_eq!(config.min_ttl, 3);
+
+        let error = failure(config.apply_vars(vars(&[("NETSEL_MIN_TTL", "soon")])));
+        assert!(error.contains("NETSEL_MIN_TTL"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_port_ranges_are_rejected() {
+        for value in ["9000", "9000-", "a-b", "9000-70000"] {
+            assert!(parse_port_range(value).is_err(), "{}", value);
+        }
+        let error = failure(NetSelConfig::default().apply_vars(vars(&[("NETSEL_PORT_RANGE", "9000")])));
+        assert!(error.starts_with("NETSEL_PORT_RANGE"), "{}", error);
+
+        let file = ConfigPath::new("inverted-ports", "port_range = [9999, 9000]\n");
+        let config = NetSelConfig::from_file(&file.0).unwrap();
+        assert!(failure(config.validate()).contains("port_range 9999-9000"));
+        assert!(invalid(|config| config.port_range = 0..=100).contains("port_range 0-100"));
+        let file = ConfigPath::new("short-ports", "port_range = [9000]\n");
+        assert!(NetSelConfig::from_file(&file.0).is_err());
+    }
+
+    #[test]
+    fn inconsistent_timeouts_are_rejected() {
+        assert_eq!(
+            invalid(|config| config.min_ttl = config.max_heartbeat_age + 1),
+            "min_ttl (61) must not be longer than max_heartbeat_age (60)"
+        );
+        assert!(invalid(|config| config.max_ttl = config.max_heartbeat_age - 1).starts_with("max_heartbeat_age "));
+        assert!(invalid(|config| config.reap_timeout = config.max_ttl - 1).starts_with("max_ttl "));
+        let error = invalid(|config| config.health_check_interval = 0);
+        assert_eq!(error, "health_check_interval must be greater than zero");
+        assert_eq!(invalid(|config| config.min_ttl = 0), "min_ttl must be greater than zero");
+    }
+}
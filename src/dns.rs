@@ -1,12 +1,425 @@
-// Simplified: DNS functionality removed for compatibility reasons
-// In production, consider using a more compatible DNS library or implementing a simple DNS server
-pub async fn start_dns_server(
-    _listen_addr: std::net::SocketAddr,
-    _registry: std::sync::Arc<crate::registry::SharedRegistry>
+//! DNS server implementation for NetSel
+//!
+//! This module answers DNS queries for registered services. A service registered as `orders` is resolvable
+//! as `orders.<zone>` (for example `orders.netsel`) over both UDP and TCP, using the registry as its data source.
+//! Every ready instance of the service contributes an A (or AAAA) record for the address it listens on, and its
+//! port is published as an SRV record under `_orders._tcp.<zone>`. The version, tags and metadata of each instance
+//! are published as a TXT record of `key=value` strings. Ready and suspect instances are published; offline,
+//! unhealthy, draining and maintenance ones are left out (see [`crate::registry::ServiceStatus`]).
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{info, warn};
+use trust_dns_proto::op::{Header, MessageType, OpCode, ResponseCode};
+use trust_dns_proto::rr::rdata::{A, AAAA, SRV, TXT};
+use trust_dns_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use trust_dns_server::ServerFuture;
+use trust_dns_server::authority::MessageResponseBuilder;
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+use crate::registry::{ServiceInfo, SharedRegistry};
+use crate::shutdown::Shutdown;
+
+/// Default DNS zone under which services are published
+pub const DEFAULT_ZONE: &str = "netsel";
+
+/// TTL (in seconds) of the records served for registered services
+const RECORD_TTL: u32 = 5;
+
+/// Longest string a TXT record can hold
+const MAX_TXT_STRING_LEN: usize = 255;
+
+/// Timeout for idle TCP DNS connections
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS request handler backed by the service registry
+///
+/// Queries for `<hostname>.<zone>` (A/AAAA/TXT) and `_<hostname>._tcp.<zone>` (SRV) are answered from the registry.
+/// Names inside the zone that do not belong to a ready service get `NXDOMAIN`; names outside the zone are refused.
+pub struct RegistryDnsHandler {
+    zone: LowerName,
+    registry: Arc<SharedRegistry>,
+}
+
+impl RegistryDnsHandler {
+    /// Create a handler serving `zone` from `registry`
+    pub fn new(zone: &str, registry: Arc<SharedRegistry>) -> Result<Self, Box<dyn std::error::Error>> {
+        let zone = Name::from_str(zone)?.append_domain(&Name::root())?;
+        Ok(Self {
+            zone: LowerName::new(&zone),
+            registry,
+        })
+    }
+
+    /// Extract the labels below the zone from a query name, if it lies inside the zone
+    fn labels_for(&self, name: &LowerName) -> Option<Vec<String>> {
+        if !self.zone.zone_of(name) || name.num_labels() <= self.zone.num_labels() {
+            return None;
+        }
+
+        let name = Name::from(name);
+        let host_labels = (name.num_labels() - self.zone.num_labels()) as usize;
+        Some(
+            name.iter()
+                .take(host_labels)
+                .map(|label| String::from_utf8_lossy(label).to_string())
+                .collect(),
+        )
+    }
+
+    /// Name under which an instance's own A record is published (`<instance_id>.<hostname>.<zone>.`)
+    fn instance_name(&self, service: &ServiceInfo) -> Result<Name, trust_dns_proto::error::ProtoError> {
+        Name::from_str(&service.instance_id)?
+            .append_domain(&Name::from_str(&service.hostname)?)?
+            .append_domain(&Name::from(&self.zone))
+    }
+
+    /// Build the answer and additional records for a query, or `None` if the name does not exist
+    ///
+    /// `<hostname>.<zone>` is answered with the A/AAAA records of all ready instances, and `_<hostname>._tcp.<zone>`
+    /// with one SRV record per ready instance carrying its port. SRV targets are `<instance_id>.<hostname>.<zone>`,
+    /// whose address records are included in the additional section. All records point at the address each
+    /// instance advertised at registration. TXT queries for either `<hostname>.<zone>` or an instance name return
+    /// one record per instance describing it.
+    async fn lookup(
+        &self,
+        name: &LowerName,
+        labels: &[String],
+        query_type: RecordType,
+    ) -> Option<(Vec<Record>, Vec<Record>)> {
+        let registry_r = self.registry.read().await;
+
+        let (instances, srv) = match labels {
+            [service, proto] if proto == "_tcp" && service.starts_with('_') => {
+                (registry_r.healthy_instances(&service[1..]), true)
+            }
+            _ => {
+                let instances = registry_r.healthy_instances(&labels.join("."));
+                if instances.is_empty() && labels.len() > 1 {
+                    let instance = registry_r
+                        .get_instance(&labels[1..].join("."), &labels[0])
+                        .filter(|service| service.is_routable());
+                    (instance.into_iter().collect(), false)
+                } else {
+                    (instances, false)
+                }
+            }
+        };
+        if instances.is_empty() {
+            return None;
+        }
+
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for service in instances {
+            if srv {
+                if matches!(query_type, RecordType::SRV | RecordType::ANY) {
+                    // One instance without a valid name must not hide the others
+                    let target = match self.instance_name(service) {
+                        Ok(target) => target,
+                        Err(e) => {
+                            warn!(instance = %service.instance_id, error = %e, "Skipping SRV record of instance");
+                            continue;
+                        }
+                    };
+                    let srv = SRV::new(0, 0, service.endpoint.port(), target.clone());
+                    answers.push(Record::from_rdata(Name::from(name), RECORD_TTL, RData::SRV(srv)));
+                    additionals.push(address_record(target, service.endpoint.ip()));
+                }
+            } else {
+                let wanted = match service.endpoint.ip() {
+                    IpAddr::V4(_) => RecordType::A,
+                    IpAddr::V6(_) => RecordType::AAAA,
+                };
+                if query_type == wanted || query_type == RecordType::ANY {
+                    answers.push(address_record(Name::from(name), service.endpoint.ip()));
+                }
+                if matches!(query_type, RecordType::TXT | RecordType::ANY) {
+                    answers.push(txt_record(Name::from(name), service));
+                }
+            }
+        }
+        Some((answers, additionals))
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for RegistryDnsHandler {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let builder = MessageResponseBuilder::from_message_request(request);
+
+        if request.message_type() != MessageType::Query || request.op_code() != OpCode::Query {
+            let response = builder.error_msg(request.header(), ResponseCode::NotImp);
+            return send(&mut response_handle, response, request.header()).await;
+        }
+
+        let query = request.query();
+        let Some(labels) = self.labels_for(query.name()) else {
+            let response = builder.error_msg(request.header(), ResponseCode::Refused);
+            return send(&mut response_handle, response, request.header()).await;
+        };
+
+        match self.lookup(query.name(), &labels, query.query_type()).await {
+            Some((answers, additionals)) => {
+                let mut header = Header::response_from_request(request.header());
+                header.set_authoritative(true);
+                let response = builder.build(header, answers.iter(), &[], &[], additionals.iter());
+                send(&mut response_handle, response, request.header()).await
+            }
+            None => {
+                let mut response = builder.error_msg(request.header(), ResponseCode::NXDomain);
+                response.header_mut().set_authoritative(true);
+                send(&mut response_handle, response, request.header()).await
+            }
+        }
+    }
+}
+
+/// Build the A or AAAA record for `ip`
+fn address_record(name: Name, ip: IpAddr) -> Record {
+    let rdata = match ip {
+        IpAddr::V4(ip) => RData::A(A(ip)),
+        IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+    };
+    Record::from_rdata(name, RECORD_TTL, rdata)
+}
+
+/// Build the TXT record describing an instance
+///
+/// The record holds `instance=<instance_id>`, then `version=<version>` and `tags=<tag>,<tag>` when set, then one
+/// `key=value` string per metadata entry. Strings too long for a TXT record are left out.
+fn txt_record(name: Name, service: &ServiceInfo) -> Record {
+    let mut strings = vec![format!("instance={}", service.instance_id)];
+    if let Some(version) = &service.version {
+        strings.push(format!("version={}", version));
+    }
+    if !service.tags.is_empty() {
+        strings.push(format!("tags={}", service.tags.iter().cloned().collect::<Vec<_>>().join(",")));
+    }
+    strings.extend(service.metadata.iter().map(|(key, value)| format!("{}={}", key, value)));
+    strings.retain(|string| string.len() <= MAX_TXT_STRING_LEN);
+
+    Record::from_rdata(name, RECORD_TTL, RData::TXT(TXT::new(strings)))
+}
+
+/// Send a response, falling back to a `SERVFAIL` header if it could not be written
+async fn send<'a, R: ResponseHandler>(
+    response_handle: &mut R,
+    response: trust_dns_server::authority::MessageResponse<
+        '_,
+        'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+    request_header: &Header,
+) -> ResponseInfo {
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!(error = %e, "Error sending DNS response");
+            let mut header = Header::response_from_request(request_header);
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
+
+/// Serve DNS on `socket` (UDP) and `listener` (TCP) with `handler` until `shutdown` fires
+///
+/// Answers A/AAAA/TXT queries for `<hostname>.<zone>` and SRV queries for `_<hostname>._tcp.<zone>`. On shutdown,
+/// queries in flight are answered before returning.
+pub async fn serve_dns(
+    socket: UdpSocket,
+    listener: TcpListener,
+    handler: RegistryDnsHandler,
+    mut shutdown: Shutdown
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!("DNS server functionality temporarily disabled for compatibility");
-    // Sleep indefinitely
-    loop {
-        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+    info!(addr = %socket.local_addr()?, zone = %handler.zone, "DNS server listening");
+    let mut server = ServerFuture::new(handler);
+    server.register_socket(socket);
+    server.register_listener(listener, TCP_TIMEOUT);
+
+    let (signal, done) = server.graceful();
+    tokio::pin!(done);
+    tokio::select! {
+        result = &mut done => result?,
+        _ = shutdown.wait() => {
+            signal.shutdown().await;
+            done.await?;
+            info!("DNS server stopped");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::watch;
+    use trust_dns_proto::op::{Message, Query};
+
+    use crate::registry::{Registration, ServiceRegistry, ServiceStatus};
+
+    /// Serve the `netsel` zone from `registry` on an ephemeral port
+    async fn serve(registry: ServiceRegistry) -> std::net::SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handler = RegistryDnsHandler::new(DEFAULT_ZONE, Arc::new(SharedRegistry::new(registry))).unwrap();
+        // The sender is dropped, so the server runs until the test ends
+        let shutdown = Shutdown::new(watch::channel(false).1, Duration::from_secs(1));
+        tokio::spawn(async move { serve_dns(socket, listener, handler, shutdown).await.unwrap() });
+        addr
+    }
+
+    async fn query(addr: std::net::SocketAddr, name: &str, query_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request.set_id(7).set_message_type(MessageType::Query).set_op_code(OpCode::Query);
+        request.add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&request.to_vec().unwrap(), addr).await.unwrap();
+        let mut buf = [0u8; 4096];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+        Message::from_vec(&buf[..len]).unwrap()
+    }
+
+    /// Registry with a described IPv4 instance, an IPv6 instance and an instance in maintenance of `orders`
+    fn registry() -> (ServiceRegistry, String, String) {
+        let mut registry = ServiceRegistry::new();
+        let mut v4 = Registration::new("orders".to_string(), IpAddr::from([192, 168, 1, 10]));
+        v4.listen_port = Some(8080);
+        v4.version = Some("1.4.0".to_string());
+        v4.tags.insert("blue".to_string());
+        v4.metadata.insert("region".to_string(), "eu".to_string());
+        let v4 = registry.register(v4).unwrap().instance_id;
+        let mut v6 = Registration::new("orders".to_string(), "fd00::10".parse().unwrap());
+        v6.listen_port = Some(8081);
+        let v6 = registry.register(v6).unwrap().instance_id;
+        let maintenance = registry.register(Registration::new("orders".to_string(), IpAddr::from([192, 168, 1, 11])));
+        registry.set_status("orders", &maintenance.unwrap().instance_id, ServiceStatus::Maintenance);
+        (registry, v4, v6)
+    }
+
+    fn addresses(records: &[Record]) -> Vec<IpAddr> {
+        records
+            .iter()
+            .filter_map(|record| match record.data()? {
+                RData::A(A(ip)) => Some(IpAddr::V4(*ip)),
+                RData::AAAA(AAAA(ip)) => Some(IpAddr::V6(*ip)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_service_name_resolves_to_its_ready_instances() {
+        let (registry, _, _) = registry();
+        let addr = serve(registry).await;
+
+        let response = query(addr, "Orders.netsel.", RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(addresses(response.answers()), [IpAddr::from([192, 168, 1, 10])]);
+
+        let response = query(addr, "orders.netsel.", RecordType::AAAA).await;
+        assert_eq!(addresses(response.answers()), ["fd00::10".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn txt_records_describe_each_instance() {
+        let (registry, v4, v6) = registry();
+        let addr = serve(registry).await;
+
+        let response = query(addr, "orders.netsel.", RecordType::TXT).await;
+        let mut texts: Vec<Vec<String>> = response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data()? {
+                RData::TXT(txt) => Some(txt.iter().map(|s| String::from_utf8_lossy(s).to_string()).collect()),
+                _ => None,
+            })
+            .collect();
+        texts.sort();
+        assert_eq!(
+            texts,
+            [
+                vec![format!("instance={}", v4), "version=1.4.0".into(), "tags=blue".into(), "region=eu".into()],
+                vec![format!("instance={}", v6)],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn srv_records_point_at_instance_names_with_their_addresses_as_additionals() {
+        let (registry, v4, v6) = registry();
+        let addr = serve(registry).await;
+
+        let response = query(addr, "_orders._tcp.netsel.", RecordType::SRV).await;
+        let mut targets: Vec<(String, u16)> = response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data()? {
+                RData::SRV(srv) => Some((srv.target().to_string(), srv.port())),
+                _ => None,
+            })
+            .collect();
+        targets.sort();
+        assert_eq!(
+            targets,
+            [(format!("{}.orders.netsel.", v4), 8080), (format!("{}.orders.netsel.", v6), 8081)]
+        );
+
+        let mut additionals: Vec<(String, IpAddr)> = response
+            .additionals()
+            .iter()
+            .map(|record| (record.name().to_string(), addresses(std::slice::from_ref(record))[0]))
+            .collect();
+        additionals.sort();
+        assert_eq!(
+            additionals,
+            [
+                (format!("{}.orders.netsel.", v4), IpAddr::from([192, 168, 1, 10])),
+                (format!("{}.orders.netsel.", v6), "fd00::10".parse().unwrap()),
+            ]
+        );
+
+        // Instance names resolve on their own
+        let response = query(addr, &format!("{}.orders.netsel.", v4), RecordType::A).await;
+        assert_eq!(addresses(response.answers()), [IpAddr::from([192, 168, 1, 10])]);
+    }
+
+    #[tokio::test]
+    async fn unknown_names_in_the_zone_do_not_exist() {
+        let (registry, _, _) = registry();
+        let addr = serve(registry).await;
+
+        for name in ["billing.netsel.", "_billing._tcp.netsel.", "orders-99.orders.netsel."] {
+            let response = query(addr, name, RecordType::A).await;
+            assert_eq!(response.response_code(), ResponseCode::NXDomain, "{}", name);
+            assert!(response.authoritative());
+        }
+    }
+
+    #[tokio::test]
+    async fn names_outside_the_zone_are_refused() {
+        let (registry, _, _) = registry();
+        let addr = serve(registry).await;
+
+        for name in ["orders.example.com.", "netsel.", "."] {
+            let response = query(addr, name, RecordType::A).await;
+            assert_eq!(response.response_code(), ResponseCode::Refused, "{}", name);
+            assert!(response.answers().is_empty());
+        }
     }
 }
@@ -0,0 +1,232 @@
+//! Active health checks for NetSel
+//!
+//! On top of heartbeats, an instance may ask at registration for the server to check it (see
+//! [`crate::protocol::HealthCheck`]): either by opening a TCP connection to its advertised endpoint, or by sending an
+//! HTTP `GET` there. Once its check failed `failure_threshold` times in a row the instance becomes
+//! [`ServiceStatus::Unhealthy`] and is left out of DNS answers and proxy routing, and the first passing check makes it
+//! ready again, unless the registry dampens it for flapping (see [`crate::registry::FlapPolicy`]). Heartbeats alone
+//! still decide whether the instance stays registered.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use http_body_util::Empty;
+use hyper::Request;
+use hyper::body::Bytes;
+use hyper::header::{HOST, USER_AGENT};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
+use tracing::{Instrument, debug, info, info_span, warn};
+
+use crate::protocol::{HealthCheck, HealthCheckKind};
+use crate::registry::{ServiceStatus, SharedRegistry};
+use crate::shutdown::Shutdown;
+
+/// How often the checker looks for checks that are due; shorter check intervals are rounded up to it
+const SCHEDULER_TICK: Duration = Duration::from_millis(500);
+
+/// Run the active health checks of the registered instances until `shutdown` fires
+///
+/// Each instance is checked every `interval` of its own check, the first time right after it registered. Checks
+/// still running on shutdown are cancelled.
+pub async fn run_health_checks(
+    registry: Arc<SharedRegistry>,
+    mut shutdown: Shutdown
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Active health checker started");
+
+    let mut next_due: HashMap<(String, String), Instant> = HashMap::new();
+    let mut checks = JoinSet::new();
+    let mut tick = tokio::time::interval(SCHEDULER_TICK);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            Some(_) = checks.join_next(), if !checks.is_empty() => continue,
+            _ = shutdown.wait() => break,
+        }
+
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut live = HashSet::new();
+        {
+            let registry_r = registry.read().await;
+            for service in registry_r.services.values().flat_map(|instances| instances.values()) {
+                let Some(check) = &service.health_check else {
+                    continue;
+                };
+                let key = (service.hostname.clone(), service.instance_id.clone());
+                if next_due.get(&key).is_none_or(|due_at| *due_at <= now) {
+                    // The timeout never exceeds the interval, so checks of an instance do not overlap
+                    next_due.insert(key.clone(), now + check.interval);
+                    due.push((key.clone(), service.endpoint, check.clone()));
+                }
+                live.insert(key);
+            }
+        }
+        next_due.retain(|key, _| live.contains(key));
+
+        for ((hostname, instance_id), endpoint, check) in due {
+            let registry = registry.clone();
+            let span = info_span!("health_check", service = %hostname, instance = %instance_id, %endpoint);
+            checks.spawn(async move {
+                let passed = match tokio::time::timeout(check.timeout, probe(&check, endpoint)).await {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        debug!(error = %e, "Health check failed");
+                        false
+                    }
+                    Err(_) => {
+                        debug!(timeout_ms = check.timeout.as_millis() as u64, "Health check timed out");
+                        false
+                    }
+                };
+                match registry.write().await.record_check(&hostname, &instance_id, passed) {
+                    Some(ServiceStatus::Unhealthy) => {
+                        warn!(failures = check.failure_threshold, "Instance failed its health check, marked unhealthy");
+                    }
+                    Some(ServiceStatus::Ready) => info!("Instance passed its health check again, marked ready"),
+                    _ => {}
+                }
+            }.instrument(span));
+        }
+    }
+
+    checks.shutdown().await;
+    info!("Active health checker stopped");
+    Ok(())
+}
+
+/// Run one check against `endpoint`
+async fn probe(check: &HealthCheck, endpoint: SocketAddr) -> Result<(), String> {
+    let stream = TcpStream::connect(endpoint).await.map_err(|e| e.to_string())?;
+    let HealthCheckKind::Http { path, expected_status } = &check.kind else {
+        return Ok(());
+    };
+
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| e.to_string())?;
+    // The connection closes once the response is dropped along with the sender
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let request = Request::get(path.as_str())
+        .header(HOST, endpoint.to_string())
+        .header(USER_AGENT, concat!("netsel/", env!("CARGO_PKG_VERSION")))
+        .body(Empty::<Bytes>::new())
+        .map_err(|e| e.to_string())?;
+    let status = sender.send_request(request).await.map_err(|e| e.to_string())?.status();
+
+    let expected = if expected_status.is_empty() {
+        status.is_success()
+    } else {
+        expected_status.contains(&status.as_u16())
+    };
+    if expected {
+        Ok(())
+    } else {
+        Err(format!("unexpected status {}", status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// HTTP server answering every request with `status`, reporting the request lines and headers it got
+    async fn http_server(status: u16) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = sender.send(String::from_utf8_lossy(&request).to_string());
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (addr, requests)
+    }
+
+    /// Address nothing listens on
+    async fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    fn http(expected_status: &[u16]) -> HealthCheck {
+        HealthCheck {
+            kind: HealthCheckKind::Http {
+                path: "/health?deep=1".to_string(),
+                expected_status: expected_status.to_vec(),
+            },
+            ..HealthCheck::tcp()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_tcp_probe_passes_when_the_endpoint_accepts_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        assert_eq!(probe(&HealthCheck::tcp(), listener.local_addr().unwrap()).await, Ok(()));
+        assert!(probe(&HealthCheck::tcp(), closed_port().await).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn an_http_probe_gets_the_check_path() {
+        let (addr, mut requests) = http_server(200).await;
+        assert_eq!(probe(&http(&[]), addr).await, Ok(()));
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("GET /health?deep=1 HTTP/1.1\r\n"), "{}", request);
+        let request = request.to_ascii_lowercase();
+        assert!(request.contains(&format!("host: {}\r\n", addr)), "{}", request);
+        assert!(request.contains("user-agent: netsel/"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn an_http_probe_without_expected_statuses_wants_a_success() {
+        for (status, passes) in [(200, true), (204, true), (301, false), (404, false), (503, false)] {
+            let (addr, _requests) = http_server(status).await;
+            assert_eq!(probe(&http(&[]), addr).await.is_ok(), passes, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_http_probe_wants_one_of_the_expected_statuses() {
+        let (addr, _requests) = http_server(404).await;
+        assert_eq!(probe(&http(&[200, 404]), addr).await, Ok(()));
+
+        let (addr, _requests) = http_server(204).await;
+        assert_eq!(probe(&http(&[200]), addr).await, Err("unexpected status 204 No Content".to_string()));
+    }
+
+    #[tokio::test]
+    async fn an_http_probe_fails_without_a_listener_or_an_http_answer() {
+        assert!(probe(&http(&[]), closed_port().await).await.is_err());
+
+        // An endpoint closing the connection right away
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+        assert!(probe(&http(&[]), addr).await.is_err());
+    }
+}
@@ -9,11 +9,14 @@
 //! 
 //! - **Service Registration**: Simple API for services to register themselves
 //! - **Heartbeat Mechanism**: Automatic service health monitoring
+//! - **Active Health Checks**: Optional TCP connect or HTTP GET checks run by the server against each instance
+//! - **Flap Dampening**: Instances going in and out of rotation too often are held out until they settle
 //! - **DNS Resolution**: Service name to IP address resolution
 //! - **TCP and HTTP Proxies**: Built-in traffic routing
 //! - **Virtual Network**: Simplified IP address management
 //! - **Concurrent Design**: Built with Tokio for high performance
 //! - **Fault Tolerance**: Graceful handling of service failures
+//! - **Structured Logging**: `tracing` spans per registration and proxied connection, as text or JSON
 //! 
 //! ## Getting Started
 //! 
@@ -25,11 +28,11 @@
 //! async fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     // Create and start NetSel server with default configuration
 //!     let server = NetSelServer::new();
-//!     server.start().await?;
+//!     let handle = server.start().await?;
 //!     
-//!     // Wait for shutdown signal
+//!     // Wait for shutdown signal, then drain connections and stop
 //!     signal::ctrl_c().await?;
-//!     println!("Shutting down NetSel Service...");
+//!     handle.shutdown().await?;
 //!     
 //!     Ok(())
 //! }
@@ -51,22 +54,17 @@
 //!     // Create service client
 //!     let mut client = ServiceClient::new(service_a_addr, hostname.to_string());
 //!     
+//!     // Advertise the port the service listens on
+//!     client.set_listen_port(8080);
+//!     
 //!     // Register with Service A
 //!     let (assigned_ip, assigned_port) = client.register().await?;
 //!     let assigned_addr = SocketAddr::new(assigned_ip, assigned_port);
 //!     
 //!     println!("Successfully registered! Assigned address: {}", assigned_addr);
 //!     
-//!     // Start heartbeats
-//!     let heartbeat_client = client.clone();
-//!     tokio::spawn(async move {
-//!         loop {
-//!             if let Err(e) = heartbeat_client.send_heartbeat().await {
-//!                 eprintln!("Heartbeat failed: {}", e);
-//!             }
-//!             tokio::time::sleep(Duration::from_secs(10)).await;
-//!         }
-//!     });
+//!     // Start sending heartbeats every 10 seconds; they stop when the handle is dropped
+//!     let _heartbeat = client.start_heartbeat(Duration::from_secs(10));
 //!     
 //!     // ... start your service logic
 //!     
@@ -81,30 +79,66 @@
 //! 1. **Registry Server**: Manages service registration and heartbeat messages
 //! 2. **TCP Proxy**: Routes TCP traffic between registered services
 //! 3. **HTTP Proxy**: Routes HTTP requests between registered services
-//! 4. **DNS Server**: Resolves service names to IP addresses
-//! 5. **Virtual Network**: Manages IP address allocation for services
-//! 6. **Health Checker**: Monitors service health based on heartbeat messages
+//! 4. **DNS Server**: Resolves `<service>.<zone>` names to IP addresses
+//! 5. **Virtual Network**: Allocates and releases the virtual IP addresses of services
+//! 6. **Health Checker**: Monitors service health based on heartbeat messages and active health checks
 //! 7. **Service Client**: Library for services to register and send heartbeats
+//! 8. **Admin API**: HTTP API for inspecting and managing the registry
 //! 
 //! ## Modules
 //! 
+//! - `admin`: Admin HTTP API for inspecting and managing the registry
+//! - `balancer`: Load balancing strategies used by the proxies to pick a service instance
 //! - `client`: Service client implementation for registering services and sending heartbeats
+//! - `config`: Loading of `NetSelConfig` from a TOML file and `NETSEL_*` environment variables
 //! - `dns`: DNS server implementation for service discovery
+//! - `health`: Active TCP and HTTP health checks of registered instances
+//! - `logging`: Log subscriber setup, with text, pretty or JSON output
+//! - `metrics`: Prometheus metrics for the registry, proxies and health checker
 //! - `network`: Virtual network implementation for IP allocation
+//! - `persistence`: Snapshot and write-ahead log keeping the registry across restarts
+//! - `protocol`: Framed wire protocol spoken between services and the registration server
 //! - `proxy`: TCP and HTTP proxy implementations for traffic routing
 //! - `registry`: Service registry implementation for managing service information
+//! - `shutdown`: Shutdown signal stopping the server components and draining their connections
 
+pub mod admin;
+pub mod balancer;
 pub mod client;
+pub mod config;
 pub mod dns;
+pub mod health;
+pub mod logging;
+pub mod metrics;
 pub mod network;
+pub mod persistence;
+pub mod protocol;
 pub mod proxy;
 pub mod registry;
+pub mod shutdown;
 
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
+use tracing::{error, info};
 
+use crate::balancer::{LoadBalanceStrategy, LoadBalancer};
+use crate::logging::LogFormat;
+use crate::metrics::Metrics;
+use crate::network::Subnet;
 use crate::registry::SharedRegistry;
+use crate::shutdown::Shutdown;
+
+/// Delay after an instance deadline before the heartbeat check runs, so the deadline has strictly passed
+const DEADLINE_SLACK: Duration = Duration::from_millis(10);
 
 /// Main NetSel server configuration
 /// 
@@ -114,16 +148,40 @@ use crate::registry::SharedRegistry;
 /// # Example
 /// 
 /// ```rust
+/// use std::collections::HashMap;
 /// use std::net::{IpAddr, SocketAddr};
+/// use std::path::PathBuf;
 /// use netsel::NetSelConfig;
+/// use netsel::balancer::LoadBalanceStrategy;
+/// use netsel::logging::LogFormat;
 /// 
 /// let config = NetSelConfig {
 ///     registry_addr: SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 9000),
 ///     tcp_proxy_addr: SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 8080),
 ///     http_proxy_addr: SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 8081),
 ///     dns_addr: SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 5353),
+///     admin_addr: SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 8082),
+///     dns_zone: "netsel".to_string(),
 ///     health_check_interval: 30,
 ///     max_heartbeat_age: 60,
+///     reap_timeout: 600,
+///     min_ttl: 5,
+///     max_ttl: 300,
+///     flap_threshold: 5,
+///     flap_window: 600,
+///     flap_cooldown: 300,
+///     load_balancing: LoadBalanceStrategy::RoundRobin,
+///     service_load_balancing: HashMap::from([
+///         ("orders".to_string(), LoadBalanceStrategy::LeastConnections),
+///     ]),
+///     legacy_protocol: false,
+///     data_dir: Some(PathBuf::from("/var/lib/netsel")),
+///     snapshot_interval: 300,
+///     shutdown_timeout: 30,
+///     port_range: 9000..=9999,
+///     subnet: "10.0.0.0/24".parse().unwrap(),
+///     log_format: LogFormat::Json,
+///     log_filter: "info".to_string(),
 /// };
 /// ```
 pub struct NetSelConfig {
@@ -131,14 +189,53 @@ pub struct NetSelConfig {
     pub registry_addr: SocketAddr,
     /// Address for the TCP proxy that routes TCP traffic between services
     pub tcp_proxy_addr: SocketAddr,
-    /// Address for the HTTP proxy that routes HTTP requests between services
+    /// Address for the HTTP proxy that routes HTTP requests between services based on their `Host` header
     pub http_proxy_addr: SocketAddr,
     /// Address for the DNS server that resolves service names to IP addresses
     pub dns_addr: SocketAddr,
-    /// Health check interval in seconds - how often to check for inactive services
+    /// Address for the admin HTTP API used to inspect and manage the registry
+    pub admin_addr: SocketAddr,
+    /// DNS zone under which services are published (e.g. `orders.netsel`)
+    pub dns_zone: String,
+    /// Longest time between two heartbeat checks in seconds; checks also run as soon as an instance is due to become
+    /// suspect, offline or reaped
     pub health_check_interval: u64,
-    /// Max allowed time since last heartbeat before removing a service (in seconds)
+    /// Lease TTL of instances that do not request one (in seconds): without a heartbeat an instance becomes suspect
+    /// after half of its TTL, and offline after all of it
     pub max_heartbeat_age: u64,
+    /// Time since last heartbeat after which an instance is removed and its port released (in seconds)
+    pub reap_timeout: u64,
+    /// Shortest lease TTL granted to an instance (in seconds)
+    pub min_ttl: u64,
+    /// Longest lease TTL granted to an instance (in seconds)
+    pub max_ttl: u64,
+    /// Times an instance may go in or out of rotation within `flap_window` before it is dampened; 0 disables
+    /// flap dampening
+    pub flap_threshold: u32,
+    /// Window over which flap transitions are counted (in seconds)
+    pub flap_window: u64,
+    /// Time a flapping instance must stay stable before it goes back into rotation (in seconds)
+    pub flap_cooldown: u64,
+    /// Load balancing strategy used by the proxies for services without an override
+    pub load_balancing: LoadBalanceStrategy,
+    /// Per-service load balancing strategies, keyed by lowercase service name
+    pub service_load_balancing: HashMap<String, LoadBalanceStrategy>,
+    /// Also accept the legacy text registration format alongside the framed protocol
+    pub legacy_protocol: bool,
+    /// Directory where the registry is persisted across restarts; `None` keeps it in memory only
+    pub data_dir: Option<PathBuf>,
+    /// Interval in seconds between registry snapshots, which compact the write-ahead log
+    pub snapshot_interval: u64,
+    /// Time in seconds given to proxied connections to finish when the server shuts down
+    pub shutdown_timeout: u64,
+    /// Ports assigned to registered instances, along with their virtual address
+    pub port_range: RangeInclusive<u16>,
+    /// Subnet from which registered instances get their virtual address
+    pub subnet: Subnet,
+    /// Output format of the logs
+    pub log_format: LogFormat,
+    /// Log filter directives such as `info` or `info,netsel::proxy=debug`; `RUST_LOG` takes precedence when set
+    pub log_filter: String,
 }
 
 impl Default for NetSelConfig {
@@ -148,8 +245,26 @@ impl Default for NetSelConfig {
             tcp_proxy_addr: SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 8080),
             http_proxy_addr: SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 8081),
             dns_addr: SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 5353),
+            admin_addr: SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 8082),
+            dns_zone: dns::DEFAULT_ZONE.to_string(),
             health_check_interval: 30,
             max_heartbeat_age: 60,
+            reap_timeout: 600,
+            min_ttl: 5,
+            max_ttl: 300,
+            flap_threshold: 5,
+            flap_window: 600,
+            flap_cooldown: 300,
+            load_balancing: LoadBalanceStrategy::RoundRobin,
+            service_load_balancing: HashMap::new(),
+            legacy_protocol: false,
+            data_dir: None,
+            snapshot_interval: 300,
+            shutdown_timeout: 30,
+            port_range: 9000..=9999,
+            subnet: Subnet::default(),
+            log_format: LogFormat::default(),
+            log_filter: "info".to_string(),
         }
     }
 }
@@ -161,7 +276,7 @@ impl Default for NetSelConfig {
 /// 
 /// # Example
 /// 
-/// ```rust
+/// ```rust,no_run
 /// use std::net::{IpAddr, SocketAddr};
 /// use netsel::{NetSelConfig, NetSelServer};
 /// 
@@ -175,6 +290,7 @@ impl Default for NetSelConfig {
 ///         dns_addr: SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 5353),
 ///         health_check_interval: 30,
 ///         max_heartbeat_age: 60,
+///         ..NetSelConfig::default()
 ///     };
 ///     
 ///     // Create server with custom configuration
@@ -189,6 +305,14 @@ impl Default for NetSelConfig {
 pub struct NetSelServer {
     config: NetSelConfig,
     registry: Arc<SharedRegistry>,
+    balancer: Arc<LoadBalancer>,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for NetSelServer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetSelServer {
@@ -198,9 +322,13 @@ impl NetSelServer {
     /// - Registry server: `0.0.0.0:9000`
     /// - TCP proxy: `0.0.0.0:8080`
     /// - HTTP proxy: `0.0.0.0:8081`
-    /// - DNS server: `127.0.0.1:5353`
+    /// - DNS server: `127.0.0.1:5353` (zone `netsel`)
+    /// - Admin API: `127.0.0.1:8082`
     /// - Health check interval: 30 seconds
     /// - Max heartbeat age: 60 seconds
+    /// - Reap timeout: 600 seconds
+    /// - Lease TTLs: 5 to 300 seconds
+    /// - Load balancing: round-robin for every service
     /// 
     /// # Example
     /// 
@@ -236,105 +364,396 @@ impl NetSelServer {
     /// let server = NetSelServer::with_config(config);
     /// ```
     pub fn with_config(config: NetSelConfig) -> Self {
-        let registry = Arc::new(SharedRegistry::new(registry::ServiceRegistry::new()));
+        let mut registry = registry::ServiceRegistry::with_network(config.port_range.clone(), config.subnet);
+        registry.set_lease_policy(registry::LeasePolicy {
+            default_ttl: Duration::from_secs(config.max_heartbeat_age),
+            min_ttl: Duration::from_secs(config.min_ttl),
+            max_ttl: Duration::from_secs(config.max_ttl),
+        });
+        registry.set_flap_policy(registry::FlapPolicy {
+            threshold: config.flap_threshold,
+            window: Duration::from_secs(config.flap_window),
+            cooldown: Duration::from_secs(config.flap_cooldown),
+        });
+        let metrics = registry.metrics();
+        let registry = Arc::new(SharedRegistry::new(registry));
+        let balancer = Arc::new(LoadBalancer::new(config.load_balancing));
+        for (service, strategy) in &config.service_load_balancing {
+            balancer.set_strategy(service, *strategy);
+        }
         
         Self {
             config,
             registry,
+            balancer,
+            metrics,
         }
     }
 
+    /// Get the load balancer shared by the proxies
+    /// 
+    /// This can be used to change the strategy of a service while the server is running.
+    /// 
+    /// # Example
+    /// 
+    /// ```rust
+    /// use netsel::NetSelServer;
+    /// use netsel::balancer::LoadBalanceStrategy;
+    /// 
+    /// let server = NetSelServer::new();
+    /// server.balancer().set_strategy("orders", LoadBalanceStrategy::ConsistentHash);
+    /// ```
+    pub fn balancer(&self) -> Arc<LoadBalancer> {
+        self.balancer.clone()
+    }
+
+    /// Get the service registry
+    /// 
+    /// This can be used to inspect the registered services, or to follow changes in-process through
+    /// [`ServiceRegistry::subscribe`](registry::ServiceRegistry::subscribe).
+    /// 
+    /// # Example
+    /// 
+    /// ```rust
+    /// use netsel::NetSelServer;
+    /// 
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let server = NetSelServer::new();
+    ///     let mut events = server.registry().read().await.subscribe();
+    ///     # drop(events);
+    /// }
+    /// ```
+    pub fn registry(&self) -> Arc<SharedRegistry> {
+        self.registry.clone()
+    }
+
     /// Start the NetSel server and all its components
     /// 
-    /// This method starts all components of the NetSel server in separate Tokio tasks:
-    /// 1. Virtual network
-    /// 2. DNS server
-    /// 3. TCP proxy
-    /// 4. HTTP proxy
-    /// 5. Registration server
+    /// The log subscriber is installed first (see [`logging::init`]), unless the application already installed one.
+    /// If a data directory is configured, the registry saved there is restored next. All listeners are then bound
+    /// before this method starts the components of the NetSel server in separate Tokio tasks:
+    /// 1. DNS server
+    /// 2. TCP proxy
+    /// 3. HTTP proxy
+    /// 4. Registration server
+    /// 5. Admin API
     /// 6. Health check task
+    /// 7. Active health checker
+    /// 8. Snapshot task, when the registry is persisted
     /// 
     /// # Returns
     /// 
-    /// * `Ok(())` - If all components started successfully
-    /// * `Err(Box<dyn std::error::Error>)` - If any component failed to start
+    /// * `Ok(ServerHandle)` - If all components started successfully; the handle stops them
+    /// * `Err(Box<dyn std::error::Error>)` - If the configuration or log filter is invalid, the persisted registry could
+    ///   not be restored or a listener could not be bound
     /// 
     /// # Example
     /// 
-    /// ```rust
+    /// ```rust,no_run
     /// use netsel::NetSelServer;
     /// 
     /// #[tokio::main]
     /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
     ///     let server = NetSelServer::new();
-    ///     server.start().await?;
+    ///     let handle = server.start().await?;
+    ///     handle.shutdown().await?;
     ///     Ok(())
     /// }
     /// ```
-    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Starting NetSel Service...");
+    pub async fn start(&self) -> Result<ServerHandle, Box<dyn std::error::Error>> {
+        self.config.validate()?;
+        logging::init(self.config.log_format, &self.config.log_filter)?;
+        info!("Starting NetSel Service");
         
-        // Start virtual network
-        let mut virtual_net = network::VirtualNetwork::new();
-        tokio::spawn(async move {
-            virtual_net.run().await;
-        });
+        // Restore the persisted registry before accepting any registration
+        if let Some(data_dir) = &self.config.data_dir {
+            let mut registry_w = self.registry.write().await;
+            registry_w.enable_persistence(data_dir)?;
+            let restored: usize = registry_w.services.values().map(|instances| instances.len()).sum();
+            info!(instances = restored, data_dir = %data_dir.display(), "Restored registry");
+        }
+        
+        // Bind every listener first, so that an address in use fails the start instead of a background task
+        let dns_handler = dns::RegistryDnsHandler::new(&self.config.dns_zone, self.registry.clone())?;
+        let dns_socket = UdpSocket::bind(self.config.dns_addr)
+            .await
+            .map_err(|e| format!("Cannot bind DNS server to {}: {}", self.config.dns_addr, e))?;
+        let dns_listener = bind("DNS server", self.config.dns_addr).await?;
+        let tcp_proxy_listener = bind("TCP proxy", self.config.tcp_proxy_addr).await?;
+        let http_proxy_listener = bind("HTTP proxy", self.config.http_proxy_addr).await?;
+        let registration_listener = bind("registration server", self.config.registry_addr).await?;
+        let admin_listener = bind("admin API", self.config.admin_addr).await?;
+        
+        let (trigger, signal) = watch::channel(false);
+        let shutdown = Shutdown::new(signal, Duration::from_secs(self.config.shutdown_timeout));
+        let mut tasks = Vec::new();
         
         // Start DNS server
-        let dns_addr = self.config.dns_addr;
-        let registry_dns = self.registry.clone();
-        tokio::spawn(async move {
-            if let Err(e) = dns::start_dns_server(dns_addr, registry_dns).await {
-                eprintln!("DNS server error: {}", e);
-            }
-        });
+        tasks.push(spawn_component(
+            "DNS server",
+            dns::serve_dns(dns_socket, dns_listener, dns_handler, shutdown.clone())
+        ));
         
         // Start TCP proxy
-        let tcp_proxy_addr = self.config.tcp_proxy_addr;
         let registry_tcp = self.registry.clone();
-        tokio::spawn(async move {
-            if let Err(e) = proxy::start_tcp_proxy(tcp_proxy_addr, registry_tcp).await {
-                eprintln!("TCP proxy error: {}", e);
-            }
-        });
+        let balancer_tcp = self.balancer.clone();
+        let metrics_tcp = self.metrics.clone();
+        tasks.push(spawn_component(
+            "TCP proxy",
+            proxy::serve_tcp_proxy(tcp_proxy_listener, registry_tcp, balancer_tcp, metrics_tcp, shutdown.clone())
+        ));
         
         // Start HTTP proxy
-        let http_proxy_addr = self.config.http_proxy_addr;
+        let http_zone = self.config.dns_zone.clone();
         let registry_http = self.registry.clone();
-        tokio::spawn(async move {
-            if let Err(e) = proxy::start_http_proxy(http_proxy_addr, registry_http).await {
-                eprintln!("HTTP proxy error: {}", e);
-            }
-        });
+        let balancer_http = self.balancer.clone();
+        let metrics_http = self.metrics.clone();
+        let shutdown_http = shutdown.clone();
+        tasks.push(spawn_component("HTTP proxy", async move {
+            proxy::serve_http_proxy(http_proxy_listener, &http_zone, registry_http, balancer_http, metrics_http, shutdown_http)
+                .await
+        }));
         
         // Start registration server
-        let reg_server_addr = self.config.registry_addr;
         let registry_reg = self.registry.clone();
-        tokio::spawn(async move {
-            if let Err(e) = registry::start_registration_server(reg_server_addr, registry_reg).await {
-                eprintln!("Registration server error: {}", e);
-            }
-        });
+        let legacy_protocol = self.config.legacy_protocol;
+        tasks.push(spawn_component(
+            "Registration server",
+            registry::serve_registration(registration_listener, registry_reg, legacy_protocol, shutdown.clone())
+        ));
+        
+        // Start admin API
+        let registry_admin = self.registry.clone();
+        let metrics_admin = self.metrics.clone();
+        tasks.push(spawn_component(
+            "Admin API",
+            admin::serve_admin(admin_listener, registry_admin, metrics_admin, shutdown.clone())
+        ));
         
         // Start health check task
         let registry_health = self.registry.clone();
         let health_check_interval = self.config.health_check_interval;
-        let max_heartbeat_age = self.config.max_heartbeat_age;
-        tokio::spawn(async move {
+        let reap_timeout = Duration::from_secs(self.config.reap_timeout);
+        let metrics_health = self.metrics.clone();
+        let mut shutdown_health = shutdown.clone();
+        let mut events = self.registry.read().await.subscribe();
+        tasks.push(spawn_component("Health checker", async move {
             let mut interval = tokio::time::interval(Duration::from_secs(health_check_interval));
             loop {
-                interval.tick().await;
+                // Check at the next instance deadline, so short leases are enforced on time, and at the latest every
+                // interval; registry changes may bring the deadline forward
+                let deadline = registry_health.read().await.next_deadline(reap_timeout);
+                let at_deadline = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until((deadline + DEADLINE_SLACK).into()).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = at_deadline => {}
+                    _ = events.recv() => continue,
+                    _ = shutdown_health.wait() => return Ok(()),
+                }
+                let started = std::time::Instant::now();
                 let mut registry_w = registry_health.write().await;
-                registry_w.cleanup_offline(Duration::from_secs(max_heartbeat_age));
+                registry_w.check_heartbeats(reap_timeout);
+                drop(registry_w);
+                metrics_health.record_health_check(started.elapsed());
             }
-        });
+        }));
+        
+        // Start active health checker
+        tasks.push(spawn_component(
+            "Active health checker",
+            health::run_health_checks(self.registry.clone(), shutdown.clone())
+        ));
+        
+        // Start snapshot task
+        if self.config.data_dir.is_some() {
+            let registry_snapshot = self.registry.clone();
+            let snapshot_interval = Duration::from_secs(self.config.snapshot_interval);
+            let mut shutdown_snapshot = shutdown.clone();
+            tasks.push(spawn_component("Snapshot task", async move {
+                let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + snapshot_interval, snapshot_interval);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown_snapshot.wait() => return Ok(()),
+                    }
+                    // Errors are logged by the writer, and the log is kept until a snapshot succeeds
+                    let written = registry_snapshot.write().await.snapshot();
+                    let _ = written.wait().await;
+                }
+            }));
+        }
         
-        println!("NetSel Service started successfully!");
-        println!("- Registration server: {}", self.config.registry_addr);
-        println!("- TCP proxy: {}", self.config.tcp_proxy_addr);
-        println!("- HTTP proxy: {}", self.config.http_proxy_addr);
-        println!("- DNS server: {}", self.config.dns_addr);
+        info!(
+            registration = %self.config.registry_addr,
+            tcp_proxy = %self.config.tcp_proxy_addr,
+            http_proxy = %self.config.http_proxy_addr,
+            dns = %self.config.dns_addr,
+            zone = %self.config.dns_zone,
+            admin = %self.config.admin_addr,
+            "NetSel Service started"
+        );
         
+        Ok(ServerHandle {
+            trigger,
+            tasks,
+            registry: self.registry.clone(),
+        })
+    }
+}
+
+/// Bind the TCP listener of a component, naming it in the error
+async fn bind(name: &str, addr: SocketAddr) -> Result<TcpListener, Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Cannot bind {} to {}: {}", name, addr, e))?;
+    Ok(listener)
+}
+
+/// Run a component in its own task, logging the error it stops with
+fn spawn_component<F>(name: &'static str, component: F) -> (&'static str, JoinHandle<()>)
+where
+    F: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'static,
+{
+    let task = tokio::spawn(async move {
+        if let Err(e) = component.await {
+            error!(component = name, error = %e, "Component stopped with an error");
+        }
+    });
+    (name, task)
+}
+
+/// Random number for load balancing and heartbeat jitter
+///
+/// Taken from the randomly seeded hasher of the standard library, which is plenty for spreading load without a
+/// dependency on a random number generator.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Handle to a started NetSel server, returned by [`NetSelServer::start`]
+///
+/// Dropping the handle leaves the server running in the background; call [`ServerHandle::shutdown`] to stop it.
+pub struct ServerHandle {
+    trigger: watch::Sender<bool>,
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+    registry: Arc<SharedRegistry>,
+}
+
+impl ServerHandle {
+    /// Stop the server
+    ///
+    /// Every component stops accepting connections, proxied connections are given `shutdown_timeout` seconds to
+    /// finish, and all tasks are joined. When the registry is persisted, a final snapshot is written.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use netsel::NetSelServer;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let server = NetSelServer::new();
+    ///     let handle = server.start().await?;
+    ///     tokio::signal::ctrl_c().await?;
+    ///     handle.shutdown().await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn shutdown(self) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Shutting down NetSel Service");
+        let _ = self.trigger.send(true);
+        for (name, task) in self.tasks {
+            if let Err(e) = task.await {
+                error!(component = name, error = %e, "Component task failed");
+            }
+        }
+        
+        let written = self.registry.write().await.snapshot();
+        written.wait().await?;
+        info!("NetSel Service stopped");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    /// A loopback address with a port that was free a moment ago
+    async fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    /// A configuration listening on loopback ports that were free a moment ago
+    async fn local_config() -> NetSelConfig {
+        NetSelConfig {
+            registry_addr: free_addr().await,
+            tcp_proxy_addr: free_addr().await,
+            http_proxy_addr: free_addr().await,
+            dns_addr: free_addr().await,
+            admin_addr: free_addr().await,
+            shutdown_timeout: 1,
+            ..NetSelConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_the_listeners_and_writes_a_final_snapshot() {
+        let data_dir = std::env::temp_dir().join(format!("netsel-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let config = NetSelConfig { data_dir: Some(data_dir.clone()), ..local_config().await };
+        let addrs = [config.registry_addr, config.tcp_proxy_addr, config.http_proxy_addr, config.dns_addr, config.admin_addr];
+
+        let handle = NetSelServer::with_config(config).start().await.unwrap();
+        for addr in addrs {
+            assert!(TcpStream::connect(addr).await.is_ok(), "{} is not listening", addr);
+        }
+
+        handle.shutdown().await.unwrap();
+        for addr in addrs {
+            assert!(TcpStream::connect(addr).await.is_err(), "{} is still listening", addr);
+            TcpListener::bind(addr).await.unwrap();
+        }
+        UdpSocket::bind(addrs[3]).await.unwrap();
+        assert!(data_dir.join("registry.snapshot").exists());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn an_address_in_use_fails_the_start() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let any_port = SocketAddr::from(([127, 0, 0, 1], 0));
+        let config = NetSelConfig {
+            registry_addr: any_port,
+            tcp_proxy_addr: any_port,
+            http_proxy_addr: any_port,
+            dns_addr: any_port,
+            admin_addr: taken.local_addr().unwrap(),
+            ..NetSelConfig::default()
+        };
+
+        let error = NetSelServer::with_config(config).start().await.err().expect("start should fail");
+        assert!(error.to_string().contains("Cannot bind admin API"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn the_lease_policy_follows_the_configuration() {
+        let server = NetSelServer::with_config(NetSelConfig {
+            max_heartbeat_age: 42,
+            min_ttl: 7,
+            max_ttl: 90,
+            ..NetSelConfig::default()
+        });
+        let policy = server.registry().read().await.lease_policy();
+        assert_eq!(policy.default_ttl, Duration::from_secs(42));
+        assert_eq!(policy.min_ttl, Duration::from_secs(7));
+        assert_eq!(policy.max_ttl, Duration::from_secs(90));
+    }
+}
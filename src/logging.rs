@@ -0,0 +1,65 @@
+//! Logging setup for NetSel
+//!
+//! NetSel logs through [`tracing`], with a span per registration connection and per proxied connection carrying
+//! the peer address and, once known, the service name. This module installs the subscriber that formats those
+//! events, as selected in [`crate::NetSelConfig`].
+
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+/// Output format of the log subscriber
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One human-readable line per event
+    #[default]
+    Text,
+    /// Multi-line human-readable output, easier to read during development
+    Pretty,
+    /// One JSON object per event, for log aggregators
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    /// Parse `text`, `pretty` or `json`, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown log format {}, expected text, pretty or json", s)),
+        }
+    }
+}
+
+/// Install a global subscriber writing events in `format`, keeping those allowed by `filter`
+///
+/// `filter` uses the `tracing_subscriber` directive syntax, e.g. `info` or `info,netsel::registry=debug`; the
+/// `RUST_LOG` environment variable overrides it when set. If the application already installed a subscriber, it
+/// is left in place and this does nothing.
+///
+/// # Example
+///
+/// ```rust
+/// use netsel::logging::{self, LogFormat};
+///
+/// logging::init(LogFormat::Json, "info")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn init(format: LogFormat, filter: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(filter)?,
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    // An error only means a subscriber is already installed
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! `netsel` server binary
+//!
+//! Runs a [`NetSelServer`] configured from, in increasing order of precedence: the defaults, the TOML file given
+//! with `--config`, the `NETSEL_*` environment variables and the command line flags. The server stops gracefully on
+//! ctrl-c or SIGTERM; a configuration or startup failure exits with status 1.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use netsel::balancer::LoadBalanceStrategy;
+use netsel::config::{parse_port_range, set};
+use netsel::logging::LogFormat;
+use netsel::network::Subnet;
+use netsel::{NetSelConfig, NetSelServer};
+
+/// Service registration and discovery server
+#[derive(Debug, Parser)]
+#[command(name = "netsel", version, about)]
+struct Cli {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Address of the registration server
+    #[arg(long, value_name = "ADDR")]
+    registry_addr: Option<SocketAddr>,
+    /// Address of the TCP proxy
+    #[arg(long, value_name = "ADDR")]
+    tcp_proxy_addr: Option<SocketAddr>,
+    /// Address of the HTTP proxy
+    #[arg(long, value_name = "ADDR")]
+    http_proxy_addr: Option<SocketAddr>,
+    /// Address of the DNS server
+    #[arg(long, value_name = "ADDR")]
+    dns_addr: Option<SocketAddr>,
+    /// Address of the admin HTTP API
+    #[arg(long, value_name = "ADDR")]
+    admin_addr: Option<SocketAddr>,
+    /// DNS zone under which services are published
+    #[arg(long, value_name = "ZONE")]
+    dns_zone: Option<String>,
+    /// Health check interval in seconds
+    #[arg(long, value_name = "SECS")]
+    health_check_interval: Option<u64>,
+    /// Lease TTL of instances that do not request one, in seconds
+    #[arg(long, value_name = "SECS")]
+    max_heartbeat_age: Option<u64>,
+    /// Time since the last heartbeat after which an instance is removed, in seconds
+    #[arg(long, value_name = "SECS")]
+    reap_timeout: Option<u64>,
+    /// Shortest lease TTL granted to an instance, in seconds
+    #[arg(long, value_name = "SECS")]
+    min_ttl: Option<u64>,
+    /// Longest lease TTL granted to an instance, in seconds
+    #[arg(long, value_name = "SECS")]
+    max_ttl: Option<u64>,
+    /// Times an instance may go in or out of rotation within the flap window before it is dampened; 0 disables it
+    #[arg(long, value_name = "COUNT")]
+    flap_threshold: Option<u32>,
+    /// Window over which flap transitions are counted, in seconds
+    #[arg(long, value_name = "SECS")]
+    flap_window: Option<u64>,
+    /// Time a flapping instance must stay stable before it goes back into rotation, in seconds
+    #[arg(long, value_name = "SECS")]
+    flap_cooldown: Option<u64>,
+    /// Load balancing strategy: round_robin, random, least_connections or consistent_hash
+    #[arg(long, value_name = "STRATEGY")]
+    load_balancing: Option<LoadBalanceStrategy>,
+    /// Load balancing strategy of one service, as SERVICE=STRATEGY; can be repeated
+    #[arg(long, value_name = "SERVICE=STRATEGY", value_parser = parse_service_strategy)]
+    service_load_balancing: Vec<(String, LoadBalanceStrategy)>,
+    /// Also accept the legacy text registration format
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    legacy_protocol: Option<bool>,
+    /// Directory where the registry is persisted across restarts
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+    /// Interval between registry snapshots, in seconds
+    #[arg(long, value_name = "SECS")]
+    snapshot_interval: Option<u64>,
+    /// Time given to proxied connections to finish on shutdown, in seconds
+    #[arg(long, value_name = "SECS")]
+    shutdown_timeout: Option<u64>,
+    /// Ports assigned to registered instances, as START-END
+    #[arg(long, value_name = "START-END", value_parser = |s: &str| parse_port_range(s).map_err(|e| e.to_string()))]
+    port_range: Option<std::ops::RangeInclusive<u16>>,
+    /// Subnet of the virtual addresses assigned to registered instances, such as 10.0.0.0/24
+    #[arg(long, value_name = "CIDR", value_parser = |s: &str| s.parse::<Subnet>().map_err(|e| e.to_string()))]
+    subnet: Option<Subnet>,
+    /// Log output format: text, pretty or json
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Log filter directives, such as info or info,netsel::proxy=debug
+    #[arg(long, value_name = "FILTER")]
+    log_filter: Option<String>,
+}
+
+impl Cli {
+    /// Build the configuration from the file, the environment and the flags
+    fn into_config(self) -> Result<NetSelConfig, Box<dyn std::error::Error>> {
+        let mut config = match &self.config {
+            Some(path) => NetSelConfig::from_file(path)?,
+            None => NetSelConfig::default(),
+        };
+        config.apply_env()?;
+
+        set(&mut config.registry_addr, self.registry_addr);
+        set(&mut config.tcp_proxy_addr, self.tcp_proxy_addr);
+        set(&mut config.http_proxy_addr, self.http_proxy_addr);
+        set(&mut config.dns_addr, self.dns_addr);
+        set(&mut config.admin_addr, self.admin_addr);
+        set(&mut config.dns_zone, self.dns_zone);
+        set(&mut config.health_check_interval, self.health_check_interval);
+        set(&mut config.max_heartbeat_age, self.max_heartbeat_age);
+        set(&mut config.reap_timeout, self.reap_timeout);
+        set(&mut config.min_ttl, self.min_ttl);
+        set(&mut config.max_ttl, self.max_ttl);
+        set(&mut config.flap_threshold, self.flap_threshold);
+        set(&mut config.flap_window, self.flap_window);
+        set(&mut config.flap_cooldown, self.flap_cooldown);
+        set(&mut config.load_balancing, self.load_balancing);
+        config.service_load_balancing.extend(self.service_load_balancing);
+        set(&mut config.legacy_protocol, self.legacy_protocol);
+        set(&mut config.data_dir, self.data_dir.map(Some));
+        set(&mut config.snapshot_interval, self.snapshot_interval);
+        set(&mut config.shutdown_timeout, self.shutdown_timeout);
+        set(&mut config.port_range, self.port_range);
+        set(&mut config.subnet, self.subnet);
+        set(&mut config.log_format, self.log_format);
+        set(&mut config.log_filter, self.log_filter);
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn parse_service_strategy(s: &str) -> Result<(String, LoadBalanceStrategy), String> {
+    let (service, strategy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid value {}, expected SERVICE=STRATEGY", s))?;
+    Ok((service.to_ascii_lowercase(), strategy.parse()?))
+}
+
+/// Wait for ctrl-c, or SIGTERM on Unix
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let server = NetSelServer::with_config(cli.into_config()?);
+    let handle = server.start().await?;
+    shutdown_signal().await?;
+    handle.shutdown().await
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("netsel: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Prometheus metrics for NetSel
+//!
+//! This module collects counters from the registry, the proxies and the health check task, and renders them in the
+//! Prometheus text exposition format. The admin API serves them on `GET /metrics`.
+//!
+//! | Metric                                          | Type    | Labels                         |
+//! |-------------------------------------------------|---------|--------------------------------|
+//! | `netsel_service_instances`                      | gauge   | `status`                       |
+//! | `netsel_registrations_total`                    | counter |                                |
+//! | `netsel_deregistrations_total`                  | counter |                                |
+//! | `netsel_heartbeats_total`                       | counter |                                |
+//! | `netsel_evictions_total`                        | counter |                                |
+//! | `netsel_active_checks_total`                    | counter | `result`                       |
+//! | `netsel_flaps_total`                            | counter |                                |
+//! | `netsel_flapping_instances`                     | gauge   |                                |
+//! | `netsel_port_pool_used`, `netsel_port_pool_size`| gauge   |                                |
+//! | `netsel_ip_pool_used`, `netsel_ip_pool_size`    | gauge   |                                |
+//! | `netsel_proxy_active_connections`               | gauge   | `proxy`, `service`             |
+//! | `netsel_proxy_connections_total`                | counter | `proxy`, `service`             |
+//! | `netsel_proxy_bytes_total`                      | counter | `proxy`, `service`, `direction`|
+//! | `netsel_proxy_upstream_connect_failures_total`  | counter | `proxy`, `service`             |
+//! | `netsel_health_check_duration_seconds`          | summary |                                |
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::registry::{ServiceRegistry, ServiceStatus};
+
+/// Counters of one proxy for one service
+#[derive(Default)]
+struct ProxyStats {
+    active: u64,
+    total: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    connect_failures: u64,
+    /// The service was removed while connections to it were open; the counters go once the last one closes
+    removed: bool,
+}
+
+/// Durations of the health check loop
+#[derive(Default)]
+struct HealthCheckStats {
+    count: u64,
+    sum: Duration,
+}
+
+/// Metrics shared by the NetSel components
+#[derive(Default)]
+pub struct Metrics {
+    registrations: AtomicU64,
+    deregistrations: AtomicU64,
+    heartbeats: AtomicU64,
+    evictions: AtomicU64,
+    active_checks_passed: AtomicU64,
+    active_checks_failed: AtomicU64,
+    flaps: AtomicU64,
+    proxies: Mutex<BTreeMap<(&'static str, String), ProxyStats>>,
+    health_checks: Mutex<HealthCheckStats>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_registration(&self) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_deregistration(&self) {
+        self.deregistrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_heartbeat(&self) {
+        self.heartbeats.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the result of an active health check of an instance
+    pub fn record_active_check(&self, passed: bool) {
+        let counter = if passed { &self.active_checks_passed } else { &self.active_checks_failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an instance starting flap dampening
+    pub fn record_flap(&self) {
+        self.flaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a failed connection from `proxy` (`tcp` or `http`) to an instance of `service`
+    pub fn record_connect_failure(&self, proxy: &'static str, service: &str) {
+        self.proxy_stats(proxy, service, |stats| stats.connect_failures += 1);
+    }
+
+    /// Record one run of the health check loop
+    pub fn record_health_check(&self, duration: Duration) {
+        let mut health_checks = self.health_checks.lock().unwrap();
+        health_checks.count += 1;
+        health_checks.sum += duration;
+    }
+
+    /// Record a new proxied connection to `service`, which stays active until the returned guard is dropped
+    pub fn proxy_connection(self: &Arc<Self>, proxy: &'static str, service: &str) -> ProxyConnection {
+        self.proxy_stats(proxy, service, |stats| {
+            stats.active += 1;
+            stats.total += 1;
+            stats.removed = false;
+        });
+        ProxyConnection {
+            metrics: self.clone(),
+            proxy,
+            service: service.to_string(),
+        }
+    }
+
+    /// Drop the proxy counters of `service`, which has no instance left
+    ///
+    /// Counters with connections still open are kept until the last one closes, so that service names that come and
+    /// go do not accumulate.
+    pub fn forget_service(&self, service: &str) {
+        self.proxies.lock().unwrap().retain(|(_, name), stats| {
+            if name != service {
+                return true;
+            }
+            stats.removed = true;
+            stats.active > 0
+        });
+    }
+
+    fn proxy_stats(&self, proxy: &'static str, service: &str, update: impl FnOnce(&mut ProxyStats)) {
+        let mut proxies = self.proxies.lock().unwrap();
+        update(proxies.entry((proxy, service.to_string())).or_default());
+    }
+
+    /// Render all metrics in the Prometheus text format, reading gauges from `registry`
+    pub fn render(&self, registry: &ServiceRegistry) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "netsel_service_instances", "gauge", "Registered service instances by status");
+        for status in ServiceStatus::ALL {
+            let count = registry.services
+                .values()
+                .flat_map(|instances| instances.values())
+                .filter(|service| service.status == status)
+                .count();
+            let _ = writeln!(out, "netsel_service_instances{{status=\"{}\"}} {}", status.as_str(), count);
+        }
+
+        counter(&mut out, "netsel_registrations_total", "Instances registered", &self.registrations);
+        counter(&mut out, "netsel_deregistrations_total", "Instances deregistered", &self.deregistrations);
+        counter(&mut out, "netsel_heartbeats_total", "Heartbeats accepted", &self.heartbeats);
+        counter(&mut out, "netsel_evictions_total", "Instances removed after the reap timeout", &self.evictions);
+        header(&mut out, "netsel_active_checks_total", "counter", "Active health checks of instances by result");
+        for (result, value) in [("pass", &self.active_checks_passed), ("fail", &self.active_checks_failed)] {
+            let _ = writeln!(out, "netsel_active_checks_total{{result=\"{}\"}} {}", result, value.load(Ordering::Relaxed));
+        }
+        counter(&mut out, "netsel_flaps_total", "Times instances were dampened for flapping", &self.flaps);
+        let flapping = registry.services
+            .values()
+            .flat_map(|instances| instances.values())
+            .filter(|service| service.is_flapping())
+            .count();
+        header(&mut out, "netsel_flapping_instances", "gauge", "Instances kept out of rotation for flapping");
+        let _ = writeln!(out, "netsel_flapping_instances {}", flapping);
+
+        let (used, size) = registry.port_pool_usage();
+        header(&mut out, "netsel_port_pool_used", "gauge", "Ports of the port pool in use");
+        let _ = writeln!(out, "netsel_port_pool_used {}", used);
+        header(&mut out, "netsel_port_pool_size", "gauge", "Ports in the port pool");
+        let _ = writeln!(out, "netsel_port_pool_size {}", size);
+
+        let (used, size) = registry.ip_pool_usage();
+        header(&mut out, "netsel_ip_pool_used", "gauge", "Virtual addresses in use");
+        let _ = writeln!(out, "netsel_ip_pool_used {}", used);
+        header(&mut out, "netsel_ip_pool_size", "gauge", "Virtual addresses in the address pool");
+        let _ = writeln!(out, "netsel_ip_pool_size {}", size);
+
+        let proxies = self.proxies.lock().unwrap();
+        header(&mut out, "netsel_proxy_active_connections", "gauge", "Active proxied connections");
+        for ((proxy, service), stats) in proxies.iter() {
+            let _ = writeln!(out, "netsel_proxy_active_connections{} {}", proxy_labels(proxy, service), stats.active);
+        }
+        header(&mut out, "netsel_proxy_connections_total", "counter", "Proxied connections");
+        for ((proxy, service), stats) in proxies.iter() {
+            let _ = writeln!(out, "netsel_proxy_connections_total{} {}", proxy_labels(proxy, service), stats.total);
+        }
+        header(&mut out, "netsel_proxy_bytes_total", "counter", "Bytes proxied, from clients (in) and to clients (out)");
+        for ((proxy, service), stats) in proxies.iter() {
+            for (direction, bytes) in [("in", stats.bytes_in), ("out", stats.bytes_out)] {
+                let _ = writeln!(
+                    out,
+                    "netsel_proxy_bytes_total{{proxy=\"{}\",service=\"{}\",direction=\"{}\"}} {}",
+                    proxy, escape(service), direction, bytes
+                );
+            }
+        }
+        header(&mut out, "netsel_proxy_upstream_connect_failures_total", "counter", "Failed connections to instances");
+        for ((proxy, service), stats) in proxies.iter() {
+            let labels = proxy_labels(proxy, service);
+            let _ = writeln!(out, "netsel_proxy_upstream_connect_failures_total{} {}", labels, stats.connect_failures);
+        }
+        drop(proxies);
+
+        let health_checks = self.health_checks.lock().unwrap();
+        header(&mut out, "netsel_health_check_duration_seconds", "summary", "Duration of the health check loop");
+        let _ = writeln!(out, "netsel_health_check_duration_seconds_sum {}", health_checks.sum.as_secs_f64());
+        let _ = writeln!(out, "netsel_health_check_duration_seconds_count {}", health_checks.count);
+
+        out
+    }
+}
+
+/// An active proxied connection, counted until dropped
+pub struct ProxyConnection {
+    metrics: Arc<Metrics>,
+    proxy: &'static str,
+    service: String,
+}
+
+impl ProxyConnection {
+    /// Record bytes received from the client
+    pub fn add_bytes_in(&self, bytes: u64) {
+        self.metrics.proxy_stats(self.proxy, &self.service, |stats| stats.bytes_in += bytes);
+    }
+
+    /// Record bytes sent to the client
+    pub fn add_bytes_out(&self, bytes: u64) {
+        self.metrics.proxy_stats(self.proxy, &self.service, |stats| stats.bytes_out += bytes);
+    }
+}
+
+impl Drop for ProxyConnection {
+    fn drop(&mut self) {
+        let mut proxies = self.metrics.proxies.lock().unwrap();
+        let key = (self.proxy, std::mem::take(&mut self.service));
+        if let Some(stats) = proxies.get_mut(&key) {
+            stats.active -= 1;
+            if stats.active == 0 && stats.removed {
+                proxies.remove(&key);
+            }
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn proxy_labels(proxy: &str, service: &str) -> String {
+    format!("{{proxy=\"{}\",service=\"{}\"}}", proxy, escape(service))
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    use crate::registry::Registration;
+
+    fn register(registry: &mut ServiceRegistry, hostname: &str) -> String {
+        let registration = Registration::new(hostname.to_string(), IpAddr::from([127, 0, 0, 1]));
+        registry.register(registration).unwrap().instance_id
+    }
+
+    fn proxied_services(metrics: &Metrics) -> Vec<(&'static str, String)> {
+        metrics.proxies.lock().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn render_follows_the_exposition_format() {
+        let mut registry = ServiceRegistry::new();
+        register(&mut registry, "orders");
+        let metrics = registry.metrics();
+        metrics.record_heartbeat();
+        metrics.record_active_check(false);
+        let connection = metrics.proxy_connection("http", "or\"d\\ers\n");
+        connection.add_bytes_in(10);
+        connection.add_bytes_out(250);
+
+        let out = metrics.render(&registry);
+        assert!(out.ends_with('\n'));
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in [
+            "# HELP netsel_registrations_total Instances registered",
+            "# TYPE netsel_registrations_total counter",
+            "netsel_registrations_total 1",
+            "netsel_heartbeats_total 1",
+            "netsel_service_instances{status=\"ready\"} 1",
+            "netsel_service_instances{status=\"offline\"} 0",
+            "netsel_active_checks_total{result=\"fail\"} 1",
+            "# TYPE netsel_proxy_active_connections gauge",
+            "netsel_proxy_active_connections{proxy=\"http\",service=\"or\\\"d\\\\ers\\n\"} 1",
+            "netsel_proxy_bytes_total{proxy=\"http\",service=\"or\\\"d\\\\ers\\n\",direction=\"out\"} 250",
+            "# TYPE netsel_health_check_duration_seconds summary",
+            "netsel_health_check_duration_seconds_count 0",
+        ] {
+            assert!(lines.contains(&expected), "missing {:?} in\n{}", expected, out);
+        }
+
+        // Every family has one HELP and one TYPE line, and every sample a numeric value
+        let mut types = Vec::new();
+        for line in &lines {
+            if let Some(family) = line.strip_prefix("# TYPE ") {
+                let name = family.split(' ').next().unwrap();
+                assert!(!types.contains(&name), "{} declared twice", name);
+                types.push(name);
+            } else if !line.starts_with("# HELP ") {
+                let (_, value) = line.rsplit_once(' ').unwrap();
+                assert!(value.parse::<f64>().is_ok(), "{}", line);
+            }
+        }
+        assert_eq!(lines.iter().filter(|line| line.starts_with("# HELP ")).count(), types.len());
+    }
+
+    #[test]
+    fn proxy_counters_go_with_the_last_instance_of_their_service() {
+        let mut registry = ServiceRegistry::new();
+        let orders = register(&mut registry, "orders");
+        let second = register(&mut registry, "orders");
+        let billing = register(&mut registry, "billing");
+        let metrics = registry.metrics();
+        drop(metrics.proxy_connection("tcp", "orders"));
+        metrics.record_connect_failure("http", "orders");
+        let open = metrics.proxy_connection("tcp", "billing");
+
+        // Other instances of the service remain
+        registry.unregister("orders", &orders);
+        assert_eq!(proxied_services(&metrics).len(), 3);
+
+        registry.unregister("orders", &second);
+        registry.unregister("billing", &billing);
+        assert_eq!(proxied_services(&metrics), [("tcp", "billing".to_string())]);
+        let out = metrics.render(&registry);
+        assert!(out.contains("netsel_proxy_active_connections{proxy=\"tcp\",service=\"billing\"} 1"));
+
+        // Counters of connections still open go once they close
+        drop(open);
+        assert!(proxied_services(&metrics).is_empty());
+        assert!(!metrics.render(&registry).contains("service=\""));
+    }
+
+    #[test]
+    fn a_service_registering_again_keeps_the_counters_of_open_connections() {
+        let mut registry = ServiceRegistry::new();
+        let id = register(&mut registry, "orders");
+        let metrics = registry.metrics();
+        let open = metrics.proxy_connection("tcp", "orders");
+        registry.unregister("orders", &id);
+
+        register(&mut registry, "orders");
+        let reopened = metrics.proxy_connection("tcp", "orders");
+        drop(open);
+        drop(reopened);
+        assert_eq!(proxied_services(&metrics), [("tcp", "orders".to_string())]);
+        let out = metrics.render(&registry);
+        assert!(out.contains("netsel_proxy_connections_total{proxy=\"tcp\",service=\"orders\"} 2"));
+    }
+}
@@ -1,47 +1,254 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::net::{IpAddr, Ipv4Addr};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::str::FromStr;
 
+/// Offset in the subnet of the first allocated address, when the subnet has room for it
+const FIRST_HOST_OFFSET: u32 = 100;
+
+/// IPv4 subnet in CIDR notation, such as `10.0.0.0/24`
+///
+/// # Example
+///
+/// ```rust
+/// use std::net::Ipv4Addr;
+/// use netsel::network::Subnet;
+///
+/// let subnet: Subnet = "10.1.0.0/16".parse()?;
+/// assert_eq!(subnet.addr(), Ipv4Addr::new(10, 1, 0, 0));
+/// assert_eq!(subnet.prefix_len(), 16);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// Create a subnet, clearing the host bits of `addr`
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        if prefix_len > 30 {
+            return Err(format!("Subnet prefix length must be at most 30, got {}", prefix_len).into());
+        }
+        let mask = mask(prefix_len);
+        Ok(Self {
+            addr: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix_len,
+        })
+    }
+
+    /// Network address of the subnet
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    fn mask(&self) -> u32 {
+        mask(self.prefix_len)
+    }
+}
+
+impl Default for Subnet {
+    fn default() -> Self {
+        Self {
+            addr: Ipv4Addr::new(10, 0, 0, 0),
+            prefix_len: 24,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid subnet {}, expected CIDR notation such as 10.0.0.0/24", s))?;
+        Self::new(addr.parse()?, prefix_len.parse()?)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0)
+}
+
+/// Pool of the virtual addresses assigned to registered instances
+///
+/// The registry owns the single pool of the server: addresses are taken at registration and given back when an
+/// instance is removed. They are handed out in order, wrapping around to the start of the pool, so a released address
+/// is only reused once the addresses after it have been tried.
+///
+/// # Example
+///
+/// ```rust
+/// use netsel::network::VirtualNetwork;
+///
+/// let mut network = VirtualNetwork::with_subnet("10.0.0.0/30".parse()?);
+/// let first = network.allocate_ip()?;
+/// let second = network.allocate_ip()?;
+/// assert!(network.allocate_ip().is_err()); // 10.0.0.1 and 10.0.0.2 are the only host addresses
+///
+/// network.release(first);
+/// assert_eq!(network.allocate_ip()?, first);
+/// # assert_ne!(first, second);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
 pub struct VirtualNetwork {
-    base_ip: u32,
-    next_ip: AtomicU32,
-    subnet_mask: u32,
+    subnet: Subnet,
+    /// First and last address of the pool
+    first_ip: u32,
+    last_ip: u32,
+    /// Where the search for a free address starts
+    next_ip: u32,
+    used: HashSet<u32>,
+}
+
+impl Default for VirtualNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VirtualNetwork {
     pub fn new() -> Self {
         // 10.0.0.0/24 network
+        Self::with_subnet(Subnet::default())
+    }
+
+    /// Create a pool of the addresses of `subnet`
+    ///
+    /// The pool starts at the 100th address of the subnet (`10.0.0.100` in `10.0.0.0/24`), or at its first host
+    /// address when the subnet is too small for that, and ends before the broadcast address.
+    pub fn with_subnet(subnet: Subnet) -> Self {
+        let base_ip = u32::from(subnet.addr());
+        let size = !subnet.mask() as u64 + 1;
+        let offset = if size > 2 * FIRST_HOST_OFFSET as u64 { FIRST_HOST_OFFSET } else { 1 };
         Self {
-            base_ip: u32::from_be_bytes([10, 0, 0, 0]),
-            next_ip: AtomicU32::new(u32::from_be_bytes([10, 0, 0, 100])), // 10.0.0.100
-            subnet_mask: u32::from_be_bytes([255, 255, 255, 0]),
+            subnet,
+            first_ip: base_ip + offset,
+            last_ip: (base_ip | !subnet.mask()) - 1,
+            next_ip: base_ip + offset,
+            used: HashSet::new(),
         }
     }
-    
-    pub async fn run(&mut self) {
-        // Simplified: no actual network interface running
-        // In production, this would manage the virtual network devices
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+
+    /// Subnet the addresses are allocated from
+    pub fn subnet(&self) -> Subnet {
+        self.subnet
+    }
+
+    /// Allocate a free address, failing when the pool is exhausted
+    pub fn allocate_ip(&mut self) -> Result<IpAddr, Box<dyn std::error::Error>> {
+        // In u64, as the offsets overflow u32 in pools spanning most of the address space
+        let size = self.size() as u64;
+        let start = u64::from(self.next_ip - self.first_ip);
+        for i in 0..size {
+            let ip = self.first_ip + ((start + i) % size) as u32;
+            if self.used.insert(ip) {
+                self.next_ip = if ip == self.last_ip { self.first_ip } else { ip + 1 };
+                return Ok(IpAddr::V4(Ipv4Addr::from(ip)));
+            }
         }
+        Err(format!("No free address left in {}", self.subnet).into())
+    }
+
+    /// Allocate `ip` itself, if it is in the pool and free
+    pub fn allocate_specific(&mut self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ipv4) => {
+                let ip = u32::from(ipv4);
+                (self.first_ip..=self.last_ip).contains(&ip) && self.used.insert(ip)
+            }
+            _ => false,
+        }
+    }
+
+    /// Return `ip` to the pool
+    pub fn release(&mut self, ip: IpAddr) {
+        if let IpAddr::V4(ipv4) = ip {
+            self.used.remove(&u32::from(ipv4));
+        }
+    }
+
+    /// Number of addresses in use
+    pub fn used(&self) -> usize {
+        self.used.len()
     }
-    
-    pub fn allocate_ip(&self) -> IpAddr {
-        let current = self.next_ip.fetch_add(1, Ordering::Relaxed);
-        let ip_bytes = current.to_be_bytes();
-        IpAddr::V4(Ipv4Addr::new(ip_bytes[0], ip_bytes[1], ip_bytes[2], ip_bytes[3]))
+
+    /// Number of addresses in the pool
+    pub fn size(&self) -> usize {
+        (self.last_ip - self.first_ip) as usize + 1
     }
-    
+
+    /// Whether `ip` belongs to the subnet
     pub fn is_internal_ip(&self, ip: IpAddr) -> bool {
         match ip {
-            IpAddr::V4(ipv4) => {
-                let ip_u32 = u32::from_be_bytes(ipv4.octets());
-                (ip_u32 & self.subnet_mask) == (self.base_ip & self.subnet_mask)
-            },
+            IpAddr::V4(ipv4) => (u32::from(ipv4) & self.subnet.mask()) == u32::from(self.subnet.addr()),
             _ => false,
         }
     }
 }
 
-pub async fn create_virtual_network() -> Result<VirtualNetwork, Box<dyn std::error::Error>> {
-    Ok(VirtualNetwork::new())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(subnet: &str) -> VirtualNetwork {
+        VirtualNetwork::with_subnet(subnet.parse().unwrap())
+    }
+
+    #[test]
+    fn allocates_from_the_100th_address_up_to_the_broadcast() {
+        let mut network = network("10.0.0.0/24");
+        assert_eq!(network.size(), 155);
+        assert_eq!(network.allocate_ip().unwrap(), IpAddr::from([10, 0, 0, 100]));
+        for _ in 1..155 {
+            network.allocate_ip().unwrap();
+        }
+        assert!(network.allocate_ip().is_err());
+        assert!(!network.allocate_specific(IpAddr::from([10, 0, 0, 255])));
+    }
+
+    #[test]
+    fn reuses_released_addresses_after_wrapping() {
+        let mut network = network("10.0.0.0/30");
+        let first = network.allocate_ip().unwrap();
+        let second = network.allocate_ip().unwrap();
+        network.release(first);
+        assert_eq!(network.allocate_ip().unwrap(), first);
+        network.release(second);
+        network.release(first);
+        // The search goes on after the last allocated address before reusing earlier ones
+        assert_eq!(network.allocate_ip().unwrap(), second);
+    }
+
+    #[test]
+    fn allocate_specific_only_takes_free_addresses_of_the_pool() {
+        let mut network = network("10.0.0.0/24");
+        assert!(network.allocate_specific(IpAddr::from([10, 0, 0, 150])));
+        assert!(!network.allocate_specific(IpAddr::from([10, 0, 0, 150])));
+        assert!(!network.allocate_specific(IpAddr::from([10, 0, 1, 150])));
+        assert_eq!(network.used(), 1);
+    }
+
+    #[test]
+    fn wraps_around_in_pools_spanning_the_address_space() {
+        for subnet in ["0.0.0.0/0", "128.0.0.0/1"] {
+            let mut network = network(subnet);
+            network.next_ip = network.last_ip;
+            let last = IpAddr::V4(Ipv4Addr::from(network.last_ip));
+            assert_eq!(network.allocate_ip().unwrap(), last);
+            assert_eq!(network.allocate_ip().unwrap(), IpAddr::V4(Ipv4Addr::from(network.first_ip)));
+        }
+    }
 }
@@ -0,0 +1,521 @@
+//! Registry persistence for NetSel
+//!
+//! This module stores the registry in a data directory so that a restarted server remembers its services. Every
+//! registration and deregistration is appended to a write-ahead log (`registry.wal`, one JSON record per line), and
+//! the whole registry is periodically written to a snapshot (`registry.snapshot`), after which the log is truncated.
+//! On startup the snapshot is loaded and the log replayed on top of it.
+//!
+//! Heartbeats are not logged: restored instances start with a fresh heartbeat, so they get a full heartbeat period
+//! to reach the restarted server. Timestamps are stored as wall-clock time (milliseconds since the Unix epoch).
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+use crate::protocol::{HealthCheck, HealthCheckKind};
+use crate::registry::{LeasePolicy, ServiceInfo, ServiceStatus};
+
+/// File name of the snapshot inside the data directory
+const SNAPSHOT_FILE: &str = "registry.snapshot";
+
+/// File name of the write-ahead log inside the data directory
+const LOG_FILE: &str = "registry.wal";
+
+/// A registered instance as stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredService {
+    pub hostname: String,
+    pub instance_id: String,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub endpoint: SocketAddr,
+    /// Registration time, in milliseconds since the Unix epoch
+    pub registered_at: u64,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub health_check: Option<StoredHealthCheck>,
+    /// Status set by an operator, if any
+    #[serde(default)]
+    pub operator_status: Option<OperatorStatus>,
+    /// Lease TTL granted at registration, in seconds
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// A status set by an operator, kept across restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperatorStatus {
+    Draining,
+    Maintenance,
+}
+
+/// An active health check as stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredHealthCheck {
+    /// Path of an HTTP check; a TCP check has none
+    #[serde(default)]
+    pub http_path: Option<String>,
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    pub failure_threshold: u32,
+}
+
+impl StoredHealthCheck {
+    pub fn from_check(check: &HealthCheck) -> Self {
+        let (http_path, expected_status) = match &check.kind {
+            HealthCheckKind::Tcp => (None, Vec::new()),
+            HealthCheckKind::Http { path, expected_status } => (Some(path.clone()), expected_status.clone()),
+        };
+        Self {
+            http_path,
+            expected_status,
+            interval_ms: check.interval.as_millis() as u64,
+            timeout_ms: check.timeout.as_millis() as u64,
+            failure_threshold: check.failure_threshold,
+        }
+    }
+
+    pub fn into_check(self) -> HealthCheck {
+        let kind = match self.http_path {
+            Some(path) => HealthCheckKind::Http {
+                path,
+                expected_status: self.expected_status,
+            },
+            None => HealthCheckKind::Tcp,
+        };
+        HealthCheck {
+            kind,
+            interval: Duration::from_millis(self.interval_ms),
+            timeout: Duration::from_millis(self.timeout_ms),
+            failure_threshold: self.failure_threshold,
+        }
+    }
+}
+
+impl StoredService {
+    pub fn from_info(service: &ServiceInfo) -> Self {
+        Self {
+            hostname: service.hostname.clone(),
+            instance_id: service.instance_id.clone(),
+            ip: service.ip,
+            port: service.port,
+            endpoint: service.endpoint,
+            registered_at: to_unix_millis(service.registered_at),
+            version: service.version.clone(),
+            tags: service.tags.clone(),
+            metadata: service.metadata.clone(),
+            health_check: service.health_check.as_ref().map(StoredHealthCheck::from_check),
+            operator_status: match service.status {
+                ServiceStatus::Draining => Some(OperatorStatus::Draining),
+                ServiceStatus::Maintenance => Some(OperatorStatus::Maintenance),
+                _ => None,
+            },
+            ttl_secs: Some(service.ttl.as_secs()),
+        }
+    }
+
+    /// Turn the stored instance back into a ready instance whose last heartbeat is now
+    ///
+    /// An instance with an active health check starts with no failed check, so it stays ready until it fails again.
+    /// An instance an operator put in draining or maintenance stays so. The stored TTL is granted again under
+    /// `lease`, whose bounds may have changed since.
+    pub fn into_info(self, lease: &LeasePolicy) -> ServiceInfo {
+        ServiceInfo {
+            addr: SocketAddr::new(self.ip, self.port),
+            hostname: self.hostname,
+            instance_id: self.instance_id,
+            ip: self.ip,
+            port: self.port,
+            endpoint: self.endpoint,
+            registered_at: from_unix_millis(self.registered_at),
+            last_heartbeat: Instant::now(),
+            status: match self.operator_status {
+                Some(OperatorStatus::Draining) => ServiceStatus::Draining,
+                Some(OperatorStatus::Maintenance) => ServiceStatus::Maintenance,
+                None => ServiceStatus::Ready,
+            },
+            version: self.version,
+            tags: self.tags,
+            metadata: self.metadata,
+            health_check: self.health_check.map(StoredHealthCheck::into_check),
+            check_failures: 0,
+            ttl: lease.grant(self.ttl_secs.map(Duration::from_secs)),
+            transitions: VecDeque::new(),
+            dampened_until: None,
+        }
+    }
+}
+
+/// One entry of the write-ahead log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum LogRecord {
+    Register { service: Box<StoredService> },
+    Unregister { hostname: String, instance_id: String },
+}
+
+/// Full registry state as stored in a snapshot
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Counter used to generate the next instance ID
+    pub next_instance: u64,
+    /// Ports of the port pool in use
+    pub used_ports: Vec<u16>,
+    pub services: Vec<StoredService>,
+}
+
+/// Snapshot and write-ahead log of a registry, kept in a data directory
+///
+/// Writes are handed over to a dedicated writer thread in order, so the registry never waits on the disk while it is
+/// locked. Log records are flushed to disk in batches; [`PendingWrite`] tells when a snapshot is on disk.
+pub struct RegistryStore {
+    commands: mpsc::Sender<Command>,
+}
+
+/// A write queued for the writer thread
+enum Command {
+    Append(LogRecord),
+    Snapshot(Box<Snapshot>, oneshot::Sender<io::Result<()>>),
+}
+
+/// A snapshot queued for writing, see [`ServiceRegistry::snapshot`](crate::registry::ServiceRegistry::snapshot)
+pub struct PendingWrite(Option<oneshot::Receiver<io::Result<()>>>);
+
+impl PendingWrite {
+    /// Nothing to wait for
+    pub(crate) fn done() -> Self {
+        Self(None)
+    }
+
+    /// Wait until the write is on disk, along with every log record queued before it
+    pub async fn wait(self) -> io::Result<()> {
+        match self.0 {
+            Some(written) => written
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("Registry writer stopped"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RegistryStore {
+    /// Open the store in `dir`, creating the directory if needed
+    ///
+    /// Returns the store along with the last snapshot and the log records written after it. A truncated last log
+    /// line, left by a crash in the middle of a write, is skipped.
+    pub fn open(dir: &Path) -> io::Result<(Self, Snapshot, Vec<LogRecord>)> {
+        fs::create_dir_all(dir)?;
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        let log_path = dir.join(LOG_FILE);
+
+        let snapshot = match fs::read(&snapshot_path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| invalid_data(&snapshot_path, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut records = Vec::new();
+        if log_path.exists() {
+            let lines: Vec<String> = BufReader::new(File::open(&log_path)?).lines().collect::<io::Result<_>>()?;
+            let count = lines.len();
+            for (index, line) in lines.into_iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(record) => records.push(record),
+                    Err(e) if index + 1 == count => {
+                        warn!(path = %log_path.display(), error = %e, "Ignoring truncated record at the end of the log");
+                    }
+                    Err(e) => return Err(invalid_data(&log_path, e)),
+                }
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        let writer = StoreWriter {
+            dir: dir.to_path_buf(),
+            snapshot_path,
+            log_path,
+            log,
+        };
+        let (commands, queue) = mpsc::channel();
+        thread::Builder::new()
+            .name("netsel-registry-writer".to_string())
+            .spawn(move || writer.run(queue))?;
+        Ok((Self { commands }, snapshot, records))
+    }
+
+    /// Queue a record to be appended to the log
+    pub fn append(&mut self, record: LogRecord) -> io::Result<()> {
+        self.commands
+            .send(Command::Append(record))
+            .map_err(|_| io::Error::other("Registry writer stopped"))
+    }
+
+    /// Queue `snapshot` to replace the snapshot on disk, truncating the log whose records it already contains
+    pub fn write_snapshot(&mut self, snapshot: Snapshot) -> PendingWrite {
+        let (written, done) = oneshot::channel();
+        // If the writer stopped, the dropped sender reports it to the waiter
+        let _ = self.commands.send(Command::Snapshot(Box::new(snapshot), written));
+        PendingWrite(Some(done))
+    }
+}
+
+/// Writer thread of a [`RegistryStore`], the only one touching its files
+struct StoreWriter {
+    dir: PathBuf,
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    log: File,
+}
+
+impl StoreWriter {
+    /// Apply the queued writes until the store is dropped
+    fn run(mut self, queue: mpsc::Receiver<Command>) {
+        let mut pending = None;
+        while let Some(command) = pending.take().or_else(|| queue.recv().ok()) {
+            match command {
+                Command::Append(record) => {
+                    // Write every record already queued, then flush them to disk at once
+                    let mut result = self.write_record(&record);
+                    while let Ok(command) = queue.try_recv() {
+                        match command {
+                            Command::Append(record) => result = result.and(self.write_record(&record)),
+                            command => {
+                                pending = Some(command);
+                                break;
+                            }
+                        }
+                    }
+                    if let Err(e) = result.and_then(|()| self.log.sync_data()) {
+                        error!(error = %e, "Error writing registry log");
+                    }
+                }
+                Command::Snapshot(snapshot, written) => {
+                    let result = self.write_snapshot(&snapshot);
+                    if let Err(e) = &result {
+                        error!(error = %e, "Error writing registry snapshot");
+                    }
+                    let _ = written.send(result);
+                }
+            }
+        }
+    }
+
+    fn write_record(&mut self, record: &LogRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        self.log.write_all(&line)
+    }
+
+    /// Replace the snapshot and truncate the log
+    ///
+    /// The snapshot is written to a temporary file first and renamed into place, so a crash never leaves a partial
+    /// snapshot behind. The rename is made durable before the log is truncated, so a crash loses neither.
+    fn write_snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let tmp_path = self.snapshot_path.with_extension("snapshot.tmp");
+        let data = serde_json::to_vec_pretty(snapshot).map_err(io::Error::other)?;
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&data)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.snapshot_path)?;
+        sync_dir(&self.dir)?;
+
+        self.log = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.log_path)?;
+        self.log.sync_all()
+    }
+}
+
+/// Flush the entries of `dir` to disk; directories cannot be opened for that on Windows
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn invalid_data(path: &Path, e: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+/// Convert an `Instant` to wall-clock milliseconds since the Unix epoch
+pub(crate) fn to_unix_millis(instant: Instant) -> u64 {
+    let time = SystemTime::now()
+        .checked_sub(instant.elapsed())
+        .unwrap_or(UNIX_EPOCH);
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Convert wall-clock milliseconds since the Unix epoch back to an `Instant`
+fn from_unix_millis(millis: u64) -> Instant {
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_millis(millis))
+        .unwrap_or_default();
+    Instant::now().checked_sub(age).unwrap_or_else(Instant::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Registration, ServiceRegistry};
+
+    /// An empty data directory for `test`, removed when dropped
+    struct DataDir(PathBuf);
+
+    impl DataDir {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("netsel-{}-{}", test, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for DataDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn stored(instance_id: &str, ip: [u8; 4], port: u16) -> StoredService {
+        StoredService {
+            hostname: "orders".to_string(),
+            instance_id: instance_id.to_string(),
+            ip: IpAddr::from(ip),
+            port,
+            endpoint: SocketAddr::from(([127, 0, 0, 1], port)),
+            registered_at: to_unix_millis(Instant::now()),
+            version: None,
+            tags: BTreeSet::new(),
+            metadata: BTreeMap::new(),
+            health_check: None,
+            operator_status: None,
+            ttl_secs: None,
+        }
+    }
+
+    fn register(registry: &mut ServiceRegistry) -> ServiceInfo {
+        registry.register(Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]))).unwrap()
+    }
+
+    fn log_line(record: &LogRecord) -> String {
+        serde_json::to_string(record).unwrap() + "\n"
+    }
+
+    #[tokio::test]
+    async fn the_log_is_replayed_on_top_of_the_snapshot() {
+        let dir = DataDir::new("replay");
+        fs::create_dir_all(&dir.0).unwrap();
+        let snapshot = Snapshot {
+            next_instance: 3,
+            used_ports: vec![9000, 9001],
+            services: vec![stored("orders-1", [10, 0, 0, 100], 9000), stored("orders-2", [10, 0, 0, 101], 9001)],
+        };
+        fs::write(dir.0.join(SNAPSHOT_FILE), serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let added = StoredService {
+            operator_status: Some(OperatorStatus::Maintenance),
+            ttl_secs: Some(20),
+            ..stored("orders-3", [10, 0, 0, 102], 9002)
+        };
+        let updated = StoredService {
+            tags: BTreeSet::from(["canary".to_string()]),
+            ..stored("orders-2", [10, 0, 0, 101], 9001)
+        };
+        let log = [
+            log_line(&LogRecord::Unregister { hostname: "orders".to_string(), instance_id: "orders-1".to_string() }),
+            log_line(&LogRecord::Register { service: Box::new(added) }),
+            log_line(&LogRecord::Register { service: Box::new(updated) }),
+            // Left by a crash in the middle of a write
+            "{\"op\":\"register\",\"serv".to_string(),
+        ];
+        fs::write(dir.0.join(LOG_FILE), log.concat()).unwrap();
+
+        let mut registry = ServiceRegistry::new();
+        registry.enable_persistence(&dir.0).unwrap();
+        let ids: Vec<&str> = registry.get_instances("orders").iter().map(|s| s.instance_id.as_str()).collect();
+        assert_eq!(ids, ["orders-2", "orders-3"]);
+        let updated = registry.get_instance("orders", "orders-2").unwrap();
+        assert_eq!(updated.status, ServiceStatus::Ready);
+        assert!(updated.tags.contains("canary"));
+        let added = registry.get_instance("orders", "orders-3").unwrap();
+        assert_eq!(added.status, ServiceStatus::Maintenance);
+        assert_eq!(added.ttl, Duration::from_secs(20));
+        assert_eq!(registry.port_pool_usage().0, 2);
+        assert_eq!(registry.ip_pool_usage().0, 2);
+
+        // The removed instance's port and address are free again, those of the others are not
+        let service = register(&mut registry);
+        assert_eq!(service.instance_id, "orders-4");
+        assert_eq!(service.port, 9000);
+        assert_eq!(service.ip, IpAddr::from([10, 0, 0, 100]));
+        registry.snapshot().wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaying_compacts_the_log_into_the_snapshot() {
+        let dir = DataDir::new("compact");
+        fs::create_dir_all(&dir.0).unwrap();
+        let record = LogRecord::Register { service: Box::new(stored("orders-1", [10, 0, 0, 100], 9000)) };
+        fs::write(dir.0.join(LOG_FILE), log_line(&record)).unwrap();
+
+        let mut registry = ServiceRegistry::new();
+        registry.enable_persistence(&dir.0).unwrap();
+        // Queued after the snapshot written by `enable_persistence`
+        registry.snapshot().wait().await.unwrap();
+
+        let (_, snapshot, records) = RegistryStore::open(&dir.0).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(snapshot.used_ports, [9000]);
+        assert_eq!(snapshot.services.len(), 1);
+        assert_eq!(snapshot.services[0].instance_id, "orders-1");
+    }
+
+    #[tokio::test]
+    async fn a_restarted_registry_gets_its_instances_back() {
+        let dir = DataDir::new("restart");
+        let mut registry = ServiceRegistry::new();
+        registry.enable_persistence(&dir.0).unwrap();
+        let kept = register(&mut registry);
+        let removed = register(&mut registry);
+        registry.set_status("orders", &kept.instance_id, ServiceStatus::Draining);
+        registry.unregister("orders", &removed.instance_id);
+        registry.snapshot().wait().await.unwrap();
+
+        let mut restarted = ServiceRegistry::new();
+        restarted.enable_persistence(&dir.0).unwrap();
+        let instances = restarted.get_instances("orders");
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].instance_id, kept.instance_id);
+        assert_eq!(instances[0].addr, kept.addr);
+        assert_eq!(instances[0].status, ServiceStatus::Draining);
+        // Generated IDs are not reused after a restart
+        let service = register(&mut restarted);
+        assert_ne!(service.instance_id, removed.instance_id);
+        restarted.snapshot().wait().await.unwrap();
+    }
+}
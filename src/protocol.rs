@@ -0,0 +1,935 @@
+//! Wire protocol of the NetSel registration server
+//!
+//! Services talk to the registration server with length-prefixed frames:
+//!
+//! | Field     | Size     | Description                                |
+//! |-----------|----------|--------------------------------------------|
+//! | `version` | 1 byte   | Protocol version, see [`PROTOCOL_VERSION`] |
+//! | `type`    | 1 byte   | Message type, see [`Message`]              |
+//! | `length`  | 4 bytes  | Payload length in bytes, big-endian        |
+//! | `payload` | `length` | Message fields                             |
+//!
+//! Payload fields are encoded in order: integers big-endian, strings as a `u16` length followed by UTF-8 bytes,
+//! IP addresses as a family byte (`4` or `6`) followed by the address octets, and optional fields as a presence
+//! byte (`0` or `1`) followed by the value. Lists are a `u16` count followed by their items, and maps a `u16`
+//! count followed by key/value string pairs. A connection may carry several request/response exchanges, except
+//! after a `Watch` request, from which point the server only streams `Event` messages on it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Current protocol version, sent as the first byte of every frame
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Largest accepted payload, in bytes
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+/// Size of the frame header (version, type and length)
+const HEADER_LEN: usize = 6;
+
+const TYPE_REGISTER: u8 = 0x01;
+const TYPE_REGISTERED: u8 = 0x02;
+const TYPE_HEARTBEAT: u8 = 0x03;
+const TYPE_HEARTBEAT_ACK: u8 = 0x04;
+const TYPE_DEREGISTER: u8 = 0x05;
+const TYPE_DEREGISTERED: u8 = 0x06;
+const TYPE_QUERY: u8 = 0x07;
+const TYPE_QUERY_RESULT: u8 = 0x08;
+const TYPE_WATCH: u8 = 0x09;
+const TYPE_EVENT: u8 = 0x0A;
+const TYPE_UPDATE_METADATA: u8 = 0x0B;
+const TYPE_METADATA_UPDATED: u8 = 0x0C;
+const TYPE_ERROR: u8 = 0xFF;
+
+const CHECK_TCP: u8 = 1;
+const CHECK_HTTP: u8 = 2;
+
+/// Error codes carried by [`Message::Error`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request was malformed or unexpected
+    BadRequest,
+    /// The instance named in the request is not registered
+    UnknownInstance,
+    /// The server could not allocate resources for the request
+    Unavailable,
+    /// The server does not support the request
+    Unsupported,
+    /// A code this version of the protocol does not know
+    Other(u16),
+}
+
+impl ErrorCode {
+    fn to_u16(self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => 1,
+            ErrorCode::UnknownInstance => 2,
+            ErrorCode::Unavailable => 3,
+            ErrorCode::Unsupported => 4,
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    fn from_u16(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::BadRequest,
+            2 => ErrorCode::UnknownInstance,
+            3 => ErrorCode::Unavailable,
+            4 => ErrorCode::Unsupported,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+/// Kind of change carried by [`Message::Event`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A new instance registered
+    Registered,
+    /// An instance that was not ready became ready again
+    Recovered,
+    /// An instance sent no heartbeat for its lease TTL and stopped receiving traffic
+    Offline,
+    /// An instance deregistered or was removed by an operator
+    Deregistered,
+    /// The version, tags or metadata of an instance changed
+    MetadataChanged,
+    /// An instance failed its active health check too many times in a row and stopped receiving traffic
+    Unhealthy,
+    /// An instance missed heartbeats and may be going offline; it still receives traffic
+    Suspect,
+    /// An instance sent no heartbeat for the reap timeout and was removed
+    Expired,
+    /// An operator put an instance in draining: it receives no new traffic, open connections carry on
+    Draining,
+    /// An operator put an instance in maintenance: it receives no new traffic, open connections are not closed
+    Maintenance,
+    /// An instance went in and out of rotation too often and is kept out until it stays stable for a cooldown
+    Flapping,
+}
+
+impl EventKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            EventKind::Registered => 1,
+            EventKind::Recovered => 2,
+            EventKind::Offline => 3,
+            EventKind::Deregistered => 4,
+            EventKind::MetadataChanged => 5,
+            EventKind::Unhealthy => 6,
+            EventKind::Suspect => 7,
+            EventKind::Expired => 8,
+            EventKind::Draining => 9,
+            EventKind::Maintenance => 10,
+            EventKind::Flapping => 11,
+        }
+    }
+
+    fn from_u8(kind: u8) -> io::Result<Self> {
+        match kind {
+            1 => Ok(EventKind::Registered),
+            2 => Ok(EventKind::Recovered),
+            3 => Ok(EventKind::Offline),
+            4 => Ok(EventKind::Deregistered),
+            5 => Ok(EventKind::MetadataChanged),
+            6 => Ok(EventKind::Unhealthy),
+            7 => Ok(EventKind::Suspect),
+            8 => Ok(EventKind::Expired),
+            9 => Ok(EventKind::Draining),
+            10 => Ok(EventKind::Maintenance),
+            11 => Ok(EventKind::Flapping),
+            kind => Err(invalid_data(&format!("unknown event kind {}", kind))),
+        }
+    }
+}
+
+/// One instance of a service, as returned by a query
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceRecord {
+    pub instance_id: String,
+    /// Virtual IP and port assigned at registration
+    pub addr: SocketAddr,
+    /// Address the instance listens on
+    pub endpoint: SocketAddr,
+    /// Whether the instance receives new traffic
+    pub ready: bool,
+    /// Version of the service run by the instance
+    pub version: Option<String>,
+    pub tags: BTreeSet<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Active health check the server runs against an instance, requested at registration
+///
+/// Checks target the address the instance advertised. After `failure_threshold` failed checks in a row the
+/// instance stops receiving traffic, until a check passes again.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+/// use netsel::protocol::HealthCheck;
+///
+/// let check = HealthCheck {
+///     interval: Duration::from_secs(5),
+///     ..HealthCheck::http("/health")
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub kind: HealthCheckKind,
+    /// Time between the start of two checks
+    pub interval: Duration,
+    /// Time after which a check that has not completed fails; at most `interval`
+    pub timeout: Duration,
+    /// Number of failed checks in a row after which the instance is no longer ready
+    pub failure_threshold: u32,
+}
+
+/// What an active health check does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    /// Open a TCP connection
+    Tcp,
+    /// Send `GET path` and expect one of `expected_status`, or any 2xx status if it is empty
+    Http { path: String, expected_status: Vec<u16> },
+}
+
+impl HealthCheck {
+    /// Default time between two checks
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+    /// Default check timeout
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+    /// Default number of failed checks in a row after which the instance is no longer ready
+    pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+    /// A TCP connect check with the default interval, timeout and threshold
+    pub fn tcp() -> Self {
+        Self::with_kind(HealthCheckKind::Tcp)
+    }
+
+    /// An HTTP check of `path` expecting a 2xx status, with the default interval, timeout and threshold
+    pub fn http(path: impl Into<String>) -> Self {
+        Self::with_kind(HealthCheckKind::Http {
+            path: path.into(),
+            expected_status: Vec::new(),
+        })
+    }
+
+    fn with_kind(kind: HealthCheckKind) -> Self {
+        Self {
+            kind,
+            interval: Self::DEFAULT_INTERVAL,
+            timeout: Self::DEFAULT_TIMEOUT,
+            failure_threshold: Self::DEFAULT_FAILURE_THRESHOLD,
+        }
+    }
+}
+
+/// A message of the registration protocol
+///
+/// # Example
+///
+/// ```rust
+/// use netsel::protocol::{Message, PROTOCOL_VERSION};
+///
+/// let message = Message::Query { hostname: "orders".to_string() };
+/// let frame = message.encode()?;
+///
+/// assert_eq!(frame[0], PROTOCOL_VERSION);
+/// assert_eq!(Message::decode(frame[1], &frame[6..])?, message);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Register a new instance of `hostname`, optionally advertising the address it listens on
+    ///
+    /// A re-registering instance may ask to keep its previous instance ID, virtual IP and port; the server grants
+    /// them when they are still available. The version, tags and metadata describe the instance to other services,
+    /// and the optional health check is run by the server on top of heartbeats. `ttl` requests a lease TTL in
+    /// seconds, which the server clamps to its bounds.
+    Register {
+        hostname: String,
+        listen_host: Option<String>,
+        listen_port: Option<u16>,
+        instance_id: Option<String>,
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+        version: Option<String>,
+        tags: BTreeSet<String>,
+        metadata: BTreeMap<String, String>,
+        health_check: Option<HealthCheck>,
+        ttl: Option<u32>,
+    },
+    /// Successful answer to `Register`, with the lease TTL granted in seconds
+    ///
+    /// The instance must heartbeat well within `ttl`: it becomes suspect after half of it without a heartbeat, and
+    /// offline after all of it.
+    Registered {
+        instance_id: String,
+        ip: IpAddr,
+        port: u16,
+        ttl: u32,
+    },
+    /// Heartbeat for a registered instance
+    Heartbeat { hostname: String, instance_id: String },
+    /// Successful answer to `Heartbeat`
+    HeartbeatAck,
+    /// Remove a registered instance
+    Deregister { hostname: String, instance_id: String },
+    /// Successful answer to `Deregister`
+    Deregistered,
+    /// List the instances of `hostname`
+    Query { hostname: String },
+    /// Answer to `Query`
+    QueryResult { instances: Vec<InstanceRecord> },
+    /// Subscribe to changes of `hostname`, or of every service if it is `None`
+    ///
+    /// The server first sends a `Registered` event for every current instance, then one event per change for as
+    /// long as the connection stays open.
+    Watch { hostname: Option<String> },
+    /// A change to an instance of `hostname`, streamed after `Watch`
+    Event {
+        kind: EventKind,
+        hostname: String,
+        instance: InstanceRecord,
+    },
+    /// Replace the version, tags and metadata of a registered instance
+    UpdateMetadata {
+        hostname: String,
+        instance_id: String,
+        version: Option<String>,
+        tags: BTreeSet<String>,
+        metadata: BTreeMap<String, String>,
+    },
+    /// Successful answer to `UpdateMetadata`
+    MetadataUpdated,
+    /// Failure answer to any request
+    Error { code: ErrorCode, message: String },
+}
+
+impl Message {
+    /// Build an error message
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Message::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn message_type(&self) -> u8 {
+        match self {
+            Message::Register { .. } => TYPE_REGISTER,
+            Message::Registered { .. } => TYPE_REGISTERED,
+            Message::Heartbeat { .. } => TYPE_HEARTBEAT,
+            Message::HeartbeatAck => TYPE_HEARTBEAT_ACK,
+            Message::Deregister { .. } => TYPE_DEREGISTER,
+            Message::Deregistered => TYPE_DEREGISTERED,
+            Message::Query { .. } => TYPE_QUERY,
+            Message::QueryResult { .. } => TYPE_QUERY_RESULT,
+            Message::Watch { .. } => TYPE_WATCH,
+            Message::Event { .. } => TYPE_EVENT,
+            Message::UpdateMetadata { .. } => TYPE_UPDATE_METADATA,
+            Message::MetadataUpdated => TYPE_METADATA_UPDATED,
+            Message::Error { .. } => TYPE_ERROR,
+        }
+    }
+
+    /// Encode the message as a complete frame
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut payload = Encoder::default();
+        match self {
+            Message::Register {
+                hostname,
+                listen_host,
+                listen_port,
+                instance_id,
+                ip,
+                port,
+                version,
+                tags,
+                metadata,
+                health_check,
+                ttl,
+            } => {
+                payload.put_str(hostname)?;
+                payload.put_opt(listen_host.as_deref(), Encoder::put_str)?;
+                payload.put_opt(*listen_port, Encoder::put_port)?;
+                payload.put_opt(instance_id.as_deref(), Encoder::put_str)?;
+                payload.put_opt(*ip, |e, ip| {
+                    e.put_ip(ip);
+                    Ok(())
+                })?;
+                payload.put_opt(*port, Encoder::put_port)?;
+                payload.put_opt(version.as_deref(), Encoder::put_str)?;
+                payload.put_strs(tags)?;
+                payload.put_map(metadata)?;
+                payload.put_opt(health_check.as_ref(), Encoder::put_health_check)?;
+                payload.put_opt(*ttl, |e, ttl| {
+                    e.put_u32(ttl);
+                    Ok(())
+                })?;
+            }
+            Message::Registered { instance_id, ip, port, ttl } => {
+                payload.put_str(instance_id)?;
+                payload.put_ip(*ip);
+                payload.put_u16(*port);
+                payload.put_u32(*ttl);
+            }
+            Message::Heartbeat { hostname, instance_id }
+            | Message::Deregister { hostname, instance_id } => {
+                payload.put_str(hostname)?;
+                payload.put_str(instance_id)?;
+            }
+            Message::HeartbeatAck | Message::Deregistered | Message::MetadataUpdated => {}
+            Message::Query { hostname } => {
+                payload.put_str(hostname)?;
+            }
+            Message::QueryResult { instances } => {
+                payload.put_len(instances.len())?;
+                for instance in instances {
+                    payload.put_instance(instance)?;
+                }
+            }
+            Message::Watch { hostname } => {
+                payload.put_opt(hostname.as_deref(), Encoder::put_str)?;
+            }
+            Message::Event { kind, hostname, instance } => {
+                payload.put_u8(kind.to_u8());
+                payload.put_str(hostname)?;
+                payload.put_instance(instance)?;
+            }
+            Message::UpdateMetadata { hostname, instance_id, version, tags, metadata } => {
+                payload.put_str(hostname)?;
+                payload.put_str(instance_id)?;
+                payload.put_opt(version.as_deref(), Encoder::put_str)?;
+                payload.put_strs(tags)?;
+                payload.put_map(metadata)?;
+            }
+            Message::Error { code, message } => {
+                payload.put_u16(code.to_u16());
+                payload.put_str(message)?;
+            }
+        }
+
+        let payload = payload.0;
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(invalid_data("payload too large"));
+        }
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.push(PROTOCOL_VERSION);
+        frame.push(self.message_type());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Decode a message from its type and payload
+    pub fn decode(message_type: u8, payload: &[u8]) -> io::Result<Self> {
+        let mut d = Decoder(payload);
+        let message = match message_type {
+            TYPE_REGISTER => Message::Register {
+                hostname: d.get_str()?,
+                listen_host: d.get_opt(Decoder::get_str)?,
+                listen_port: d.get_opt(Decoder::get_u16)?,
+                instance_id: d.get_opt(Decoder::get_str)?,
+                ip: d.get_opt(Decoder::get_ip)?,
+                port: d.get_opt(Decoder::get_u16)?,
+                version: d.get_opt(Decoder::get_str)?,
+                tags: d.get_strs()?,
+                metadata: d.get_map()?,
+                health_check: d.get_opt(Decoder::get_health_check)?,
+                ttl: d.get_opt(Decoder::get_u32)?,
+            },
+            TYPE_REGISTERED => Message::Registered {
+                instance_id: d.get_str()?,
+                ip: d.get_ip()?,
+                port: d.get_u16()?,
+                ttl: d.get_u32()?,
+            },
+            TYPE_HEARTBEAT => Message::Heartbeat {
+                hostname: d.get_str()?,
+                instance_id: d.get_str()?,
+            },
+            TYPE_HEARTBEAT_ACK => Message::HeartbeatAck,
+            TYPE_DEREGISTER => Message::Deregister {
+                hostname: d.get_str()?,
+                instance_id: d.get_str()?,
+            },
+            TYPE_DEREGISTERED => Message::Deregistered,
+            TYPE_QUERY => Message::Query { hostname: d.get_str()? },
+            TYPE_QUERY_RESULT => {
+                let count = d.get_u16()? as usize;
+                let mut instances = Vec::with_capacity(count);
+                for _ in 0..count {
+                    instances.push(d.get_instance()?);
+                }
+                Message::QueryResult { instances }
+            }
+            TYPE_WATCH => Message::Watch { hostname: d.get_opt(Decoder::get_str)? },
+            TYPE_EVENT => Message::Event {
+                kind: EventKind::from_u8(d.get_u8()?)?,
+                hostname: d.get_str()?,
+                instance: d.get_instance()?,
+            },
+            TYPE_UPDATE_METADATA => Message::UpdateMetadata {
+                hostname: d.get_str()?,
+                instance_id: d.get_str()?,
+                version: d.get_opt(Decoder::get_str)?,
+                tags: d.get_strs()?,
+                metadata: d.get_map()?,
+            },
+            TYPE_METADATA_UPDATED => Message::MetadataUpdated,
+            TYPE_ERROR => Message::Error {
+                code: ErrorCode::from_u16(d.get_u16()?),
+                message: d.get_str()?,
+            },
+            other => return Err(invalid_data(&format!("unknown message type {:#04x}", other))),
+        };
+
+        if !d.0.is_empty() {
+            return Err(invalid_data("trailing bytes in payload"));
+        }
+        Ok(message)
+    }
+}
+
+/// Read one message, returning `None` if the peer closed the connection before sending anything
+///
+/// A connection closed partway through a frame, header included, is an `UnexpectedEof` error.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Message>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame header truncated")),
+            n => filled += n,
+        }
+    }
+
+    if header[0] != PROTOCOL_VERSION {
+        return Err(invalid_data(&format!("unsupported protocol version {}", header[0])));
+    }
+    let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(invalid_data("payload too large"));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Message::decode(header[1], &payload).map(Some)
+}
+
+/// Write one message as a frame
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> io::Result<()> {
+    writer.write_all(&message.encode()?).await?;
+    writer.flush().await
+}
+
+/// A duration in whole milliseconds, saturating at `u32::MAX`
+fn millis(duration: Duration) -> u32 {
+    u32::try_from(duration.as_millis()).unwrap_or(u32::MAX)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn put_u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_port(&mut self, port: u16) -> io::Result<()> {
+        self.put_u16(port);
+        Ok(())
+    }
+
+    fn put_len(&mut self, len: usize) -> io::Result<()> {
+        let len = u16::try_from(len).map_err(|_| invalid_data("field too long"))?;
+        self.put_u16(len);
+        Ok(())
+    }
+
+    fn put_str(&mut self, value: &str) -> io::Result<()> {
+        self.put_len(value.len())?;
+        self.0.extend_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    fn put_ip(&mut self, ip: IpAddr) {
+        match ip {
+            IpAddr::V4(ip) => {
+                self.put_u8(4);
+                self.0.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                self.put_u8(6);
+                self.0.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+
+    fn put_socket_addr(&mut self, addr: SocketAddr) {
+        self.put_ip(addr.ip());
+        self.put_u16(addr.port());
+    }
+
+    fn put_strs(&mut self, values: &BTreeSet<String>) -> io::Result<()> {
+        self.put_len(values.len())?;
+        for value in values {
+            self.put_str(value)?;
+        }
+        Ok(())
+    }
+
+    fn put_map(&mut self, map: &BTreeMap<String, String>) -> io::Result<()> {
+        self.put_len(map.len())?;
+        for (key, value) in map {
+            self.put_str(key)?;
+            self.put_str(value)?;
+        }
+        Ok(())
+    }
+
+    fn put_instance(&mut self, instance: &InstanceRecord) -> io::Result<()> {
+        self.put_str(&instance.instance_id)?;
+        self.put_socket_addr(instance.addr);
+        self.put_socket_addr(instance.endpoint);
+        self.put_u8(instance.ready as u8);
+        self.put_opt(instance.version.as_deref(), Encoder::put_str)?;
+        self.put_strs(&instance.tags)?;
+        self.put_map(&instance.metadata)
+    }
+
+    /// Encode a health check as its kind byte, the HTTP path and expected statuses for an HTTP check, then the
+    /// interval and timeout in milliseconds and the failure threshold
+    fn put_health_check(&mut self, check: &HealthCheck) -> io::Result<()> {
+        match &check.kind {
+            HealthCheckKind::Tcp => self.put_u8(CHECK_TCP),
+            HealthCheckKind::Http { path, expected_status } => {
+                self.put_u8(CHECK_HTTP);
+                self.put_str(path)?;
+                self.put_len(expected_status.len())?;
+                for status in expected_status {
+                    self.put_u16(*status);
+                }
+            }
+        }
+        self.put_u32(millis(check.interval));
+        self.put_u32(millis(check.timeout));
+        self.put_u32(check.failure_threshold);
+        Ok(())
+    }
+
+    fn put_opt<T>(
+        &mut self,
+        value: Option<T>,
+        put: impl FnOnce(&mut Self, T) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.put_u8(1);
+                put(self, value)
+            }
+            None => {
+                self.put_u8(0);
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("payload truncated"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn get_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn get_u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn get_str(&mut self) -> io::Result<String> {
+        let len = self.get_u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("invalid UTF-8 string"))
+    }
+
+    fn get_ip(&mut self) -> io::Result<IpAddr> {
+        match self.get_u8()? {
+            4 => {
+                let octets: [u8; 4] = self.take(4)?.try_into().unwrap();
+                Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            6 => {
+                let octets: [u8; 16] = self.take(16)?.try_into().unwrap();
+                Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            family => Err(invalid_data(&format!("unknown address family {}", family))),
+        }
+    }
+
+    fn get_socket_addr(&mut self) -> io::Result<SocketAddr> {
+        let ip = self.get_ip()?;
+        Ok(SocketAddr::new(ip, self.get_u16()?))
+    }
+
+    fn get_strs(&mut self) -> io::Result<BTreeSet<String>> {
+        let count = self.get_u16()?;
+        (0..count).map(|_| self.get_str()).collect()
+    }
+
+    fn get_map(&mut self) -> io::Result<BTreeMap<String, String>> {
+        let count = self.get_u16()?;
+        (0..count).map(|_| Ok((self.get_str()?, self.get_str()?))).collect()
+    }
+
+    fn get_instance(&mut self) -> io::Result<InstanceRecord> {
+        Ok(InstanceRecord {
+            instance_id: self.get_str()?,
+            addr: self.get_socket_addr()?,
+            endpoint: self.get_socket_addr()?,
+            ready: self.get_u8()? != 0,
+            version: self.get_opt(Decoder::get_str)?,
+            tags: self.get_strs()?,
+            metadata: self.get_map()?,
+        })
+    }
+
+    fn get_health_check(&mut self) -> io::Result<HealthCheck> {
+        let kind = match self.get_u8()? {
+            CHECK_TCP => HealthCheckKind::Tcp,
+            CHECK_HTTP => HealthCheckKind::Http {
+                path: self.get_str()?,
+                expected_status: {
+                    let count = self.get_u16()?;
+                    (0..count).map(|_| self.get_u16()).collect::<io::Result<_>>()?
+                },
+            },
+            kind => return Err(invalid_data(&format!("unknown health check kind {}", kind))),
+        };
+        Ok(HealthCheck {
+            kind,
+            interval: Duration::from_millis(self.get_u32()? as u64),
+            timeout: Duration::from_millis(self.get_u32()? as u64),
+            failure_threshold: self.get_u32()?,
+        })
+    }
+
+    fn get_opt<T>(&mut self, get: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<Option<T>> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            1 => get(self).map(Some),
+            _ => Err(invalid_data("invalid presence byte")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance() -> InstanceRecord {
+        InstanceRecord {
+            instance_id: "orders-1".to_string(),
+            addr: "10.0.0.100:8080".parse().unwrap(),
+            endpoint: "[::1]:3000".parse().unwrap(),
+            ready: true,
+            version: Some("1.2.0".to_string()),
+            tags: BTreeSet::from(["canary".to_string(), "eu".to_string()]),
+            metadata: BTreeMap::from([("zone".to_string(), "eu-west-1a".to_string())]),
+        }
+    }
+
+    /// One message of every type, with optional fields both set and unset
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Register {
+                hostname: "orders".to_string(),
+                listen_host: Some("127.0.0.1".to_string()),
+                listen_port: Some(3000),
+                instance_id: Some("orders-1".to_string()),
+                ip: Some("10.0.0.100".parse().unwrap()),
+                port: Some(8080),
+                version: Some("1.2.0".to_string()),
+                tags: BTreeSet::from(["canary".to_string()]),
+                metadata: BTreeMap::from([("zone".to_string(), "eu-west-1a".to_string())]),
+                health_check: Some(HealthCheck {
+                    kind: HealthCheckKind::Http {
+                        path: "/health".to_string(),
+                        expected_status: vec![200, 204],
+                    },
+                    interval: Duration::from_millis(1500),
+                    timeout: Duration::from_millis(500),
+                    failure_threshold: 2,
+                }),
+                ttl: Some(30),
+            },
+            Message::Register {
+                hostname: "orders".to_string(),
+                listen_host: None,
+                listen_port: None,
+                instance_id: None,
+                ip: None,
+                port: None,
+                version: None,
+                tags: BTreeSet::new(),
+                metadata: BTreeMap::new(),
+                health_check: Some(HealthCheck::tcp()),
+                ttl: None,
+            },
+            Message::Registered {
+                instance_id: "orders-1".to_string(),
+                ip: "fd00::64".parse().unwrap(),
+                port: 8080,
+                ttl: 30,
+            },
+            Message::Heartbeat {
+                hostname: "orders".to_string(),
+                instance_id: "orders-1".to_string(),
+            },
+            Message::HeartbeatAck,
+            Message::Deregister {
+                hostname: "orders".to_string(),
+                instance_id: "orders-1".to_string(),
+            },
+            Message::Deregistered,
+            Message::Query { hostname: "orders".to_string() },
+            Message::QueryResult { instances: Vec::new() },
+            Message::QueryResult {
+                instances: vec![instance(), InstanceRecord { ready: false, version: None, ..instance() }],
+            },
+            Message::Watch { hostname: None },
+            Message::Watch { hostname: Some("orders".to_string()) },
+            Message::Event {
+                kind: EventKind::Flapping,
+                hostname: "orders".to_string(),
+                instance: instance(),
+            },
+            Message::UpdateMetadata {
+                hostname: "orders".to_string(),
+                instance_id: "orders-1".to_string(),
+                version: None,
+                tags: BTreeSet::from(["eu".to_string()]),
+                metadata: BTreeMap::new(),
+            },
+            Message::MetadataUpdated,
+            Message::error(ErrorCode::UnknownInstance, "Unknown instance orders-1"),
+            Message::error(ErrorCode::Other(42), ""),
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in messages() {
+            let frame = message.encode().unwrap();
+            assert_eq!(frame[0], PROTOCOL_VERSION);
+            assert_eq!(u32::from_be_bytes(frame[2..6].try_into().unwrap()) as usize, frame.len() - HEADER_LEN);
+            assert_eq!(Message::decode(frame[1], &frame[HEADER_LEN..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn every_event_kind_round_trips() {
+        for value in 0..=u8::MAX {
+            if let Ok(kind) = EventKind::from_u8(value) {
+                assert_eq!(kind.to_u8(), value);
+            }
+        }
+        assert!(EventKind::from_u8(0xEE).is_err());
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        for message in messages() {
+            let frame = message.encode().unwrap();
+            let payload = &frame[HEADER_LEN..];
+            for len in 0..payload.len() {
+                let error = Message::decode(frame[1], &payload[..len]).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?} truncated to {} bytes", message, len);
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for message in messages() {
+            let mut frame = message.encode().unwrap();
+            frame.push(0);
+            let error = Message::decode(frame[1], &frame[HEADER_LEN..]).unwrap_err();
+            assert_eq!(error.to_string(), "trailing bytes in payload", "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn unknown_types_and_invalid_fields_are_rejected() {
+        assert!(Message::decode(0x42, &[]).is_err());
+        // Presence byte other than 0 or 1
+        assert!(Message::decode(TYPE_WATCH, &[2]).is_err());
+        // Address family other than 4 or 6
+        assert!(Message::decode(TYPE_REGISTERED, &[0, 0, 5, 127, 0, 0, 1, 0, 80, 0, 0, 0, 30]).is_err());
+        // Invalid UTF-8 hostname
+        assert!(Message::decode(TYPE_QUERY, &[0, 1, 0xFF]).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_message_checks_the_frame_header() {
+        let message = Message::Query { hostname: "orders".to_string() };
+        let frame = message.encode().unwrap();
+        assert_eq!(read_message(&mut &frame[..]).await.unwrap(), Some(message));
+        assert_eq!(read_message(&mut &[][..]).await.unwrap(), None);
+
+        let mut other_version = frame.clone();
+        other_version[0] = PROTOCOL_VERSION + 1;
+        assert!(read_message(&mut &other_version[..]).await.is_err());
+
+        let mut too_large = frame.clone();
+        too_large[2..6].copy_from_slice(&(MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes());
+        assert!(read_message(&mut &too_large[..]).await.is_err());
+
+        // A frame cut short in its payload is an error, unlike a connection closed between frames
+        assert!(read_message(&mut &frame[..frame.len() - 1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_unexpected_eof() {
+        let frame = Message::Query { hostname: "orders".to_string() }.encode().unwrap();
+        for len in 1..HEADER_LEN {
+            let error = read_message(&mut &frame[..len]).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "header truncated to {} bytes", len);
+        }
+    }
+}
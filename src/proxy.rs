@@ -1,101 +1,644 @@
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, copy, Result as IoResult};
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONNECTION, HOST, HeaderMap, HeaderName, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Result as IoResult};
 use tokio::net::{TcpListener, TcpStream};
-use crate::registry::SharedRegistry;
+use tokio::task::JoinSet;
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
+use crate::balancer::LoadBalancer;
+use crate::metrics::Metrics;
+use crate::registry::{ServiceInfo, SharedRegistry};
+use crate::shutdown::Shutdown;
 
-pub async fn start_tcp_proxy(
-    listen_addr: SocketAddr,
-    registry: Arc<SharedRegistry>
+/// Size of the buffer of each direction of a proxied TCP connection, as used by `tokio::io::copy`
+const COPY_BUFFER_LEN: usize = 8 * 1024;
+
+/// Longest service name line accepted at the start of a TCP proxy connection, newline included
+const MAX_SERVICE_NAME_LEN: usize = 256;
+
+/// Headers that only apply to a single connection, besides the `Proxy-*` ones and those listed in `Connection`
+const HOP_BY_HOP_HEADERS: [&str; 6] = ["connection", "keep-alive", "transfer-encoding", "upgrade", "te", "trailer"];
+
+/// Body type returned by the HTTP proxy, either streamed from the upstream or generated locally
+type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// Serve the TCP proxy on `listener` until `shutdown` fires
+///
+/// Each connection starts with the name of the target service and a newline; the rest of the stream is forwarded to
+/// one of its ready instances, chosen by `balancer`. Connections and bytes are counted in `metrics`. Connections already open
+/// to an instance that goes draining, maintenance or offline are left to finish. On shutdown, open connections are
+/// given the drain timeout to finish.
+pub async fn serve_tcp_proxy(
+    listener: TcpListener,
+    registry: Arc<SharedRegistry>,
+    balancer: Arc<LoadBalancer>,
+    metrics: Arc<Metrics>,
+    mut shutdown: Shutdown
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(listen_addr).await?;
-    println!("TCP proxy listening on {}", listen_addr);
+    info!(addr = %listener.local_addr()?, "TCP proxy listening");
     
+    let mut connections = JoinSet::new();
     loop {
-        match listener.accept().await {
-            Ok((inbound, peer_addr)) => {
-                println!("New proxy connection from {}", peer_addr);
-                
-                let registry_clone = registry.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_proxy_connection(inbound, registry_clone).await {
-                        eprintln!("Error handling proxy connection: {}", e);
-                    }
-                });
-            }
-            Err(e) => {
-                eprintln!("Error accepting proxy connection: {}", e);
-            }
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((inbound, peer_addr)) => {
+                    let span = info_span!("tcp_proxy", peer = %peer_addr, service = field::Empty);
+                    let registry_clone = registry.clone();
+                    let balancer_clone = balancer.clone();
+                    let metrics_clone = metrics.clone();
+                    connections.spawn(async move {
+                        let result =
+                            handle_proxy_connection(inbound, peer_addr, registry_clone, balancer_clone, metrics_clone).await;
+                        if let Err(e) = result {
+                            warn!(error = %e, "Error handling proxy connection");
+                        }
+                    }.instrument(span));
+                }
+                Err(e) => {
+                    error!(error = %e, "Error accepting proxy connection");
+                }
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = shutdown.wait() => break,
         }
     }
+    
+    drop(listener);
+    shutdown.drain(&mut connections).await;
+    info!("TCP proxy stopped");
+    Ok(())
 }
 
 async fn handle_proxy_connection(
     mut inbound: TcpStream,
-    registry: Arc<SharedRegistry>
+    peer_addr: SocketAddr,
+    registry: Arc<SharedRegistry>,
+    balancer: Arc<LoadBalancer>,
+    metrics: Arc<Metrics>
 ) -> IoResult<()> {
-    // Simple proxy protocol: first read the service name
-    let mut service_name_buf = [0u8; 256];
-    let n = inbound.read(&mut service_name_buf).await?;
-    
-    if n == 0 {
+    // Simple proxy protocol: first read the service name, up to a newline
+    let Some((service_name, early_data)) = read_service_name(&mut inbound).await? else {
         return Ok(());
-    }
-    
-    // Extract service name
-    let service_name = String::from_utf8_lossy(&service_name_buf[..n])
-        .trim()
-        .to_string();
+    };
     
-    println!("Proxy request for service: {}", service_name);
+    Span::current().record("service", service_name.as_str());
+    debug!("Proxy request");
     
     // Look up service in registry
-    let service_info = {
-        let registry_r = registry.read().await;
-        registry_r.services.get(&service_name).cloned()
-    };
-    
-    if let Some(info) = service_info {
-        if info.status == crate::registry::ServiceStatus::Ready {
-            // For testing, use localhost instead of virtual IP
-            let local_addr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), info.port);
-            println!("Forwarding to local address {}", local_addr);
+    match resolve_upstream(&registry, &balancer, &service_name, peer_addr).await {
+        Upstream::Ready(info) => {
+            let _connection = balancer.connect(&info);
+            let connection = metrics.proxy_connection("tcp", &service_name);
+            debug!(instance = %info.instance_id, endpoint = %info.endpoint, "Forwarding connection");
             
-            // Connect to the actual service B
-            match TcpStream::connect(local_addr).await {
+            // Connect to the address the service registered from
+            match TcpStream::connect(info.endpoint).await {
                 Ok(mut outbound) => {
-                    // Set up bidirectional copy between inbound and outbound
+                    // Set up bidirectional copy between inbound and outbound, counting bytes as they go through
                     let (mut ri, mut wi) = inbound.split();
                     let (mut ro, mut wo) = outbound.split();
                     
-                    let client_to_server = copy(&mut ri, &mut wo);
-                    let server_to_client = copy(&mut ro, &mut wi);
+                    // Bytes the client sent along with the service name go first
+                    wo.write_all(&early_data).await?;
+                    connection.add_bytes_in(early_data.len() as u64);
+                    
+                    let client_to_server = copy_counted(&mut ri, &mut wo, |n| connection.add_bytes_in(n));
+                    let server_to_client = copy_counted(&mut ro, &mut wi, |n| connection.add_bytes_out(n));
                     
                     tokio::try_join!(client_to_server, server_to_client)?;
-                    println!("Proxy connection closed successfully");
+                    debug!("Proxy connection closed");
                 }
                 Err(e) => {
-                    eprintln!("Failed to connect to service: {}", e);
+                    metrics.record_connect_failure("tcp", &service_name);
+                    warn!(endpoint = %info.endpoint, error = %e, "Failed to connect to service");
                 }
             }
-        } else {
-            eprintln!("Service {} is not ready", service_name);
         }
-    } else {
-        eprintln!("Service {} not found in registry", service_name);
+        Upstream::NotReady => {
+            warn!("Service is not ready");
+        }
+        Upstream::NotFound => {
+            warn!("Service not found in registry");
+        }
     }
     
     Ok(())
 }
 
-pub async fn start_http_proxy(
-    listen_addr: SocketAddr,
-    _registry: Arc<SharedRegistry>
+/// Read the newline-terminated service name that starts a TCP proxy connection
+///
+/// Returns the lowercase name and the bytes already read past the newline, or `None` if the client closed the
+/// connection before sending anything.
+async fn read_service_name(inbound: &mut TcpStream) -> IoResult<Option<(String, Vec<u8>)>> {
+    let mut buf = Vec::with_capacity(MAX_SERVICE_NAME_LEN);
+    let mut chunk = [0u8; MAX_SERVICE_NAME_LEN];
+    loop {
+        if let Some(end) = buf.iter().position(|&byte| byte == b'\n') {
+            let early_data = buf.split_off(end + 1);
+            let service_name = String::from_utf8_lossy(&buf).trim().to_ascii_lowercase();
+            return Ok(Some((service_name, early_data)));
+        }
+        if buf.len() >= MAX_SERVICE_NAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "service name too long"));
+        }
+        
+        let n = inbound.read(&mut chunk[..MAX_SERVICE_NAME_LEN - buf.len()]).await?;
+        if n == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the service name ended"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Copy `reader` to `writer` until end of file, reporting every chunk written to `count`
+///
+/// The end of file is passed on by shutting `writer` down, so the other side sees the stream end too.
+async fn copy_counted<R, W>(reader: &mut R, writer: &mut W, count: impl Fn(u64)) -> IoResult<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; COPY_BUFFER_LEN];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        count(n as u64);
+    }
+}
+
+/// Result of resolving a service name to one of its instances
+enum Upstream {
+    Ready(Box<ServiceInfo>),
+    NotReady,
+    NotFound,
+}
+
+/// Pick the instance of `service_name` that a proxied connection from `peer_addr` should go to
+async fn resolve_upstream(
+    registry: &SharedRegistry,
+    balancer: &LoadBalancer,
+    service_name: &str,
+    peer_addr: SocketAddr
+) -> Upstream {
+    let registry_r = registry.read().await;
+    let instances = registry_r.get_instances(service_name);
+    if instances.is_empty() {
+        return Upstream::NotFound;
+    }
+
+    let ready: Vec<&ServiceInfo> = instances
+        .into_iter()
+        .filter(|info| info.is_routable())
+        .collect();
+    match balancer.select(service_name, &ready, peer_addr.ip()) {
+        Some(info) => Upstream::Ready(Box::new(info.clone())),
+        None => Upstream::NotReady,
+    }
+}
+
+/// Serve the HTTP reverse proxy on `listener` until `shutdown` fires
+///
+/// Serves HTTP/1.1 and routes each request by its `Host` header: `orders.<zone>` (or plain
+/// `orders`) is forwarded to one of the ready instances of the registered `orders` service, chosen by `balancer`.
+/// Unknown services get `502 Bad Gateway`, services that are not ready get `503 Service Unavailable`. Hop-by-hop
+/// headers are removed in both directions. Requests and body bytes are counted in `metrics`. On shutdown, idle
+/// connections are closed and requests in flight are given the drain timeout to finish.
+pub async fn serve_http_proxy(
+    listener: TcpListener,
+    zone: &str,
+    registry: Arc<SharedRegistry>,
+    balancer: Arc<LoadBalancer>,
+    metrics: Arc<Metrics>,
+    mut shutdown: Shutdown
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Simplified: HTTP proxy functionality removed for compatibility reasons
-    println!("HTTP proxy listening on {}", listen_addr);
-    // Sleep indefinitely
+    info!(addr = %listener.local_addr()?, "HTTP proxy listening");
+    let zone: Arc<str> = Arc::from(zone);
+
+    let mut connections = JoinSet::new();
     loop {
-        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((inbound, peer_addr)) => {
+                    let registry_clone = registry.clone();
+                    let balancer_clone = balancer.clone();
+                    let metrics_clone = metrics.clone();
+                    let zone = zone.clone();
+                    let mut connection_shutdown = shutdown.clone();
+                    connections.spawn(async move {
+                        let service = service_fn(move |req: Request<Incoming>| {
+                            let span = info_span!(
+                                "request",
+                                method = %req.method(),
+                                uri = %req.uri(),
+                                service = field::Empty
+                            );
+                            forward_http_request(
+                                req,
+                                peer_addr,
+                                zone.clone(),
+                                registry_clone.clone(),
+                                balancer_clone.clone(),
+                                metrics_clone.clone()
+                            )
+                            .instrument(span)
+                        });
+                        let connection = http1::Builder::new().serve_connection(TokioIo::new(inbound), service);
+                        tokio::pin!(connection);
+                        let result = tokio::select! {
+                            result = connection.as_mut() => result,
+                            _ = connection_shutdown.wait() => {
+                                connection.as_mut().graceful_shutdown();
+                                connection.await
+                            }
+                        };
+                        if let Err(e) = result {
+                            warn!(error = %e, "Error handling HTTP proxy connection");
+                        }
+                    }.instrument(info_span!("http_proxy", peer = %peer_addr)));
+                }
+                Err(e) => {
+                    error!(error = %e, "Error accepting HTTP proxy connection");
+                }
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    drop(listener);
+    shutdown.drain(&mut connections).await;
+    info!("HTTP proxy stopped");
+    Ok(())
+}
+
+/// Forward a single HTTP request to the service named by its `Host` header
+async fn forward_http_request(
+    mut req: Request<Incoming>,
+    peer_addr: SocketAddr,
+    zone: Arc<str>,
+    registry: Arc<SharedRegistry>,
+    balancer: Arc<LoadBalancer>,
+    metrics: Arc<Metrics>
+) -> Result<Response<ProxyBody>, hyper::Error> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or("");
+    let Some(service_name) = service_from_host(host, &zone) else {
+        return Ok(error_response(StatusCode::BAD_REQUEST, "Missing or invalid Host header"));
+    };
+    Span::current().record("service", service_name.as_str());
+
+    let info = match resolve_upstream(&registry, &balancer, &service_name, peer_addr).await {
+        Upstream::Ready(info) => info,
+        Upstream::NotReady => {
+            warn!("Service is not ready");
+            return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, "Service not ready"));
+        }
+        Upstream::NotFound => {
+            warn!("Service not found in registry");
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "Service not found"));
+        }
+    };
+
+    let active = balancer.connect(&info);
+    let connection = Arc::new(metrics.proxy_connection("http", &service_name));
+    let upstream = info.endpoint;
+    debug!(instance = %info.instance_id, endpoint = %upstream, "Forwarding request");
+
+    let stream = match TcpStream::connect(upstream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            metrics.record_connect_failure("http", &service_name);
+            warn!(endpoint = %upstream, error = %e, "Failed to connect to service");
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "Failed to connect to service"));
+        }
+    };
+
+    let (mut sender, conn) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            metrics.record_connect_failure("http", &service_name);
+            warn!(endpoint = %upstream, error = %e, "HTTP handshake with service failed");
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "Failed to connect to service"));
+        }
+    };
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            warn!(error = %e, "Upstream HTTP connection error");
+        }
+    }.instrument(Span::current()));
+
+    strip_hop_by_hop(req.headers_mut());
+    if let Ok(value) = HeaderValue::from_str(&peer_addr.ip().to_string()) {
+        req.headers_mut().append("x-forwarded-for", value);
+    }
+
+    // Count body bytes as they stream; the connection stays active, for the metrics and the balancer, until the
+    // response body is done
+    let request_connection = connection.clone();
+    let req = req.map(move |body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                request_connection.add_bytes_in(data.len() as u64);
+            }
+            frame
+        })
+    });
+
+    match sender.send_request(req).await {
+        Ok(mut response) => {
+            strip_hop_by_hop(response.headers_mut());
+            Ok(response.map(move |body| {
+                body.map_frame(move |frame| {
+                    let _active = &active;
+                    if let Some(data) = frame.data_ref() {
+                        connection.add_bytes_out(data.len() as u64);
+                    }
+                    frame
+                })
+                .boxed()
+            }))
+        }
+        Err(e) => {
+            warn!(error = %e, "Error forwarding HTTP request");
+            Ok(error_response(StatusCode::BAD_GATEWAY, "Error forwarding request"))
+        }
+    }
+}
+
+/// Remove the headers meant for a single connection, which must not be forwarded (RFC 9110 section 7.6.1)
+///
+/// These are the headers named in `Connection`, the fixed hop-by-hop headers and every `Proxy-*` header.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+    let proxy: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect();
+    for name in proxy {
+        headers.remove(name);
+    }
+}
+
+/// Extract the service name from a `Host` header value, stripping any port and the DNS zone suffix
+fn service_from_host(host: &str, zone: &str) -> Option<String> {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let service = match host.strip_suffix(zone) {
+        Some(service) if service.ends_with('.') => service.trim_end_matches('.').to_string(),
+        _ => host,
+    };
+
+    if service.is_empty() { None } else { Some(service) }
+}
+
+/// Build a plain-text response generated by the proxy itself
+fn error_response(status: StatusCode, message: &str) -> Response<ProxyBody> {
+    let body = Full::new(Bytes::from(format!("{}\n", message)))
+        .map_err(|never| match never {})
+        .boxed();
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+
+    use crate::registry::{Registration, ServiceRegistry, ServiceStatus};
+
+    /// Serve the HTTP proxy for the `netsel` zone from `registry` on an ephemeral port
+    async fn proxy(registry: ServiceRegistry) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = registry.metrics();
+        let registry = Arc::new(SharedRegistry::new(registry));
+        // The sender is dropped, so the proxy runs until the test ends
+        let shutdown = Shutdown::new(watch::channel(false).1, Duration::from_secs(1));
+        tokio::spawn(async move {
+            serve_http_proxy(listener, "netsel", registry, Arc::new(LoadBalancer::default()), metrics, shutdown)
+                .await
+                .unwrap()
+        });
+        addr
+    }
+
+    /// Send a raw HTTP/1.1 request, which should ask to close the connection, and read the whole response
+    async fn send(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+        response
+    }
+
+    /// Lowercase names of the headers of a raw request or response
+    fn header_names(message: &str) -> Vec<String> {
+        message
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, _)| name.to_ascii_lowercase())
+            .collect()
+    }
+
+    fn register(registry: &mut ServiceRegistry, listen_port: u16) -> String {
+        let mut registration = Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]));
+        registration.listen_port = Some(listen_port);
+        registry.register(registration).unwrap().instance_id
+    }
+
+    /// Serve the TCP proxy for an `orders` instance listening on `upstream`, on an ephemeral port
+    async fn tcp_proxy(upstream: &TcpListener) -> SocketAddr {
+        let mut registry = ServiceRegistry::new();
+        register(&mut registry, upstream.local_addr().unwrap().port());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = registry.metrics();
+        let registry = Arc::new(SharedRegistry::new(registry));
+        // The sender is dropped, so the proxy runs until the test ends
+        let shutdown = Shutdown::new(watch::channel(false).1, Duration::from_secs(1));
+        tokio::spawn(async move {
+            serve_tcp_proxy(listener, registry, Arc::new(LoadBalancer::default()), metrics, shutdown).await.unwrap()
+        });
+        addr
+    }
+
+    /// Send `writes` to the TCP proxy at `addr`, pausing between them, and read what reaches `upstream`
+    async fn through_tcp_proxy(addr: SocketAddr, upstream: &TcpListener, writes: &[&[u8]]) -> Vec<u8> {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        for write in writes {
+            client.write_all(write).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        client.shutdown().await.unwrap();
+
+        let (mut inbound, _) = tokio::time::timeout(Duration::from_secs(5), upstream.accept()).await.unwrap().unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), inbound.read_to_end(&mut received)).await.unwrap().unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn tcp_service_names_match_regardless_of_case() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_proxy(&upstream).await;
+
+        assert_eq!(through_tcp_proxy(addr, &upstream, &[b"Orders\n", b"ping"]).await, b"ping");
+    }
+
+    #[tokio::test]
+    async fn data_sent_with_the_service_name_is_forwarded() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_proxy(&upstream).await;
+
+        assert_eq!(through_tcp_proxy(addr, &upstream, &[b"orders\nping"]).await, b"ping");
+        assert_eq!(through_tcp_proxy(addr, &upstream, &[b"ord", b"ers\r\npi", b"ng"]).await, b"ping");
+    }
+
+    #[tokio::test]
+    async fn a_service_name_without_newline_is_rejected() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_proxy(&upstream).await;
+
+        let long_name = vec![b'o'; MAX_SERVICE_NAME_LEN];
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&long_name).await.unwrap();
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received)).await.unwrap();
+        assert_eq!(read.unwrap(), 0);
+        assert!(tokio::time::timeout(Duration::from_millis(100), upstream.accept()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn an_upstream_closing_first_ends_the_client_stream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_proxy(&upstream).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"orders\n").await.unwrap();
+        let (mut inbound, _) = tokio::time::timeout(Duration::from_secs(5), upstream.accept()).await.unwrap().unwrap();
+        inbound.write_all(b"bye").await.unwrap();
+        drop(inbound);
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received)).await.unwrap().unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[test]
+    fn the_service_is_the_host_without_port_case_or_zone() {
+        assert_eq!(service_from_host("orders", "netsel").as_deref(), Some("orders"));
+        assert_eq!(service_from_host("Orders.NetSel:8080", "netsel").as_deref(), Some("orders"));
+        assert_eq!(service_from_host("orders.netsel.", "netsel").as_deref(), Some("orders"));
+        assert_eq!(service_from_host("api.orders.netsel", "netsel").as_deref(), Some("api.orders"));
+        // Only a whole zone label is stripped
+        assert_eq!(service_from_host("ordersnetsel", "netsel").as_deref(), Some("ordersnetsel"));
+        assert_eq!(service_from_host("orders:http", "netsel").as_deref(), Some("orders:http"));
+        assert_eq!(service_from_host("", "netsel"), None);
+        assert_eq!(service_from_host(":80", "netsel"), None);
+    }
+
+    #[tokio::test]
+    async fn an_unknown_service_is_a_bad_gateway() {
+        let addr = proxy(ServiceRegistry::new()).await;
+        let response = send(addr, "GET / HTTP/1.1\r\nHost: billing.netsel\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 502 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn a_service_without_ready_instances_is_unavailable() {
+        let mut registry = ServiceRegistry::new();
+        let id = register(&mut registry, 1);
+        registry.set_status("orders", &id, ServiceStatus::Maintenance);
+        let addr = proxy(registry).await;
+
+        let response = send(addr, "GET / HTTP/1.1\r\nHost: orders.netsel\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_headers_are_not_forwarded_either_way() {
+        // Upstream replying with hop-by-hop headers and reporting the request it got
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let (forwarded, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            forwarded.send(String::from_utf8(request).unwrap()).unwrap();
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: X-Hop\r\nX-Hop: 1\r\n\
+                Keep-Alive: timeout=5\r\nProxy-Authenticate: Basic\r\nX-Kept: 1\r\n\r\nok";
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let mut registry = ServiceRegistry::new();
+        register(&mut registry, upstream_port);
+        let addr = proxy(registry).await;
+
+        let response = send(
+            addr,
+            "GET / HTTP/1.1\r\nHost: orders.netsel\r\nConnection: close, X-Secret\r\nX-Secret: 1\r\nTE: trailers\r\n\
+                Upgrade: websocket\r\nProxy-Authorization: Basic abc\r\nX-Kept: 1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.ends_with("ok"));
+        let headers = header_names(&response);
+        assert!(headers.contains(&"x-kept".to_string()));
+        for name in ["x-hop", "keep-alive", "proxy-authenticate"] {
+            assert!(!headers.contains(&name.to_string()), "{} in {:?}", name, headers);
+        }
+
+        let headers = header_names(&received.recv().await.unwrap());
+        assert!(headers.contains(&"x-kept".to_string()));
+        assert!(headers.contains(&"x-forwarded-for".to_string()));
+        for name in ["connection", "x-secret", "te", "upgrade", "proxy-authorization"] {
+            assert!(!headers.contains(&name.to_string()), "{} in {:?}", name, headers);
+        }
     }
 }
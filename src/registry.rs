@@ -1,98 +1,831 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, broadcast};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+
+use crate::metrics::Metrics;
+use crate::network::{Subnet, VirtualNetwork};
+use crate::persistence::{LogRecord, PendingWrite, RegistryStore, Snapshot, StoredService};
+use crate::protocol::{EventKind, HealthCheck, HealthCheckKind, InstanceRecord};
+
+/// Capacity of the registry event channel; slower subscribers miss events and must resubscribe
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Longest wait for a complete request on a registration connection before it is closed
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits on the version, tags and metadata of an instance, which every query result and watch event carries
+pub const MAX_VERSION_LEN: usize = 64;
+pub const MAX_TAGS: usize = 16;
+pub const MAX_TAG_LEN: usize = 64;
+pub const MAX_METADATA_ENTRIES: usize = 16;
+pub const MAX_METADATA_KEY_LEN: usize = 64;
+pub const MAX_METADATA_VALUE_LEN: usize = 256;
 
 #[derive(Debug, Clone)]
 pub struct ServiceInfo {
     pub hostname: String,
+    pub instance_id: String,
     pub ip: IpAddr,
     pub port: u16,
     pub addr: SocketAddr,
+    /// Address the service actually listens on, as advertised at registration; the proxies and DNS point here
+    pub endpoint: SocketAddr,
     pub registered_at: Instant,
     pub last_heartbeat: Instant,
     pub status: ServiceStatus,
+    /// Version of the service run by the instance, e.g. `1.4.0`
+    pub version: Option<String>,
+    /// Free-form labels, e.g. `canary` or `stable`
+    pub tags: BTreeSet<String>,
+    /// Arbitrary key/value metadata supplied at registration
+    pub metadata: BTreeMap<String, String>,
+    /// Active health check requested at registration, run by [`crate::health`]
+    pub health_check: Option<HealthCheck>,
+    /// Number of active health checks failed in a row
+    pub check_failures: u32,
+    /// Lease TTL granted at registration: without a heartbeat the instance becomes suspect after half of it, and
+    /// offline after all of it
+    pub ttl: Duration,
+    /// When the instance went in or out of rotation on its own, within the flap window
+    pub transitions: VecDeque<Instant>,
+    /// Set while the instance is dampened for flapping: it stays out of rotation until then
+    pub dampened_until: Option<Instant>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl ServiceInfo {
+    /// Whether the active health check failed `failure_threshold` times in a row
+    pub fn check_failing(&self) -> bool {
+        self.health_check
+            .as_ref()
+            .is_some_and(|check| self.check_failures >= check.failure_threshold)
+    }
+
+    /// Whether the instance is kept out of rotation for flapping
+    pub fn is_flapping(&self) -> bool {
+        self.dampened_until.is_some()
+    }
+
+    /// Whether the instance receives new traffic: its status is routable and it is not flapping
+    pub fn is_routable(&self) -> bool {
+        self.status.is_routable() && !self.is_flapping()
+    }
+
+    /// Move the instance to `status` on its own, returning whether that starts flap dampening
+    ///
+    /// Only changes going in or out of rotation count as transitions. Every transition while dampened pushes the
+    /// end of the dampening back to a full cooldown.
+    fn transition(&mut self, status: ServiceStatus, flap: &FlapPolicy) -> bool {
+        let flipped = self.status.is_routable() != status.is_routable();
+        self.status = status;
+        if !flipped || flap.threshold == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        self.transitions.push_back(now);
+        self.prune_transitions(now, flap);
+        let starts = !self.is_flapping() && self.transitions.len() > flap.threshold as usize;
+        if starts || self.is_flapping() {
+            self.dampened_until = Some(now + flap.cooldown);
+        }
+        starts
+    }
+
+    /// Forget the transitions older than the flap window
+    fn prune_transitions(&mut self, now: Instant, flap: &FlapPolicy) {
+        while self.transitions.front().is_some_and(|at| now.duration_since(*at) > flap.window) {
+            self.transitions.pop_front();
+        }
+    }
+}
+
+/// Lifecycle status of an instance
+///
+/// Instances move between `Ready`, `Suspect`, `Offline` and `Unhealthy` on their own as heartbeats and active health
+/// checks come and go. `Draining` and `Maintenance` are set by an operator and left alone by those transitions.
+/// Only `Ready` and `Suspect` instances are returned by DNS and picked by the proxies, unless they are flapping (see
+/// [`FlapPolicy`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ServiceStatus {
     Ready,
+    /// Missed heartbeats for half of its TTL; still routed to, as it may just be a network blip
+    Suspect,
+    /// Sent no heartbeat for its TTL; kept with its address and port until the reap timeout
     Offline,
+    /// Still sending heartbeats but failing its active health check, so kept out of DNS and the proxies
+    Unhealthy,
+    /// Set by an operator: receives no new traffic while its open connections finish
+    Draining,
+    /// Set by an operator: receives no new traffic; like `Draining`, connections already open are not closed
+    Maintenance,
+}
+
+impl ServiceStatus {
+    /// Every status, in lifecycle order
+    pub const ALL: [ServiceStatus; 6] = [
+        ServiceStatus::Ready,
+        ServiceStatus::Suspect,
+        ServiceStatus::Offline,
+        ServiceStatus::Unhealthy,
+        ServiceStatus::Draining,
+        ServiceStatus::Maintenance,
+    ];
+
+    /// Whether instances in this status receive new traffic
+    pub fn is_routable(self) -> bool {
+        matches!(self, ServiceStatus::Ready | ServiceStatus::Suspect)
+    }
+
+    /// Lowercase name of the status, as shown by the admin API and metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServiceStatus::Ready => "ready",
+            ServiceStatus::Suspect => "suspect",
+            ServiceStatus::Offline => "offline",
+            ServiceStatus::Unhealthy => "unhealthy",
+            ServiceStatus::Draining => "draining",
+            ServiceStatus::Maintenance => "maintenance",
+        }
+    }
+
+    /// Event published when an instance enters this status
+    fn event(self) -> EventKind {
+        match self {
+            ServiceStatus::Ready => EventKind::Recovered,
+            ServiceStatus::Suspect => EventKind::Suspect,
+            ServiceStatus::Offline => EventKind::Offline,
+            ServiceStatus::Unhealthy => EventKind::Unhealthy,
+            ServiceStatus::Draining => EventKind::Draining,
+            ServiceStatus::Maintenance => EventKind::Maintenance,
+        }
+    }
+}
+
+impl FromStr for ServiceStatus {
+    type Err = String;
+
+    /// Parse the lowercase name of a status, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ServiceStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown status {}", s))
+    }
+}
+
+/// Parameters of a registration
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub hostname: String,
+    /// IP the instance listens on
+    pub host_ip: IpAddr,
+    /// Port the instance listens on; without it, the instance is expected to listen on its assigned port
+    pub listen_port: Option<u16>,
+    /// Instance ID to keep when re-registering, used if no live instance has it
+    pub instance_id: Option<String>,
+    /// Port to keep when re-registering, used if it is still free
+    pub port: Option<u16>,
+    /// Virtual IP to keep when re-registering, used if it is still free
+    pub ip: Option<IpAddr>,
+    pub version: Option<String>,
+    pub tags: BTreeSet<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub health_check: Option<HealthCheck>,
+    /// Requested lease TTL, clamped by the [`LeasePolicy`] of the registry
+    pub ttl: Option<Duration>,
+}
+
+impl Registration {
+    pub fn new(hostname: String, host_ip: IpAddr) -> Self {
+        Self {
+            hostname,
+            host_ip,
+            listen_port: None,
+            instance_id: None,
+            port: None,
+            ip: None,
+            version: None,
+            tags: BTreeSet::new(),
+            metadata: BTreeMap::new(),
+            health_check: None,
+            ttl: None,
+        }
+    }
+}
+
+/// Lease TTLs granted at registration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeasePolicy {
+    /// TTL of instances that do not request one
+    pub default_ttl: Duration,
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+}
+
+impl Default for LeasePolicy {
+    fn default() -> Self {
+        Self {
+            default_ttl: Duration::from_secs(60),
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(300),
+        }
+    }
+}
+
+impl LeasePolicy {
+    /// The TTL granted for a `requested` one: the default TTL if none, clamped to the bounds
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use netsel::registry::LeasePolicy;
+    ///
+    /// let policy = LeasePolicy::default();
+    /// assert_eq!(policy.grant(Some(Duration::from_secs(1))), policy.min_ttl);
+    /// assert_eq!(policy.grant(None), policy.default_ttl);
+    /// ```
+    pub fn grant(&self, requested: Option<Duration>) -> Duration {
+        requested
+            .unwrap_or(self.default_ttl)
+            .clamp(self.min_ttl, self.max_ttl.max(self.min_ttl))
+    }
+}
+
+/// Flap dampening of instances going in and out of rotation on their own
+///
+/// An instance that went in or out of rotation more than `threshold` times within `window` is kept out of rotation
+/// until it makes no such transition for `cooldown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlapPolicy {
+    /// Transitions allowed within the window; 0 disables dampening
+    pub threshold: u32,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+impl Default for FlapPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            window: Duration::from_secs(600),
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Instances registered under one service name, keyed by instance ID
+pub type ServiceInstances = BTreeMap<String, ServiceInfo>;
+
+/// A change to the registry, published to subscribers
+#[derive(Debug, Clone)]
+pub struct RegistryEvent {
+    pub kind: EventKind,
+    /// The instance after the change, or as it was when it was removed
+    pub service: ServiceInfo,
+}
+
+impl From<&ServiceInfo> for InstanceRecord {
+    fn from(service: &ServiceInfo) -> Self {
+        InstanceRecord {
+            instance_id: service.instance_id.clone(),
+            addr: service.addr,
+            endpoint: service.endpoint,
+            ready: service.is_routable(),
+            version: service.version.clone(),
+            tags: service.tags.clone(),
+            metadata: service.metadata.clone(),
+        }
+    }
 }
 
 pub struct ServiceRegistry {
-    pub services: HashMap<String, ServiceInfo>,
+    pub services: HashMap<String, ServiceInstances>,
     port_pool: PortPool,
+    network: VirtualNetwork,
+    lease: LeasePolicy,
+    flap: FlapPolicy,
+    next_instance: u64,
+    store: Option<RegistryStore>,
+    events: broadcast::Sender<RegistryEvent>,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for ServiceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ServiceRegistry {
     pub fn new() -> Self {
+        Self::with_network(9000..=9999, Subnet::default())
+    }
+
+    /// Create a registry assigning ports from `ports` and virtual addresses from `subnet`
+    pub fn with_network(ports: RangeInclusive<u16>, subnet: Subnet) -> Self {
         Self {
             services: HashMap::new(),
-            port_pool: PortPool::new(9000, 9999),
+            port_pool: PortPool::new(*ports.start(), *ports.end()),
+            network: VirtualNetwork::with_subnet(subnet),
+            lease: LeasePolicy::default(),
+            flap: FlapPolicy::default(),
+            next_instance: 1,
+            store: None,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            metrics: Arc::new(Metrics::new()),
         }
     }
 
-    pub fn register(&mut self, hostname: String, ip: IpAddr) -> Option<ServiceInfo> {
-        if self.services.contains_key(&hostname) {
-            return None;
+    /// Metrics updated by the registry, shared with the other NetSel components
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Subnet of the virtual addresses assigned to instances
+    pub fn subnet(&self) -> Subnet {
+        self.network.subnet()
+    }
+
+    /// TTLs granted to registering instances
+    pub fn lease_policy(&self) -> LeasePolicy {
+        self.lease
+    }
+
+    /// Set the TTLs granted to instances registering from now on
+    pub fn set_lease_policy(&mut self, lease: LeasePolicy) {
+        self.lease = lease;
+    }
+
+    /// Flap dampening applied to instances
+    pub fn flap_policy(&self) -> FlapPolicy {
+        self.flap
+    }
+
+    /// Set the flap dampening applied to instances
+    pub fn set_flap_policy(&mut self, flap: FlapPolicy) {
+        self.flap = flap;
+    }
+
+    /// Number of ports of the port pool in use, and size of the pool
+    pub fn port_pool_usage(&self) -> (usize, usize) {
+        (self.port_pool.used.len(), self.port_pool.size())
+    }
+
+    /// Number of virtual addresses in use, and size of the address pool
+    pub fn ip_pool_usage(&self) -> (usize, usize) {
+        (self.network.used(), self.network.size())
+    }
+
+    /// Subscribe to changes of the registry
+    ///
+    /// Events are published while the registry is locked for writing, so a subscriber that reads the current
+    /// instances under the same lock it subscribed with sees every later change exactly once.
+    pub fn subscribe(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, kind: EventKind, service: &ServiceInfo) {
+        // Having no subscriber is fine
+        let _ = self.events.send(RegistryEvent {
+            kind,
+            service: service.clone(),
+        });
+    }
+
+    /// Persist the registry in `dir`, restoring the state saved there by a previous run
+    ///
+    /// The snapshot in `dir` is loaded and the write-ahead log replayed on top of it, replacing the current
+    /// contents of the registry. From then on, every registration and deregistration is logged; call
+    /// [`ServiceRegistry::snapshot`] periodically to compact the log.
+    pub fn enable_persistence(&mut self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let (store, snapshot, records) = RegistryStore::open(dir)?;
+
+        self.services.clear();
+        self.port_pool.used = snapshot.used_ports.into_iter().collect();
+        self.network = VirtualNetwork::with_subnet(self.network.subnet());
+        self.next_instance = snapshot.next_instance.max(1);
+        for service in snapshot.services {
+            self.network.allocate_specific(service.ip);
+            self.insert_instance(service.into_info(&self.lease));
+        }
+        for record in records {
+            match record {
+                LogRecord::Register { service } => {
+                    self.port_pool.used.insert(service.port);
+                    self.network.allocate_specific(service.ip);
+                    self.insert_instance(service.into_info(&self.lease));
+                }
+                LogRecord::Unregister { hostname, instance_id } => {
+                    if let Some(service) = self.remove_instance(&hostname, &instance_id) {
+                        self.port_pool.release(service.port);
+                        self.network.release(service.ip);
+                    }
+                }
+            }
         }
 
-        if let Some(port) = self.port_pool.allocate() {
-            let addr = SocketAddr::new(ip, port);
-            let now = Instant::now();
-            let service_info = ServiceInfo {
-                hostname: hostname.clone(),
-                ip,
-                port,
-                addr,
-                registered_at: now,
-                last_heartbeat: now,
-                status: ServiceStatus::Ready,
-            };
-            self.services.insert(hostname, service_info.clone());
-            Some(service_info)
-        } else {
-            None
+        self.store = Some(store);
+        // Compact the replayed log; a failure is logged by the writer and the log kept
+        let _ = self.snapshot();
+        Ok(())
+    }
+
+    /// Queue a snapshot of the registry, which truncates the write-ahead log once written
+    ///
+    /// The disk is written by the store's writer thread; wait on the returned [`PendingWrite`], after releasing the
+    /// registry lock, to know when the snapshot is on disk. Does nothing without persistence.
+    pub fn snapshot(&mut self) -> PendingWrite {
+        let Some(store) = self.store.as_mut() else {
+            return PendingWrite::done();
+        };
+
+        let mut used_ports: Vec<u16> = self.port_pool.used.iter().copied().collect();
+        used_ports.sort_unstable();
+        let snapshot = Snapshot {
+            next_instance: self.next_instance,
+            used_ports,
+            services: self.services
+                .values()
+                .flat_map(|instances| instances.values())
+                .map(StoredService::from_info)
+                .collect(),
+        };
+        store.write_snapshot(snapshot)
+    }
+
+    /// Register a new instance, returning it with its instance ID
+    ///
+    /// The instance ID, port and virtual IP requested in `registration` are kept when they are still available;
+    /// otherwise a new ID is generated and a free port and address allocated. The requested TTL is granted within the
+    /// bounds of the [`LeasePolicy`]. Fails when the port pool or the address pool is exhausted.
+    pub fn register(&mut self, registration: Registration) -> Result<ServiceInfo, String> {
+        let Registration {
+            hostname,
+            host_ip,
+            listen_port,
+            instance_id,
+            port,
+            ip,
+            version,
+            tags,
+            metadata,
+            health_check,
+            ttl,
+        } = registration;
+
+        let port = match port {
+            Some(port) if self.port_pool.allocate_specific(port) => port,
+            _ => self.port_pool.allocate().ok_or("No port available")?,
+        };
+        let ip = match ip {
+            Some(ip) if self.network.allocate_specific(ip) => ip,
+            _ => match self.network.allocate_ip() {
+                Ok(ip) => ip,
+                Err(e) => {
+                    self.port_pool.release(port);
+                    return Err(e.to_string());
+                }
+            },
+        };
+        let instance_id = match instance_id {
+            Some(id) if !id.is_empty() && self.get_instance(&hostname, &id).is_none() => id,
+            _ => {
+                let id = format!("{}-{}", hostname, self.next_instance);
+                self.next_instance += 1;
+                id
+            }
+        };
+
+        let addr = SocketAddr::new(ip, port);
+        let now = Instant::now();
+        let service_info = ServiceInfo {
+            hostname,
+            instance_id,
+            ip,
+            port,
+            addr,
+            endpoint: SocketAddr::new(host_ip, listen_port.unwrap_or(port)),
+            registered_at: now,
+            last_heartbeat: now,
+            status: ServiceStatus::Ready,
+            version,
+            tags,
+            metadata,
+            health_check,
+            check_failures: 0,
+            ttl: self.lease.grant(ttl),
+            transitions: VecDeque::new(),
+            dampened_until: None,
+        };
+        self.insert_instance(service_info.clone());
+        self.log(LogRecord::Register { service: Box::new(StoredService::from_info(&service_info)) });
+        self.publish(EventKind::Registered, &service_info);
+        self.metrics.record_registration();
+        Ok(service_info)
+    }
+
+    pub fn unregister(&mut self, hostname: &str, instance_id: &str) -> bool {
+        self.evict(hostname, instance_id, EventKind::Deregistered)
+    }
+
+    /// Remove every instance of `hostname`, returning the removed instances
+    pub fn unregister_service(&mut self, hostname: &str) -> Vec<ServiceInfo> {
+        let instances: Vec<ServiceInfo> = self.get_instances(hostname).into_iter().cloned().collect();
+        for service in &instances {
+            self.evict(hostname, &service.instance_id, EventKind::Deregistered);
         }
+        instances
     }
 
-    pub fn unregister(&mut self, hostname: &str) -> bool {
-        if let Some(service) = self.services.remove(hostname) {
-            self.port_pool.release(service.port);
-            true
-        } else {
-            false
+    /// Replace the version, tags and metadata of an instance, returning whether it exists
+    pub fn update_metadata(
+        &mut self,
+        hostname: &str,
+        instance_id: &str,
+        version: Option<String>,
+        tags: BTreeSet<String>,
+        metadata: BTreeMap<String, String>,
+    ) -> bool {
+        let service = self.services
+            .get_mut(hostname)
+            .and_then(|instances| instances.get_mut(instance_id));
+        let Some(service) = service else {
+            return false;
+        };
+        if service.version == version && service.tags == tags && service.metadata == metadata {
+            return true;
+        }
+
+        service.version = version;
+        service.tags = tags;
+        service.metadata = metadata;
+        let service = service.clone();
+        self.log(LogRecord::Register { service: Box::new(StoredService::from_info(&service)) });
+        self.publish(EventKind::MetadataChanged, &service);
+        true
+    }
+
+    /// Remove an instance and release its port and address, publishing `kind`
+    fn evict(&mut self, hostname: &str, instance_id: &str, kind: EventKind) -> bool {
+        let Some(service) = self.remove_instance(hostname, instance_id) else {
+            return false;
+        };
+
+        self.port_pool.release(service.port);
+        self.network.release(service.ip);
+        self.log(LogRecord::Unregister {
+            hostname: service.hostname.clone(),
+            instance_id: service.instance_id.clone(),
+        });
+        self.publish(kind, &service);
+        match kind {
+            EventKind::Expired => self.metrics.record_eviction(),
+            _ => self.metrics.record_deregistration(),
+        }
+        if !self.services.contains_key(hostname) {
+            self.metrics.forget_service(hostname);
         }
+        true
+    }
+
+    /// Report an instance that just started flap dampening
+    fn dampen(&self, service: &ServiceInfo) {
+        warn!(
+            hostname = %service.hostname,
+            instance_id = %service.instance_id,
+            transitions = service.transitions.len(),
+            cooldown_secs = self.flap.cooldown.as_secs(),
+            "Instance is flapping, kept out of rotation"
+        );
+        self.publish(EventKind::Flapping, service);
+        self.metrics.record_flap();
     }
 
-    pub fn get_service(&self, hostname: &str) -> Option<&ServiceInfo> {
-        self.services.get(hostname)
+    fn insert_instance(&mut self, service: ServiceInfo) {
+        // Keep generated IDs unique across restarts
+        let number = service.instance_id
+            .strip_prefix(service.hostname.as_str())
+            .and_then(|suffix| suffix.strip_prefix('-'))
+            .and_then(|n| n.parse::<u64>().ok());
+        if let Some(number) = number {
+            self.next_instance = self.next_instance.max(number + 1);
+        }
+
+        self.services
+            .entry(service.hostname.clone())
+            .or_default()
+            .insert(service.instance_id.clone(), service);
     }
 
-    pub fn update_heartbeat(&mut self, hostname: &str) -> bool {
-        if let Some(service) = self.services.get_mut(hostname) {
+    fn remove_instance(&mut self, hostname: &str, instance_id: &str) -> Option<ServiceInfo> {
+        let instances = self.services.get_mut(hostname)?;
+        let service = instances.remove(instance_id)?;
+        if instances.is_empty() {
+            self.services.remove(hostname);
+        }
+        Some(service)
+    }
+
+    /// Append a record to the write-ahead log, if persistence is enabled
+    fn log(&mut self, record: LogRecord) {
+        if let Some(store) = self.store.as_mut()
+            && let Err(e) = store.append(record)
+        {
+            error!(error = %e, "Error writing registry log");
+        }
+    }
+
+    /// All instances registered under `hostname`, whatever their status
+    pub fn get_instances(&self, hostname: &str) -> Vec<&ServiceInfo> {
+        self.services
+            .get(hostname)
+            .map(|instances| instances.values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Instances of `hostname` that receive new traffic, see [`ServiceInfo::is_routable`]
+    pub fn healthy_instances(&self, hostname: &str) -> Vec<&ServiceInfo> {
+        self.get_instances(hostname)
+            .into_iter()
+            .filter(|service| service.is_routable())
+            .collect()
+    }
+
+    pub fn get_instance(&self, hostname: &str, instance_id: &str) -> Option<&ServiceInfo> {
+        self.services.get(hostname)?.get(instance_id)
+    }
+
+    pub fn update_heartbeat(&mut self, hostname: &str, instance_id: &str) -> bool {
+        let service = self.services
+            .get_mut(hostname)
+            .and_then(|instances| instances.get_mut(instance_id));
+        if let Some(service) = service {
+            self.metrics.record_heartbeat();
             service.last_heartbeat = Instant::now();
-            service.status = ServiceStatus::Ready;
+            // An unhealthy instance only recovers by passing its active health check, and operator statuses stay
+            if matches!(service.status, ServiceStatus::Suspect | ServiceStatus::Offline) {
+                let status = if service.check_failing() { ServiceStatus::Unhealthy } else { ServiceStatus::Ready };
+                let flapping = service.transition(status, &self.flap);
+                let service = service.clone();
+                info!(%hostname, %instance_id, status = service.status.as_str(), "Instance heartbeating again");
+                self.publish(service.status.event(), &service);
+                if flapping {
+                    self.dampen(&service);
+                }
+            }
             true
         } else {
             false
         }
     }
 
-    pub fn cleanup_offline(&mut self, timeout: Duration) {
-        let now = Instant::now();
-        let to_remove: Vec<String> = self.services
-            .iter()
-            .filter(|(_, service)| {
-                now.duration_since(service.last_heartbeat) > timeout
+    /// Record the result of an active health check of an instance, returning its new status if the check changed it
+    ///
+    /// A routable instance becomes `Unhealthy` once its check failed `failure_threshold` times in a row, and an
+    /// unhealthy instance becomes `Ready` again as soon as a check passes.
+    pub fn record_check(&mut self, hostname: &str, instance_id: &str, passed: bool) -> Option<ServiceStatus> {
+        let service = self.services
+            .get_mut(hostname)
+            .and_then(|instances| instances.get_mut(instance_id))?;
+        self.metrics.record_active_check(passed);
+
+        if passed {
+            service.check_failures = 0;
+            if service.status != ServiceStatus::Unhealthy {
+                return None;
+            }
+        } else {
+            service.check_failures = service.check_failures.saturating_add(1);
+            if !service.status.is_routable() || !service.check_failing() {
+                return None;
+            }
+        }
+        let status = if passed { ServiceStatus::Ready } else { ServiceStatus::Unhealthy };
+        let flapping = service.transition(status, &self.flap);
+        let service = service.clone();
+        self.publish(status.event(), &service);
+        if flapping {
+            self.dampen(&service);
+        }
+        Some(status)
+    }
+
+    /// Set the status of an instance on behalf of an operator, returning the updated instance
+    ///
+    /// `Draining` and `Maintenance` take the instance out of rotation until an operator sets `Ready` again, which
+    /// hands the instance back to the automatic transitions; the next heartbeat check demotes it if its heartbeats
+    /// stopped meanwhile. Setting `Ready` also lifts flap dampening.
+    pub fn set_status(&mut self, hostname: &str, instance_id: &str, status: ServiceStatus) -> Option<ServiceInfo> {
+        let service = self.services
+            .get_mut(hostname)
+            .and_then(|instances| instances.get_mut(instance_id))?;
+        let status = match status {
+            ServiceStatus::Ready if service.check_failing() => ServiceStatus::Unhealthy,
+            status => status,
+        };
+        let undampen = status == ServiceStatus::Ready && service.is_flapping();
+        if service.status == status && !undampen {
+            return Some(service.clone());
+        }
+
+        service.status = status;
+        if undampen {
+            service.transitions.clear();
+            service.dampened_until = None;
+        }
+        let service = service.clone();
+        self.log(LogRecord::Register { service: Box::new(StoredService::from_info(&service)) });
+        self.publish(status.event(), &service);
+        Some(service)
+    }
+
+    /// Earliest time at which [`ServiceRegistry::check_heartbeats`] may change an instance, if any
+    ///
+    /// That is when a ready instance reaches half of its TTL, a ready, suspect or unhealthy one its whole TTL, any
+    /// instance the reap timeout, or a flapping one the end of its cooldown.
+    pub fn next_deadline(&self, reap_timeout: Duration) -> Option<Instant> {
+        self.services
+            .values()
+            .flat_map(|instances| instances.values())
+            .flat_map(|service| {
+                let last = service.last_heartbeat;
+                let status = match service.status {
+                    ServiceStatus::Ready => Some(last + service.ttl / 2),
+                    ServiceStatus::Suspect | ServiceStatus::Unhealthy => Some(last + service.ttl),
+                    _ => None,
+                };
+                [status, Some(last + reap_timeout), service.dampened_until]
             })
-            .map(|(hostname, _)| hostname.clone())
-            .collect();
+            .flatten()
+            .min()
+    }
+
+    /// Apply the heartbeat transitions to every instance
+    ///
+    /// Without a heartbeat for half of its TTL a ready instance becomes `Suspect`, and after its whole TTL it goes
+    /// `Offline`, keeping its address and port. Instances without a heartbeat for `reap_timeout` are removed,
+    /// whatever their status. Operator statuses are only subject to the removal. Flapping instances that stayed stable
+    /// for the cooldown go back into rotation.
+    pub fn check_heartbeats(&mut self, reap_timeout: Duration) {
+        let now = Instant::now();
+        let mut changed = Vec::new();
+        let mut dampened = Vec::new();
+        let mut settled = Vec::new();
+        let mut expired = Vec::new();
+        for service in self.services.values_mut().flat_map(|instances| instances.values_mut()) {
+            service.prune_transitions(now, &self.flap);
+            if service.dampened_until.is_some_and(|until| until <= now) {
+                service.dampened_until = None;
+                service.transitions.clear();
+                settled.push(service.clone());
+            }
+
+            let age = now.duration_since(service.last_heartbeat);
+            let status = match service.status {
+                _ if age > reap_timeout => {
+                    expired.push((service.hostname.clone(), service.instance_id.clone()));
+                    continue;
+                }
+                ServiceStatus::Ready | ServiceStatus::Suspect | ServiceStatus::Unhealthy if age > service.ttl => {
+                    ServiceStatus::Offline
+                }
+                ServiceStatus::Ready if age > service.ttl / 2 => ServiceStatus::Suspect,
+                _ => continue,
+            };
+            if service.transition(status, &self.flap) {
+                dampened.push(service.clone());
+            }
+            changed.push(service.clone());
+        }
 
-        for hostname in to_remove {
-            self.unregister(&hostname);
+        for service in settled {
+            info!(hostname = %service.hostname, instance_id = %service.instance_id, "Instance stable again after flapping");
+            if service.is_routable() {
+                self.publish(EventKind::Recovered, &service);
+            }
+        }
+        for service in changed {
+            info!(
+                hostname = %service.hostname,
+                instance_id = %service.instance_id,
+                status = service.status.as_str(),
+                "Instance missed heartbeats"
+            );
+            self.publish(service.status.event(), &service);
+        }
+        for service in dampened {
+            self.dampen(&service);
+        }
+        for (hostname, instance_id) in expired {
+            info!(%hostname, %instance_id, "Removing instance after the reap timeout");
+            self.evict(&hostname, &instance_id, EventKind::Expired);
         }
     }
 }
@@ -122,6 +855,19 @@ impl PortPool {
         None
     }
 
+    /// Allocate `port` itself, if it is in range and free
+    pub fn allocate_specific(&mut self, port: u16) -> bool {
+        if port < self.start || port > self.end {
+            return false;
+        }
+        self.used.insert(port)
+    }
+
+    /// Number of ports in the pool
+    pub fn size(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+
     pub fn release(&mut self, port: u16) {
         self.used.remove(&port);
     }
@@ -129,43 +875,527 @@ impl PortPool {
 
 pub type SharedRegistry = RwLock<ServiceRegistry>;
 
-use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinSet;
 
-/// Start the registration server
-pub async fn start_registration_server(
-    addr: SocketAddr,
-    registry: Arc<SharedRegistry>
+use crate::protocol::{self, ErrorCode, Message, PROTOCOL_VERSION};
+use crate::shutdown::Shutdown;
+
+/// Serve the registration server on `listener` until `shutdown` fires
+///
+/// Services talk to the server with the framed protocol from [`crate::protocol`]. When `legacy_protocol` is set,
+/// the old text messages (`hostname[|host|port]`, `HEARTBEAT|hostname[|instance_id]` and
+/// `DEREGISTER|hostname[|instance_id]`) are accepted as well. A connection that does not deliver a complete
+/// request within 30 seconds is closed. Open connections, such as watch subscriptions, are closed on shutdown.
+pub async fn serve_registration(
+    listener: TcpListener,
+    registry: Arc<SharedRegistry>,
+    legacy_protocol: bool,
+    mut shutdown: Shutdown
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(addr).await?;
-    println!("Registration server listening on {}", addr);
+    info!(addr = %listener.local_addr()?, "Registration server listening");
     
+    let mut connections = JoinSet::new();
     loop {
-        match listener.accept().await {
-            Ok((stream, peer_addr)) => {
-                println!("New registration from {}", peer_addr);
-                
-                let registry_clone = registry.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_registration(stream, registry_clone).await {
-                        eprintln!("Error handling registration: {}", e);
-                    }
-                });
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer_addr)) => {
+                    let registry_clone = registry.clone();
+                    connections.spawn(async move {
+                        debug!("New registration connection");
+                        if let Err(e) = handle_registration(stream, peer_addr, registry_clone, legacy_protocol).await {
+                            warn!(error = %e, "Error handling registration");
+                        }
+                    }.instrument(info_span!("registration", peer = %peer_addr)));
+                }
+                Err(e) => {
+                    error!(error = %e, "Error accepting registration connection");
+                }
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+    
+    connections.shutdown().await;
+    info!("Registration server stopped");
+    Ok(())
+}
+
+/// Handle a connection to the registration server
+async fn handle_registration(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    registry: Arc<SharedRegistry>,
+    legacy_protocol: bool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut version = [0u8; 1];
+    let Ok(peeked) = tokio::time::timeout(FRAME_TIMEOUT, stream.peek(&mut version)).await else {
+        debug!("No request within the frame timeout, closing");
+        return Ok(());
+    };
+    if peeked? == 0 {
+        return Ok(());
+    }
+    
+    if version[0] != PROTOCOL_VERSION {
+        if legacy_protocol {
+            return handle_legacy_registration(stream, peer_addr, registry).await;
+        }
+        let response = Message::error(ErrorCode::BadRequest, "Unsupported protocol version");
+        send_response(&mut stream, &response).await?;
+        return Ok(());
+    }
+    
+    loop {
+        // Only the wait for a request is bounded; a watch subscription is idle by design
+        let Ok(read) = tokio::time::timeout(FRAME_TIMEOUT, protocol::read_message(&mut stream)).await else {
+            debug!("No complete request within the frame timeout, closing");
+            return Ok(());
+        };
+        let message = match read {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                let response = Message::error(ErrorCode::BadRequest, e.to_string());
+                send_response(&mut stream, &response).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        
+        if let Message::Watch { hostname } = message {
+            return watch(stream, hostname.map(|hostname| hostname.to_ascii_lowercase()), &registry).await;
+        }
+        let response = handle_message(message, peer_addr, &registry).await;
+        send_response(&mut stream, &response).await?;
+    }
+}
+
+/// Write a response, replacing it with an `Unavailable` error if it cannot be encoded, e.g. a query result with too
+/// many instances to fit in a frame
+async fn send_response<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> std::io::Result<()> {
+    let frame = match message.encode() {
+        Ok(frame) => frame,
+        Err(e) => {
+            warn!(error = %e, "Cannot encode response");
+            Message::error(ErrorCode::Unavailable, format!("Cannot send response: {}", e)).encode()?
+        }
+    };
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Stream registry events for `hostname` (or every service) until the subscriber disconnects
+///
+/// The current instances are sent first as `Registered` events. A subscriber that falls too far behind gets an
+/// `Unavailable` error and is disconnected, so it can resubscribe and rebuild its view.
+async fn watch(
+    mut stream: TcpStream,
+    hostname: Option<String>,
+    registry: &SharedRegistry
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(hostname = hostname.as_deref().unwrap_or("*"), "Watch started");
+    
+    let wanted = |service: &ServiceInfo| hostname.as_deref().is_none_or(|hostname| service.hostname == hostname);
+    let (mut events, current) = {
+        let registry_r = registry.read().await;
+        let current: Vec<Message> = registry_r.services
+            .values()
+            .flat_map(|instances| instances.values())
+            .filter(|service| wanted(service))
+            .map(|service| event_message(EventKind::Registered, service))
+            .collect();
+        (registry_r.subscribe(), current)
+    };
+    for message in current {
+        send_response(&mut stream, &message).await?;
+    }
+    
+    let (mut reader, mut writer) = stream.split();
+    let mut buf = [0u8; 1];
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if wanted(&event.service) => {
+                    send_response(&mut writer, &event_message(event.kind, &event.service)).await?;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    let response = Message::error(ErrorCode::Unavailable, format!("Watch lagged, {} events missed", missed));
+                    send_response(&mut writer, &response).await?;
+                    return Ok(());
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            // The subscriber sends nothing after `Watch`, so a completed read means it disconnected
+            _ = reader.read(&mut buf) => return Ok(()),
+        }
+    }
+}
+
+fn event_message(kind: EventKind, service: &ServiceInfo) -> Message {
+    Message::Event {
+        kind,
+        hostname: service.hostname.clone(),
+        instance: InstanceRecord::from(service),
+    }
+}
+
+/// Handle one request of the framed protocol and build its response
+///
+/// Hostnames and instance IDs are stored lowercase, so they are matched ignoring case.
+async fn handle_message(message: Message, peer_addr: SocketAddr, registry: &SharedRegistry) -> Message {
+    match message.normalize_names() {
+        Message::Register {
+            hostname,
+            listen_host,
+            listen_port,
+            instance_id,
+            ip,
+            port,
+            version,
+            tags,
+            metadata,
+            health_check,
+            ttl,
+        } => {
+            let request = RegisterRequest {
+                hostname,
+                listen_host,
+                listen_port,
+                instance_id,
+                ip,
+                port,
+                version,
+                tags,
+                metadata,
+                health_check,
+                ttl,
+            };
+            register_instance(registry, peer_addr, request).await
+        }
+        Message::Heartbeat { hostname, instance_id } => {
+            debug!(%hostname, %instance_id, "Heartbeat");
+            
+            let success = {
+                let mut registry_w = registry.write().await;
+                registry_w.update_heartbeat(&hostname, &instance_id)
+            };
+            
+            if success {
+                Message::HeartbeatAck
+            } else {
+                Message::error(ErrorCode::UnknownInstance, format!("Unknown instance {} of {}", instance_id, hostname))
+            }
+        }
+        Message::Query { hostname } => {
+            let registry_r = registry.read().await;
+            let instances = registry_r
+                .get_instances(&hostname)
+                .into_iter()
+                .map(InstanceRecord::from)
+                .collect();
+            Message::QueryResult { instances }
+        }
+        Message::UpdateMetadata { hostname, instance_id, version, tags, metadata } => {
+            if let Err(e) = validate_metadata(version.as_deref(), &tags, &metadata) {
+                return Message::error(ErrorCode::BadRequest, e);
+            }
+            let success = {
+                let mut registry_w = registry.write().await;
+                registry_w.update_metadata(&hostname, &instance_id, version, tags, metadata)
+            };
+            
+            if success {
+                Message::MetadataUpdated
+            } else {
+                Message::error(ErrorCode::UnknownInstance, format!("Unknown instance {} of {}", instance_id, hostname))
+            }
+        }
+        Message::Deregister { hostname, instance_id } => {
+            info!(%hostname, %instance_id, "Deregistering");
+            
+            let success = {
+                let mut registry_w = registry.write().await;
+                registry_w.unregister(&hostname, &instance_id)
+            };
+            
+            if success {
+                Message::Deregistered
+            } else {
+                Message::error(ErrorCode::UnknownInstance, format!("Unknown instance {} of {}", instance_id, hostname))
             }
-            Err(e) => {
-                eprintln!("Error accepting registration connection: {}", e);
+        }
+        _ => Message::error(ErrorCode::BadRequest, "Unexpected message"),
+    }
+}
+
+impl Message {
+    /// Lowercase the hostname and instance ID of a request other than a registration, which validates them instead
+    fn normalize_names(self) -> Message {
+        let lowercase = |name: String| name.to_ascii_lowercase();
+        match self {
+            Message::Heartbeat { hostname, instance_id } => Message::Heartbeat {
+                hostname: lowercase(hostname),
+                instance_id: lowercase(instance_id),
+            },
+            Message::Query { hostname } => Message::Query { hostname: lowercase(hostname) },
+            Message::UpdateMetadata { hostname, instance_id, version, tags, metadata } => Message::UpdateMetadata {
+                hostname: lowercase(hostname),
+                instance_id: lowercase(instance_id),
+                version,
+                tags,
+                metadata,
+            },
+            Message::Deregister { hostname, instance_id } => Message::Deregister {
+                hostname: lowercase(hostname),
+                instance_id: lowercase(instance_id),
+            },
+            message => message,
+        }
+    }
+}
+
+/// A registration request, in either protocol
+///
+/// A re-registering service may ask to keep its instance ID, virtual IP and port.
+#[derive(Default)]
+struct RegisterRequest {
+    hostname: String,
+    listen_host: Option<String>,
+    listen_port: Option<u16>,
+    instance_id: Option<String>,
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    version: Option<String>,
+    tags: BTreeSet<String>,
+    metadata: BTreeMap<String, String>,
+    health_check: Option<HealthCheck>,
+    /// Requested lease TTL, in seconds
+    ttl: Option<u32>,
+}
+
+/// Register a new instance, answering with `Registered` or `Error`
+///
+/// Without an advertised host, the IP the registration came from is recorded, and without an advertised port, the
+/// service is expected to listen on its assigned port.
+async fn register_instance(
+    registry: &SharedRegistry,
+    peer_addr: SocketAddr,
+    request: RegisterRequest
+) -> Message {
+    if request.hostname.is_empty() {
+        return Message::error(ErrorCode::BadRequest, "Missing hostname");
+    }
+    let hostname = match dns_label(&request.hostname) {
+        Ok(hostname) => hostname,
+        Err(e) => return Message::error(ErrorCode::BadRequest, format!("Invalid hostname: {}", e)),
+    };
+    let instance_id = match request.instance_id.as_deref().filter(|id| !id.is_empty()).map(dns_label) {
+        Some(Ok(instance_id)) => Some(instance_id),
+        Some(Err(e)) => return Message::error(ErrorCode::BadRequest, format!("Invalid instance ID: {}", e)),
+        None => None,
+    };
+    if let Err(e) = validate_metadata(request.version.as_deref(), &request.tags, &request.metadata) {
+        return Message::error(ErrorCode::BadRequest, e);
+    }
+    if let Some(check) = &request.health_check
+        && let Err(e) = validate_health_check(check)
+    {
+        return Message::error(ErrorCode::BadRequest, format!("Invalid health check: {}", e));
+    }
+    debug!(%hostname, "Registering service");
+    
+    let host_ip = match request.listen_host.as_deref() {
+        Some(host) => match resolve_host(host).await {
+            Some(ip) => ip,
+            None => {
+                return Message::error(ErrorCode::BadRequest, format!("Cannot resolve advertised host: {}", host));
             }
+        },
+        None => peer_addr.ip(),
+    };
+    
+    let registration = Registration {
+        listen_port: request.listen_port,
+        instance_id,
+        port: request.port,
+        ip: request.ip,
+        version: request.version,
+        tags: request.tags,
+        metadata: request.metadata,
+        health_check: request.health_check,
+        ttl: request.ttl.map(|ttl| Duration::from_secs(ttl.into())),
+        ..Registration::new(hostname.clone(), host_ip)
+    };
+    let registration_result = {
+        let mut registry_w = registry.write().await;
+        registry_w.register(registration)
+    };
+    
+    match registration_result {
+        Ok(service_info) => {
+            info!(
+                hostname = %service_info.hostname,
+                instance_id = %service_info.instance_id,
+                addr = %service_info.addr,
+                endpoint = %service_info.endpoint,
+                ttl_secs = service_info.ttl.as_secs(),
+                "Service registered"
+            );
+            Message::Registered {
+                instance_id: service_info.instance_id,
+                ip: service_info.ip,
+                port: service_info.port,
+                ttl: u32::try_from(service_info.ttl.as_secs()).unwrap_or(u32::MAX),
+            }
+        }
+        Err(e) => {
+            warn!(%hostname, error = %e, "Failed to register service");
+            Message::error(ErrorCode::Unavailable, e)
         }
     }
 }
 
-/// Handle registration requests
-async fn handle_registration(
-    mut stream: tokio::net::TcpStream,
+/// Lowercase `name`, checking that it is a valid DNS label
+///
+/// Hostnames and instance IDs are published in DNS, so they are limited to 63 bytes of `[a-z0-9-]`.
+fn dns_label(name: &str) -> Result<String, String> {
+    if name.len() > 63 {
+        return Err(format!("{} is longer than 63 bytes", name));
+    }
+    let name = name.to_ascii_lowercase();
+    match name.chars().find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '-')) {
+        Some(c) => Err(format!("{} contains {:?}, expected letters, digits and -", name, c)),
+        None => Ok(name),
+    }
+}
+
+/// Check the version, tags and metadata of an instance against their limits
+///
+/// Tags and metadata keys must be non-empty printable ASCII without spaces, `,` or `=`, as DNS TXT records list them
+/// as `tags=<tag>,<tag>` and `key=value`; the version and metadata values must not hold control characters.
+fn validate_metadata(
+    version: Option<&str>,
+    tags: &BTreeSet<String>,
+    metadata: &BTreeMap<String, String>
+) -> Result<(), String> {
+    if let Some(version) = version {
+        if version.len() > MAX_VERSION_LEN {
+            return Err(format!("Version is longer than {} bytes", MAX_VERSION_LEN));
+        }
+        if version.chars().any(char::is_control) {
+            return Err("Version contains control characters".to_string());
+        }
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("Too many tags, at most {} are allowed", MAX_TAGS));
+    }
+    if let Some(tag) = tags.iter().find(|tag| tag.len() > MAX_TAG_LEN) {
+        return Err(format!("Tag {}... is longer than {} bytes", truncated(tag), MAX_TAG_LEN));
+    }
+    for tag in tags {
+        check_token("Tag", tag)?;
+    }
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(format!("Too many metadata entries, at most {} are allowed", MAX_METADATA_ENTRIES));
+    }
+    for (key, value) in metadata {
+        if key.len() > MAX_METADATA_KEY_LEN {
+            return Err(format!("Metadata key {}... is longer than {} bytes", truncated(key), MAX_METADATA_KEY_LEN));
+        }
+        check_token("Metadata key", key)?;
+        if value.len() > MAX_METADATA_VALUE_LEN {
+            return Err(format!("Metadata value of {} is longer than {} bytes", key, MAX_METADATA_VALUE_LEN));
+        }
+        if value.chars().any(char::is_control) {
+            return Err(format!("Metadata value of {} contains control characters", key));
+        }
+    }
+    Ok(())
+}
+
+/// Check that a tag or metadata key is non-empty printable ASCII without spaces, `,` or `=`
+fn check_token(kind: &str, token: &str) -> Result<(), String> {
+    if token.is_empty() {
+        return Err(format!("{} must not be empty", kind));
+    }
+    match token.chars().find(|c| !c.is_ascii_graphic() || matches!(c, ',' | '=')) {
+        Some(c) => Err(format!("{} {} contains {:?}, expected printable ASCII without , and =", kind, token, c)),
+        None => Ok(()),
+    }
+}
+
+/// The first characters of an overlong name, to quote it in an error
+fn truncated(name: &str) -> String {
+    name.chars().take(16).collect()
+}
+
+/// Check the settings of a requested health check
+fn validate_health_check(check: &HealthCheck) -> Result<(), String> {
+    if check.interval.is_zero() || check.timeout.is_zero() {
+        return Err("interval and timeout must be greater than zero".to_string());
+    }
+    if check.timeout > check.interval {
+        return Err("timeout must not exceed the interval".to_string());
+    }
+    if check.failure_threshold == 0 {
+        return Err("failure threshold must be greater than zero".to_string());
+    }
+    if let HealthCheckKind::Http { path, expected_status } = &check.kind {
+        if !path.starts_with('/') {
+            return Err(format!("HTTP path {} must start with /", path));
+        }
+        if let Some(status) = expected_status.iter().find(|status| !(100..=599).contains(*status)) {
+            return Err(format!("invalid HTTP status {}", status));
+        }
+    }
+    Ok(())
+}
+
+/// Instance ID of a legacy heartbeat or deregistration
+///
+/// Old clients send only the hostname. The instance is then the one of `hostname` registered from the IP of
+/// `peer_addr`, or else the only instance of `hostname`; when neither is unique the ID stays empty and the request
+/// fails.
+async fn legacy_instance_id(
+    registry: &SharedRegistry,
+    hostname: &str,
+    instance_id: Option<&str>,
+    peer_addr: SocketAddr
+) -> String {
+    if let Some(instance_id) = instance_id.map(str::trim).filter(|id| !id.is_empty()) {
+        return instance_id.to_string();
+    }
+
+    let registry_r = registry.read().await;
+    let instances = registry_r.get_instances(&hostname.to_ascii_lowercase());
+    let mut from_peer = instances.iter().filter(|service| service.endpoint.ip() == peer_addr.ip());
+    match (from_peer.next(), from_peer.next(), instances.as_slice()) {
+        (Some(service), None, _) | (None, None, [service]) => service.instance_id.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Handle a request in the legacy text format
+///
+/// A registration message is `hostname`, optionally followed by `|host|port` to advertise the address the service
+/// listens on; either part may be left empty. A heartbeat is `HEARTBEAT|hostname[|instance_id]` and a
+/// deregistration `DEREGISTER|hostname[|instance_id]`; without an instance ID, as sent by old clients, the instance
+/// is found with [`legacy_instance_id`].
+async fn handle_legacy_registration(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
     registry: Arc<SharedRegistry>
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).await?;
+    let Ok(read) = tokio::time::timeout(FRAME_TIMEOUT, stream.read(&mut buf)).await else {
+        debug!("No legacy request within the frame timeout, closing");
+        return Ok(());
+    };
+    let n = read?;
     
     if n == 0 {
         return Ok(());
@@ -175,55 +1405,694 @@ async fn handle_registration(
         .trim_end_matches(char::from(0))
         .to_string();
     
-    println!("Received message: {}", message);
+    debug!(%message, "Received legacy message");
     
     if message.starts_with("HEARTBEAT|") {
-        // Handle heartbeat - properly parse hostname and trim any whitespace
-        let hostname = message.split('|').nth(1).unwrap_or("").trim();
-        println!("Heartbeat from: {}", hostname);
+        // Handle heartbeat - properly parse hostname and instance ID and trim any whitespace
+        let mut parts = message.split('|').skip(1);
+        let hostname = parts.next().unwrap_or("").trim().to_string();
+        let instance_id = legacy_instance_id(&registry, &hostname, parts.next(), peer_addr).await;
         
-        let success = {
-            let mut registry_w = registry.write().await;
-            registry_w.update_heartbeat(hostname)
+        // Send response without null terminator
+        let response = match handle_message(Message::Heartbeat { hostname, instance_id }, peer_addr, &registry).await {
+            Message::HeartbeatAck => "HEARTBEAT_OK",
+            _ => "HEARTBEAT_FAILED",
         };
+        stream.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+    
+    if message.starts_with("DEREGISTER|") {
+        let mut parts = message.split('|').skip(1);
+        let hostname = parts.next().unwrap_or("").trim().to_string();
+        let instance_id = legacy_instance_id(&registry, &hostname, parts.next(), peer_addr).await;
         
-        // Send response without null terminator
-        let response = if success {
-            "HEARTBEAT_OK"
-        } else {
-            "HEARTBEAT_FAILED"
+        let response = match handle_message(Message::Deregister { hostname, instance_id }, peer_addr, &registry).await {
+            Message::Deregistered => "DEREGISTER_OK",
+            _ => "DEREGISTER_FAILED",
         };
         stream.write_all(response.as_bytes()).await?;
         return Ok(());
     }
     
     // Handle registration
-    let hostname = message;
-    println!("Registering service: {}", hostname);
-    
-    // Allocate IP from virtual network
-    let virtual_net = crate::network::VirtualNetwork::new();
-    let ip = virtual_net.allocate_ip();
+    let mut parts = message.split('|').map(str::trim);
+    let hostname = parts.next().unwrap_or("").to_string();
+    let listen_host = parts.next().filter(|host| !host.is_empty()).map(str::to_string);
+    let listen_port = match parts.next().filter(|port| !port.is_empty()).map(str::parse::<u16>) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(_)) => {
+            stream.write_all(b"FAILED|Invalid advertised port\0").await?;
+            return Ok(());
+        }
+        None => None,
+    };
     
-    let registration_result = {
-        let mut registry_w = registry.write().await;
-        registry_w.register(hostname.clone(), ip)
+    let request = RegisterRequest {
+        hostname,
+        listen_host,
+        listen_port,
+        ..RegisterRequest::default()
     };
+    let response = match register_instance(&registry, peer_addr, request).await {
+        Message::Registered { instance_id, ip, port, ttl } => {
+            format!("SUCCESS|{}|{}|{}|{}\0", ip, port, ttl, instance_id)
+        }
+        Message::Error { message, .. } => format!("FAILED|{}\0", message),
+        _ => "FAILED|Unexpected response\0".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
     
-    match registration_result {
-        Some(service_info) => {
-            println!("Service registered successfully: {:?}", service_info);
-            
-            // Send registration response
-            let response = format!("SUCCESS|{}|{}|86400\0", service_info.ip, service_info.port);
-            stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Resolve an advertised host, given as an IP address or a host name
+async fn resolve_host(host: &str) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    tokio::net::lookup_host((host, 0))
+        .await
+        .ok()?
+        .next()
+        .map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ServiceClient, ServiceWatch, WatchEvent};
+
+    const TTL: Duration = Duration::from_secs(10);
+    const REAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn registry() -> ServiceRegistry {
+        let mut registry = ServiceRegistry::new();
+        registry.set_lease_policy(LeasePolicy {
+            default_ttl: TTL,
+            ..LeasePolicy::default()
+        });
+        registry
+    }
+
+    fn register(registry: &mut ServiceRegistry, health_check: Option<HealthCheck>) -> String {
+        let mut registration = Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]));
+        registration.health_check = health_check;
+        registry.register(registration).unwrap().instance_id
+    }
+
+    fn check(failure_threshold: u32) -> Option<HealthCheck> {
+        Some(HealthCheck {
+            failure_threshold,
+            ..HealthCheck::tcp()
+        })
+    }
+
+    fn status(registry: &ServiceRegistry, instance_id: &str) -> ServiceStatus {
+        registry.get_instance("orders", instance_id).unwrap().status
+    }
+
+    /// Pretend the last heartbeat of an instance was `age` ago
+    fn age(registry: &mut ServiceRegistry, instance_id: &str, age: Duration) {
+        let service = registry.services.get_mut("orders").unwrap().get_mut(instance_id).unwrap();
+        service.last_heartbeat = Instant::now().checked_sub(age).expect("system up for longer than the test ages");
+    }
+
+    fn received(events: &mut broadcast::Receiver<RegistryEvent>) -> Vec<EventKind> {
+        std::iter::from_fn(|| events.try_recv().ok()).map(|event| event.kind).collect()
+    }
+
+    #[test]
+    fn missed_heartbeats_make_an_instance_suspect_then_offline() {
+        let mut registry = registry();
+        let id = register(&mut registry, None);
+        let mut events = registry.subscribe();
+
+        age(&mut registry, &id, TTL / 2 - Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert_eq!(status(&registry, &id), ServiceStatus::Ready);
+
+        age(&mut registry, &id, TTL / 2 + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert_eq!(status(&registry, &id), ServiceStatus::Suspect);
+        assert_eq!(registry.healthy_instances("orders").len(), 1);
+
+        age(&mut registry, &id, TTL + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert_eq!(status(&registry, &id), ServiceStatus::Offline);
+        assert!(registry.healthy_instances("orders").is_empty());
+
+        assert!(registry.update_heartbeat("orders", &id));
+        assert_eq!(status(&registry, &id), ServiceStatus::Ready);
+        assert_eq!(received(&mut events), [EventKind::Suspect, EventKind::Offline, EventKind::Recovered]);
+    }
+
+    #[test]
+    fn a_ready_instance_past_its_ttl_goes_straight_offline() {
+        let mut registry = registry();
+        let id = register(&mut registry, None);
+
+        age(&mut registry, &id, TTL + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert_eq!(status(&registry, &id), ServiceStatus::Offline);
+    }
+
+    #[test]
+    fn instances_past_the_reap_timeout_are_removed_whatever_their_status() {
+        let mut registry = registry();
+        let offline = register(&mut registry, None);
+        let maintenance = register(&mut registry, None);
+        registry.set_status("orders", &maintenance, ServiceStatus::Maintenance);
+        let mut events = registry.subscribe();
+
+        age(&mut registry, &offline, REAP_TIMEOUT - Duration::from_secs(1));
+        age(&mut registry, &maintenance, REAP_TIMEOUT - Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert_eq!(status(&registry, &offline), ServiceStatus::Offline);
+        // Operator statuses are left alone by the heartbeat transitions
+        assert_eq!(status(&registry, &maintenance), ServiceStatus::Maintenance);
+
+        age(&mut registry, &offline, REAP_TIMEOUT + Duration::from_secs(1));
+        age(&mut registry, &maintenance, REAP_TIMEOUT + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert!(registry.get_instances("orders").is_empty());
+        assert_eq!(registry.port_pool_usage().0, 0);
+        assert_eq!(registry.ip_pool_usage().0, 0);
+        assert_eq!(received(&mut events), [EventKind::Offline, EventKind::Expired, EventKind::Expired]);
+    }
+
+    #[test]
+    fn requested_ttls_are_clamped_to_the_policy_bounds() {
+        let policy = LeasePolicy {
+            default_ttl: Duration::from_secs(30),
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(120),
+        };
+        assert_eq!(policy.grant(None), Duration::from_secs(30));
+        assert_eq!(policy.grant(Some(Duration::from_secs(1))), Duration::from_secs(5));
+        assert_eq!(policy.grant(Some(Duration::from_secs(60))), Duration::from_secs(60));
+        assert_eq!(policy.grant(Some(Duration::from_secs(600))), Duration::from_secs(120));
+
+        // A default outside the bounds is clamped too, and inverted bounds settle on the minimum
+        let policy = LeasePolicy { default_ttl: Duration::from_secs(600), ..policy };
+        assert_eq!(policy.grant(None), Duration::from_secs(120));
+        let policy = LeasePolicy { max_ttl: Duration::from_secs(1), ..policy };
+        assert_eq!(policy.grant(Some(Duration::from_secs(60))), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn instances_get_the_ttl_granted_by_the_policy() {
+        let mut registry = registry();
+        let id = register(&mut registry, None);
+        assert_eq!(registry.get_instance("orders", &id).unwrap().ttl, TTL);
+
+        let mut registration = Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]));
+        registration.ttl = Some(Duration::from_secs(3600));
+        let service = registry.register(registration).unwrap();
+        assert_eq!(service.ttl, LeasePolicy::default().max_ttl);
+    }
+
+    #[test]
+    fn the_next_deadline_is_the_earliest_transition_of_any_instance() {
+        let mut registry = registry();
+        assert_eq!(registry.next_deadline(REAP_TIMEOUT), None);
+
+        let ready = register(&mut registry, None);
+        let heartbeat = registry.get_instance("orders", &ready).unwrap().last_heartbeat;
+        assert_eq!(registry.next_deadline(REAP_TIMEOUT), Some(heartbeat + TTL / 2));
+
+        // Suspect instances go offline after the whole TTL, operator statuses only wait for the reap timeout
+        registry.set_status("orders", &ready, ServiceStatus::Suspect);
+        assert_eq!(registry.next_deadline(REAP_TIMEOUT), Some(heartbeat + TTL));
+        registry.set_status("orders", &ready, ServiceStatus::Maintenance);
+        assert_eq!(registry.next_deadline(REAP_TIMEOUT), Some(heartbeat + REAP_TIMEOUT));
+
+        // The earliest instance wins
+        let late = register(&mut registry, None);
+        age(&mut registry, &late, REAP_TIMEOUT);
+        let late_heartbeat = registry.get_instance("orders", &late).unwrap().last_heartbeat;
+        assert_eq!(registry.next_deadline(REAP_TIMEOUT), Some(late_heartbeat + TTL / 2));
+
+        // A flapping instance is due at the end of its cooldown
+        registry.unregister("orders", &late);
+        let dampened = Instant::now() + Duration::from_secs(1);
+        registry.services.get_mut("orders").unwrap().get_mut(&ready).unwrap().dampened_until = Some(dampened);
+        assert_eq!(registry.next_deadline(REAP_TIMEOUT), Some(dampened));
+    }
+
+    #[test]
+    fn failed_checks_make_an_instance_unhealthy_until_one_passes() {
+        let mut registry = registry();
+        let id = register(&mut registry, check(2));
+        let mut events = registry.subscribe();
+
+        assert_eq!(registry.record_check("orders", &id, false), None);
+        assert_eq!(registry.record_check("orders", &id, true), None);
+        assert_eq!(registry.record_check("orders", &id, false), None);
+        assert_eq!(status(&registry, &id), ServiceStatus::Ready);
+        assert_eq!(registry.record_check("orders", &id, false), Some(ServiceStatus::Unhealthy));
+        assert_eq!(registry.record_check("orders", &id, false), None);
+
+        // Heartbeats do not make up for a failing check
+        assert!(registry.update_heartbeat("orders", &id));
+        assert_eq!(status(&registry, &id), ServiceStatus::Unhealthy);
+        assert!(registry.healthy_instances("orders").is_empty());
+
+        assert_eq!(registry.record_check("orders", &id, true), Some(ServiceStatus::Ready));
+        assert_eq!(registry.record_check("orders", &id, true), None);
+        assert_eq!(received(&mut events), [EventKind::Unhealthy, EventKind::Recovered]);
+    }
+
+    #[test]
+    fn an_unhealthy_instance_goes_offline_and_stays_unhealthy_when_heartbeating_again() {
+        let mut registry = registry();
+        let id = register(&mut registry, check(1));
+        registry.record_check("orders", &id, false);
+
+        age(&mut registry, &id, TTL + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert_eq!(status(&registry, &id), ServiceStatus::Offline);
+
+        registry.update_heartbeat("orders", &id);
+        assert_eq!(status(&registry, &id), ServiceStatus::Unhealthy);
+    }
+
+    #[test]
+    fn checks_do_not_override_operator_statuses() {
+        let mut registry = registry();
+        let id = register(&mut registry, check(1));
+        registry.set_status("orders", &id, ServiceStatus::Draining);
+
+        assert_eq!(registry.record_check("orders", &id, false), None);
+        assert_eq!(registry.record_check("orders", &id, true), None);
+        assert_eq!(status(&registry, &id), ServiceStatus::Draining);
+
+        // Back to ready, unless the check is failing
+        registry.record_check("orders", &id, false);
+        registry.set_status("orders", &id, ServiceStatus::Ready);
+        assert_eq!(status(&registry, &id), ServiceStatus::Unhealthy);
+    }
+
+    /// Error of `validate_metadata` for the given version, tags and metadata, if any
+    fn metadata_error(version: Option<&str>, tags: &[&str], metadata: &[(&str, &str)]) -> Option<String> {
+        let tags = tags.iter().map(|tag| tag.to_string()).collect();
+        let metadata = metadata.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        validate_metadata(version, &tags, &metadata).err()
+    }
+
+    #[test]
+    fn metadata_within_the_limits_is_accepted() {
+        let version = "v".repeat(MAX_VERSION_LEN);
+        let tag = "t".repeat(MAX_TAG_LEN);
+        let key = "k".repeat(MAX_METADATA_KEY_LEN);
+        let value = "v".repeat(MAX_METADATA_VALUE_LEN);
+        assert_eq!(metadata_error(Some(&version), &[&tag, "canary"], &[(&key, &value)]), None);
+        assert_eq!(metadata_error(Some("1.4.0 (build 7)"), &["team:orders"], &[("owner", "Ops team, EU")]), None);
+        assert_eq!(metadata_error(None, &[], &[("empty", "")]), None);
+    }
+
+    #[test]
+    fn overlong_metadata_is_rejected() {
+        let error = metadata_error(Some(&"v".repeat(MAX_VERSION_LEN + 1)), &[], &[]).unwrap();
+        assert_eq!(error, "Version is longer than 64 bytes");
+        let error = metadata_error(None, &[&"t".repeat(MAX_TAG_LEN + 1)], &[]).unwrap();
+        assert_eq!(error, "Tag tttttttttttttttt... is longer than 64 bytes");
+        let error = metadata_error(None, &[], &[(&"k".repeat(MAX_METADATA_KEY_LEN + 1), "v")]).unwrap();
+        assert_eq!(error, "Metadata key kkkkkkkkkkkkkkkk... is longer than 64 bytes");
+        let error = metadata_error(None, &[], &[("owner", &"v".repeat(MAX_METADATA_VALUE_LEN + 1))]).unwrap();
+        assert_eq!(error, "Metadata value of owner is longer than 256 bytes");
+    }
+
+    #[test]
+    fn too_many_tags_or_metadata_entries_are_rejected() {
+        let names: Vec<String> = (0..=MAX_TAGS.max(MAX_METADATA_ENTRIES)).map(|i| format!("n{}", i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        assert_eq!(metadata_error(None, &names[..MAX_TAGS], &[]), None);
+        let error = metadata_error(None, &names[..=MAX_TAGS], &[]).unwrap();
+        assert_eq!(error, "Too many tags, at most 16 are allowed");
+
+        let entries: Vec<(&str, &str)> = names.iter().map(|name| (*name, "v")).collect();
+        assert_eq!(metadata_error(None, &[], &entries[..MAX_METADATA_ENTRIES]), None);
+        let error = metadata_error(None, &[], &entries[..=MAX_METADATA_ENTRIES]).unwrap();
+        assert_eq!(error, "Too many metadata entries, at most 16 are allowed");
+    }
+
+    #[test]
+    fn metadata_outside_the_charset_is_rejected() {
+        for tag in ["", "two words", "a,b", "a=b", "caf\u{e9}", "tab\t"] {
+            assert!(metadata_error(None, &[tag], &[]).unwrap().starts_with("Tag "), "{:?}", tag);
         }
-        None => {
-            println!("Failed to register service: {}", hostname);
-            let response = "FAILED|Service already registered or port unavailable\0";
-            stream.write_all(response.as_bytes()).await?;
+        for key in ["", "a key", "a=b", "a,b", "\u{1b}[31m"] {
+            assert!(metadata_error(None, &[], &[(key, "v")]).unwrap().starts_with("Metadata key "), "{:?}", key);
         }
+        let error = metadata_error(None, &[], &[("owner", "line\nbreak")]).unwrap();
+        assert_eq!(error, "Metadata value of owner contains control characters");
+        let error = metadata_error(Some("1.4\r\n"), &[], &[]).unwrap();
+        assert_eq!(error, "Version contains control characters");
+    }
+
+    fn flapping_registry(threshold: u32) -> ServiceRegistry {
+        let mut registry = registry();
+        registry.set_flap_policy(FlapPolicy {
+            threshold,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        });
+        registry
+    }
+
+    /// Fail then pass the check of an instance, going out of and back into rotation
+    fn flap(registry: &mut ServiceRegistry, instance_id: &str) {
+        registry.record_check("orders", instance_id, false);
+        registry.record_check("orders", instance_id, true);
+    }
+
+    /// Pretend the dampening of an instance ends now
+    fn end_cooldown(registry: &mut ServiceRegistry, instance_id: &str) {
+        let service = registry.services.get_mut("orders").unwrap().get_mut(instance_id).unwrap();
+        service.dampened_until = Some(Instant::now());
+    }
+
+    #[test]
+    fn an_instance_flapping_past_the_threshold_is_dampened_until_the_cooldown_ends() {
+        let mut registry = flapping_registry(2);
+        let id = register(&mut registry, check(1));
+        let mut events = registry.subscribe();
+
+        flap(&mut registry, &id);
+        assert!(!registry.get_instance("orders", &id).unwrap().is_flapping());
+        registry.record_check("orders", &id, false);
+        assert!(registry.get_instance("orders", &id).unwrap().is_flapping());
+        assert_eq!(
+            received(&mut events),
+            [EventKind::Unhealthy, EventKind::Recovered, EventKind::Unhealthy, EventKind::Flapping]
+        );
+
+        // Passing checks bring the status back, but not the instance into rotation
+        registry.record_check("orders", &id, true);
+        assert_eq!(status(&registry, &id), ServiceStatus::Ready);
+        assert!(registry.healthy_instances("orders").is_empty());
+        registry.check_heartbeats(REAP_TIMEOUT);
+        assert!(registry.healthy_instances("orders").is_empty());
+        received(&mut events);
+
+        end_cooldown(&mut registry, &id);
+        registry.check_heartbeats(REAP_TIMEOUT);
+        let service = registry.get_instance("orders", &id).unwrap();
+        assert!(!service.is_flapping());
+        assert!(service.transitions.is_empty());
+        assert_eq!(registry.healthy_instances("orders").len(), 1);
+        assert_eq!(received(&mut events), [EventKind::Recovered]);
+    }
+
+    #[test]
+    fn transitions_while_dampened_restart_the_cooldown() {
+        let mut registry = flapping_registry(1);
+        let id = register(&mut registry, check(1));
+        flap(&mut registry, &id);
+        end_cooldown(&mut registry, &id);
+
+        registry.record_check("orders", &id, false);
+        let until = registry.get_instance("orders", &id).unwrap().dampened_until.unwrap();
+        assert!(until > Instant::now() + Duration::from_secs(29));
+    }
+
+    #[test]
+    fn transitions_older_than_the_window_are_forgotten() {
+        let mut registry = flapping_registry(2);
+        let id = register(&mut registry, check(1));
+        flap(&mut registry, &id);
+
+        let service = registry.services.get_mut("orders").unwrap().get_mut(&id).unwrap();
+        for at in service.transitions.iter_mut() {
+            *at = at.checked_sub(Duration::from_secs(61)).expect("system up for longer than the test ages");
+        }
+        registry.record_check("orders", &id, false);
+        let service = registry.get_instance("orders", &id).unwrap();
+        assert!(!service.is_flapping());
+        assert_eq!(service.transitions.len(), 1);
+    }
+
+    #[test]
+    fn status_changes_within_or_out_of_rotation_are_not_transitions() {
+        let mut registry = flapping_registry(1);
+        let id = register(&mut registry, check(1));
+
+        // Ready to suspect stays in rotation, offline to unhealthy stays out of it
+        age(&mut registry, &id, TTL / 2 + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        registry.record_check("orders", &id, false);
+        age(&mut registry, &id, TTL + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+        registry.update_heartbeat("orders", &id);
+        assert_eq!(status(&registry, &id), ServiceStatus::Unhealthy);
+        assert_eq!(registry.get_instance("orders", &id).unwrap().transitions.len(), 1);
+    }
+
+    #[test]
+    fn a_zero_threshold_disables_dampening() {
+        let mut registry = flapping_registry(0);
+        let id = register(&mut registry, check(1));
+        for _ in 0..10 {
+            flap(&mut registry, &id);
+        }
+        assert!(!registry.get_instance("orders", &id).unwrap().is_flapping());
+    }
+
+    #[test]
+    fn setting_an_instance_ready_lifts_dampening() {
+        let mut registry = flapping_registry(1);
+        let id = register(&mut registry, check(1));
+        flap(&mut registry, &id);
+        assert!(registry.healthy_instances("orders").is_empty());
+
+        registry.set_status("orders", &id, ServiceStatus::Ready);
+        assert_eq!(registry.healthy_instances("orders").len(), 1);
+    }
+
+    #[test]
+    fn a_second_instance_of_a_name_gets_its_own_id_and_address() {
+        let mut registry = registry();
+        let first = register(&mut registry, None);
+        let mut events = registry.subscribe();
+
+        // Asking for the ID of a live instance does not take it over
+        let mut registration = Registration::new("orders".to_string(), IpAddr::from([127, 0, 0, 1]));
+        registration.instance_id = Some(first.clone());
+        let second = registry.register(registration).unwrap();
+        assert_ne!(second.instance_id, first);
+        assert_ne!(second.addr, registry.get_instance("orders", &first).unwrap().addr);
+        assert_eq!(registry.get_instances("orders").len(), 2);
+        assert_eq!(registry.healthy_instances("orders").len(), 2);
+        assert_eq!(registry.port_pool_usage().0, 2);
+        assert_eq!(received(&mut events), [EventKind::Registered]);
+    }
+
+    #[test]
+    fn instances_are_evicted_one_at_a_time() {
+        let mut registry = registry();
+        let deregistered = register(&mut registry, None);
+        let expired = register(&mut registry, None);
+        let kept = register(&mut registry, None);
+        let mut events = registry.subscribe();
+
+        assert!(registry.unregister("orders", &deregistered));
+        assert!(!registry.unregister("orders", &deregistered));
+        age(&mut registry, &expired, REAP_TIMEOUT + Duration::from_secs(1));
+        registry.check_heartbeats(REAP_TIMEOUT);
+
+        let remaining: Vec<&str> = registry.get_instances("orders").iter().map(|s| s.instance_id.as_str()).collect();
+        assert_eq!(remaining, [kept.as_str()]);
+        assert_eq!(status(&registry, &kept), ServiceStatus::Ready);
+        assert_eq!(registry.port_pool_usage().0, 1);
+        assert_eq!(registry.ip_pool_usage().0, 1);
+        assert_eq!(received(&mut events), [EventKind::Deregistered, EventKind::Expired]);
+    }
+
+    /// Serve the registration server for `registry` on an ephemeral port
+    async fn registration_server(registry: ServiceRegistry) -> (SocketAddr, Arc<SharedRegistry>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(SharedRegistry::new(registry));
+        // The sender is dropped, so the server runs until the test ends
+        let shutdown = Shutdown::new(tokio::sync::watch::channel(false).1, Duration::from_secs(1));
+        let served = registry.clone();
+        tokio::spawn(async move { serve_registration(listener, served, false, shutdown).await.unwrap() });
+        (addr, registry)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_without_a_complete_request_are_closed() {
+        let (addr, _) = registration_server(registry()).await;
+        let started = tokio::time::Instant::now();
+
+        let idle = TcpStream::connect(addr).await.unwrap();
+        let mut trickling = TcpStream::connect(addr).await.unwrap();
+        // A header announcing a 256-byte payload, of which only one byte arrives
+        trickling.write_all(&[PROTOCOL_VERSION, 0x01, 0, 0, 1, 0, 0]).await.unwrap();
+
+        for mut stream in [idle, trickling] {
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert!(received.is_empty());
+        }
+        assert!(started.elapsed() >= FRAME_TIMEOUT);
+    }
+
+    fn tags(tags: &[&str]) -> BTreeSet<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn updating_metadata_publishes_a_change_only_when_something_changed() {
+        let mut registry = registry();
+        let id = register(&mut registry, None);
+        let mut events = registry.subscribe();
+
+        assert!(registry.update_metadata("orders", &id, Some("1.2.0".to_string()), tags(&["eu"]), BTreeMap::new()));
+        assert!(registry.update_metadata("orders", &id, Some("1.2.0".to_string()), tags(&["eu"]), BTreeMap::new()));
+        assert!(!registry.update_metadata("orders", "orders-99", None, BTreeSet::new(), BTreeMap::new()));
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, EventKind::MetadataChanged);
+        assert_eq!(event.service.version.as_deref(), Some("1.2.0"));
+        assert_eq!(event.service.tags, tags(&["eu"]));
+        assert!(events.try_recv().is_err());
+    }
+
+    /// Wait for the next event of `watch`, failing the test if none comes
+    async fn next_event(watch: &mut ServiceWatch) -> WatchEvent {
+        tokio::time::timeout(Duration::from_secs(5), watch.next()).await.unwrap().unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_watch_streams_the_current_instances_then_the_changes_of_its_service() {
+        let mut initial = registry();
+        let first = register(&mut initial, None);
+        initial.register(Registration::new("billing".to_string(), IpAddr::from([127, 0, 0, 1]))).unwrap();
+        let (addr, registry) = registration_server(initial).await;
+
+        let client = ServiceClient::new(addr, "payments".to_string());
+        let mut watch = client.watch("orders").await.unwrap();
+        let event = next_event(&mut watch).await;
+        assert_eq!((event.kind, event.hostname.as_str()), (EventKind::Registered, "orders"));
+        assert_eq!(event.instance.instance_id, first);
+
+        let second = {
+            let mut registry_w = registry.write().await;
+            // Another service's changes are filtered out
+            registry_w.register(Registration::new("billing".to_string(), IpAddr::from([127, 0, 0, 2]))).unwrap();
+            let second = register(&mut registry_w, None);
+            registry_w.update_metadata("orders", &first, None, tags(&["canary"]), BTreeMap::new());
+            registry_w.unregister("orders", &first);
+            second
+        };
+        let received: Vec<(EventKind, String)> = [
+            next_event(&mut watch).await,
+            next_event(&mut watch).await,
+            next_event(&mut watch).await,
+        ]
+        .into_iter()
+        .map(|event| (event.kind, event.instance.instance_id))
+        .collect();
+        assert_eq!(received, [
+            (EventKind::Registered, second),
+            (EventKind::MetadataChanged, first.clone()),
+            (EventKind::Deregistered, first),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn a_lagging_watch_gets_an_error() {
+        let mut initial = registry();
+        let id = register(&mut initial, None);
+        let (addr, registry) = registration_server(initial).await;
+
+        let mut watch = ServiceClient::new(addr, "payments".to_string()).watch("orders").await.unwrap();
+        // The snapshot is sent once the server subscribed
+        next_event(&mut watch).await;
+        {
+            // Publish more events than the channel holds before the watch task gets to run
+            let mut registry_w = registry.write().await;
+            for i in 0..=EVENT_CHANNEL_CAPACITY {
+                let tag = if i % 2 == 0 { "blue" } else { "green" };
+                registry_w.update_metadata("orders", &id, None, tags(&[tag]), BTreeMap::new());
+            }
+        }
+
+        let error = tokio::time::timeout(Duration::from_secs(5), watch.next()).await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("Watch lagged"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn a_disconnected_watch_unsubscribes() {
+        let (addr, registry) = registration_server(registry()).await;
+        let subscribers = || async { registry.read().await.events.receiver_count() };
+
+        let watch = ServiceClient::new(addr, "payments".to_string()).watch("orders").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while subscribers().await == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        drop(watch);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while subscribers().await > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Registry with one instance of `orders` per host IP
+    fn legacy_registry(hosts: &[[u8; 4]]) -> SharedRegistry {
+        let mut registry = registry();
+        for &host in hosts {
+            registry.register(Registration::new("orders".to_string(), IpAddr::from(host))).unwrap();
+        }
+        SharedRegistry::new(registry)
+    }
+
+    fn peer(host: [u8; 4]) -> SocketAddr {
+        SocketAddr::from((host, 40000))
+    }
+
+    #[tokio::test]
+    async fn a_legacy_request_names_the_instance_registered_from_its_ip() {
+        let registry = legacy_registry(&[[192, 168, 1, 10], [192, 168, 1, 11]]);
+        let expected = {
+            let registry = registry.read().await;
+            let instances = registry.get_instances("orders");
+            let from_peer = instances.iter().find(|service| service.endpoint.ip() == IpAddr::from([192, 168, 1, 11]));
+            from_peer.unwrap().instance_id.clone()
+        };
+
+        assert_eq!(legacy_instance_id(&registry, "Orders", None, peer([192, 168, 1, 11])).await, expected);
+        // An explicit ID is used as is
+        let explicit = legacy_instance_id(&registry, "orders", Some(" orders-9 "), peer([192, 168, 1, 11])).await;
+        assert_eq!(explicit, "orders-9");
+    }
+
+    #[tokio::test]
+    async fn a_legacy_request_names_the_sole_instance_from_any_ip() {
+        let registry = legacy_registry(&[[192, 168, 1, 10]]);
+        let expected = registry.read().await.get_instances("orders")[0].instance_id.clone();
+
+        assert_eq!(legacy_instance_id(&registry, "orders", None, peer([10, 1, 1, 1])).await, expected);
+        assert_eq!(legacy_instance_id(&registry, "orders", Some(""), peer([10, 1, 1, 1])).await, expected);
+    }
+
+    #[tokio::test]
+    async fn a_legacy_request_names_no_instance_when_several_match_or_none_does() {
+        let registry = legacy_registry(&[[192, 168, 1, 10], [192, 168, 1, 11], [192, 168, 1, 11]]);
+
+        assert_eq!(legacy_instance_id(&registry, "orders", None, peer([10, 1, 1, 1])).await, "");
+        assert_eq!(legacy_instance_id(&registry, "orders", None, peer([192, 168, 1, 11])).await, "");
+        assert_eq!(legacy_instance_id(&registry, "billing", None, peer([192, 168, 1, 10])).await, "");
     }
-    
-    Ok(())
 }
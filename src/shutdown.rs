@@ -0,0 +1,59 @@
+//! Shutdown signal for NetSel
+//!
+//! The components started by [`crate::NetSelServer::start`] watch a shared [`Shutdown`] signal. Once it fires they
+//! stop accepting connections, the proxies let their open connections finish within the drain timeout, and the
+//! serving functions return.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::warn;
+
+/// Signal telling a component to stop, along with how long it may take to drain its connections
+#[derive(Clone)]
+pub struct Shutdown {
+    signal: watch::Receiver<bool>,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    /// Create a signal that fires once `true` is sent on the channel of `signal`
+    ///
+    /// Dropping the sender without sending `true` never fires the signal.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use tokio::sync::watch;
+    /// use netsel::shutdown::Shutdown;
+    ///
+    /// let (trigger, signal) = watch::channel(false);
+    /// let shutdown = Shutdown::new(signal, Duration::from_secs(30));
+    /// // ... pass `shutdown` to the components, later:
+    /// trigger.send(true).ok();
+    /// ```
+    pub fn new(signal: watch::Receiver<bool>, drain_timeout: Duration) -> Self {
+        Self { signal, drain_timeout }
+    }
+
+    /// Wait until the shutdown signal fires
+    pub async fn wait(&mut self) {
+        if self.signal.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Wait for the `connections` still open to finish, aborting those left after the drain timeout
+    pub async fn drain(&self, connections: &mut JoinSet<()>) {
+        let finished = tokio::time::timeout(self.drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_err() {
+            warn!(connections = connections.len(), "Aborting connections still open after the drain timeout");
+            connections.shutdown().await;
+        }
+    }
+}